@@ -0,0 +1,83 @@
+use std::{
+    fmt,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// AMQP reply code the broker closes a channel with when a declaration does not match
+/// the existing exchange or queue.
+pub const PRECONDITION_FAILED: u16 = 406;
+
+/// An exchange or queue exists on the broker with different arguments than configured.
+/// Retrying cannot fix it, so the supervisor gives up.
+#[derive(Debug)]
+pub struct TopologyMismatch {
+    pub declaration: String,
+    pub reason: String,
+}
+
+impl fmt::Display for TopologyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} does not match the existing declaration ({}); delete it or change the \
+             configuration to match",
+            self.declaration, self.reason
+        )
+    }
+}
+
+impl std::error::Error for TopologyMismatch {}
+
+/// Run a declaration. If the broker rejects it with PRECONDITION_FAILED, the channel close
+/// callback has recorded the reason in `closed` by the time the declaration fails.
+pub async fn declare<T>(
+    declaration: String,
+    closed: &Mutex<Option<(u16, String)>>,
+    future: impl Future<Output = Result<T, amqprs::error::Error>>,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    match future.await {
+        Ok(result) => Ok(result),
+        Err(e) => match closed.lock().unwrap().take() {
+            Some((PRECONDITION_FAILED, reason)) => Err(Box::new(TopologyMismatch {
+                declaration,
+                reason,
+            })),
+            _ => Err(e.into()),
+        },
+    }
+}
+
+/// Exponential backoff from `initial_ms` capped at `max_ms`, with up to 25% jitter so
+/// instances restarting together do not retry in lockstep.
+pub fn backoff_delay(initial_ms: u64, max_ms: u64, attempt: u32) -> Duration {
+    let base = initial_ms
+        .saturating_mul(1u64 << attempt.min(16))
+        .min(max_ms);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.subsec_nanos() as u64)
+        .unwrap_or(0);
+    let jitter = nanos % (base / 4 + 1);
+    Duration::from_millis(base - jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_with_at_most_a_quarter_of_jitter() {
+        for (attempt, base) in [(0, 100), (1, 200), (3, 800), (4, 1000), (40, 1000)] {
+            let delay = backoff_delay(100, 1000, attempt);
+            assert!(delay <= Duration::from_millis(base), "{:?}", delay);
+            assert!(
+                delay >= Duration::from_millis(base - base / 4),
+                "{:?}",
+                delay
+            );
+        }
+        assert_eq!(backoff_delay(0, 1000, 3), Duration::ZERO);
+    }
+}
@@ -5,7 +5,8 @@ export type GetStockPricesResponse = ReturnType<
   {
     stock_id: string;
     stock_name: string;
-    current_price: number;
+    current_price: number | null; // null while nothing is offered
+    trading_status?: "active" | "active_no_quotes";
   }[]
 >;
 export function isGetStockPricesResponse(obj: any): obj is GetStockPricesResponse {
@@ -21,7 +22,7 @@ export function isGetStockPricesResponse(obj: any): obj is GetStockPricesRespons
         "stock_name" in item &&
         typeof item.stock_name === "string" &&
         "current_price" in item &&
-        typeof item.current_price === "number"
+        (typeof item.current_price === "number" || item.current_price === null)
     )
   );
 }
@@ -0,0 +1,226 @@
+//! Inspects and re-drives messages parked in a dead-letter queue.
+//!
+//! ```bash
+//! cargo run --bin me-dead-letters -- list <queue> [--limit <n>]
+//! cargo run --bin me-dead-letters -- redrive <queue> [--limit <n>]
+//! ```
+//!
+//! `list` prints up to `--limit` messages (default 20) as JSON lines and leaves them in the
+//! queue. `redrive` publishes messages back to the exchange and routing key they originally
+//! came from, without the failure headers, and removes them from the queue; each message is
+//! republished and acknowledged in one transaction. Messages the broker dead-lettered itself
+//! (rejected, expired or dropped from a full queue) go back to where their `x-death` header
+//! says they were last delivered from. It stops after the messages that were in the queue
+//! when it started, so messages that fail again are not picked up twice. Messages without
+//! either are skipped and stay in the queue.
+//!
+//! Connection settings come from `RABBITMQ_HOST`, `RABBITMQ_PORT`, `RABBITMQ_USERNAME` and
+//! `RABBITMQ_PASSWORD`, as for the engine.
+
+use amqprs::{
+    channel::{
+        BasicAckArguments, BasicGetArguments, BasicNackArguments, BasicPublishArguments, Channel,
+    },
+    connection::{Connection, OpenConnectionArguments},
+};
+use matching_engine::dead_letter::{original_destination, remove_failure_headers};
+use serde::Serialize;
+use std::{collections::BTreeMap, env, process};
+
+#[derive(Serialize)]
+struct ListedMessage {
+    routing_key: String,
+    redelivered: bool,
+    headers: BTreeMap<String, String>,
+    body: String,
+}
+
+enum Command {
+    List,
+    Redrive,
+}
+
+struct Args {
+    command: Command,
+    queue: String,
+    limit: Option<u32>,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: me-dead-letters <list|redrive> <queue> [--limit <n>]");
+    process::exit(2);
+}
+
+fn parse_args() -> Args {
+    let mut args = env::args().skip(1);
+    let command = match args.next().as_deref() {
+        Some("list") => Command::List,
+        Some("redrive") => Command::Redrive,
+        _ => usage(),
+    };
+    let queue = args
+        .next()
+        .filter(|queue| !queue.starts_with("--"))
+        .unwrap_or_else(|| usage());
+
+    let mut limit = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--limit" => {
+                limit = Some(
+                    args.next()
+                        .and_then(|limit| limit.parse().ok())
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            _ => usage(),
+        }
+    }
+
+    Args {
+        command,
+        queue,
+        limit,
+    }
+}
+
+async fn connect() -> Result<(Connection, Channel), Box<dyn std::error::Error>> {
+    let host = env::var("RABBITMQ_HOST").unwrap_or_else(|_| "localhost".to_string());
+    let port = env::var("RABBITMQ_PORT")
+        .unwrap_or_else(|_| "5672".to_string())
+        .parse()
+        .unwrap_or(5672);
+    let username = env::var("RABBITMQ_USERNAME").unwrap_or_else(|_| "guest".to_string());
+    let password = env::var("RABBITMQ_PASSWORD").unwrap_or_else(|_| "guest".to_string());
+
+    let connection = Connection::open(&OpenConnectionArguments::new(
+        &host, port, &username, &password,
+    ))
+    .await?;
+    let channel = connection.open_channel(None).await?;
+    Ok((connection, channel))
+}
+
+async fn list(
+    channel: &Channel,
+    queue: &str,
+    limit: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut last_tag = None;
+    for _ in 0..limit {
+        let Some((get_ok, properties, body)) =
+            channel.basic_get(BasicGetArguments::new(queue)).await?
+        else {
+            break;
+        };
+        last_tag = Some(get_ok.delivery_tag());
+
+        let headers = properties
+            .headers()
+            .map(|headers| {
+                headers
+                    .as_ref()
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let message = ListedMessage {
+            routing_key: get_ok.routing_key().to_string(),
+            redelivered: get_ok.redelivered(),
+            headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        };
+        println!("{}", serde_json::to_string(&message)?);
+    }
+
+    // Put everything back where it was
+    if let Some(tag) = last_tag {
+        channel
+            .basic_nack(BasicNackArguments::new(tag, true, true))
+            .await?;
+    }
+    Ok(())
+}
+
+async fn redrive(
+    channel: &Channel,
+    queue: &str,
+    limit: Option<u32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if limit == Some(0) {
+        return Ok(());
+    }
+    channel.tx_select().await?;
+
+    let mut remaining: Option<u32> = None;
+    let mut redriven = 0u32;
+    let mut skipped = Vec::new(); // Delivery tags, kept unacknowledged until the end
+    while remaining != Some(0) {
+        let Some((get_ok, mut properties, body)) =
+            channel.basic_get(BasicGetArguments::new(queue)).await?
+        else {
+            break;
+        };
+        let tag = get_ok.delivery_tag();
+        // Messages that fail again land behind the ones that were already there
+        let budget = remaining
+            .unwrap_or_else(|| (get_ok.message_count() + 1).min(limit.unwrap_or(u32::MAX)));
+        remaining = Some(budget - 1);
+
+        let Some((exchange, routing_key)) = original_destination(&properties) else {
+            eprintln!(
+                "Skipping message {}: no original exchange or routing key, and no x-death",
+                tag
+            );
+            skipped.push(tag);
+            continue;
+        };
+        remove_failure_headers(&mut properties);
+
+        channel
+            .basic_publish(
+                properties,
+                body,
+                BasicPublishArguments::new(&exchange, &routing_key),
+            )
+            .await?;
+        channel
+            .basic_ack(BasicAckArguments::new(tag, false))
+            .await?;
+        channel.tx_commit().await?;
+        redriven += 1;
+    }
+
+    // Requeued only now, so they are not fetched again in place of the messages behind them
+    for tag in &skipped {
+        channel
+            .basic_nack(BasicNackArguments::new(*tag, false, true))
+            .await?;
+    }
+    channel.tx_commit().await?;
+
+    eprintln!("Re-drove {} messages ({} skipped)", redriven, skipped.len());
+    Ok(())
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let (connection, channel) = connect().await?;
+
+    let result = match args.command {
+        Command::List => list(&channel, &args.queue, args.limit.unwrap_or(20)).await,
+        Command::Redrive => redrive(&channel, &args.queue, args.limit).await,
+    };
+
+    channel.close().await?;
+    connection.close().await?;
+    result
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run(parse_args()).await {
+        eprintln!("me-dead-letters failed: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,249 @@
+//! Measures how long limit sell cancellations take while a shard is under market buy
+//! pressure, against a running engine and broker.
+//!
+//! ```bash
+//! cargo run --release --bin me-load-test -- [--shard <n>] [--buys <n>] [--cancels <n>]
+//! ```
+//!
+//! It places `--cancels` limit sell orders (default 20) on shard `--shard` (default 0),
+//! floods the shard's market buy queue with `--buys` orders (default 20000) for a stock with
+//! no sellers, and cancels the sell orders one by one while the buy backlog is still being
+//! worked off. Latency is the time from publishing a cancellation to receiving its
+//! `order.cancelled` event, matched by correlation id. Compare runs with different
+//! `PREFETCH_*` settings on the engine.
+//!
+//! Connection settings come from `RABBITMQ_HOST`, `RABBITMQ_PORT`, `RABBITMQ_USERNAME` and
+//! `RABBITMQ_PASSWORD`, as for the engine.
+
+use amqprs::{
+    channel::{
+        BasicConsumeArguments, BasicPublishArguments, Channel, QueueBindArguments,
+        QueueDeclareArguments,
+    },
+    connection::{Connection, OpenConnectionArguments},
+    consumer::AsyncConsumer,
+    BasicProperties, Deliver,
+};
+use async_trait::async_trait;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    env, process,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+struct Args {
+    shard: u32,
+    buys: u32,
+    cancels: u32,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: me-load-test [--shard <n>] [--buys <n>] [--cancels <n>]");
+    process::exit(2);
+}
+
+fn parse_args() -> Args {
+    let mut parsed = Args {
+        shard: 0,
+        buys: 20_000,
+        cancels: 20,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--shard" => parsed.shard = value,
+            "--buys" => parsed.buys = value,
+            "--cancels" => parsed.cancels = value,
+            _ => usage(),
+        }
+    }
+    if parsed.cancels == 0 {
+        usage();
+    }
+    parsed
+}
+
+/// Forwards the correlation id of every `order.cancelled` event with the time it arrived.
+#[derive(Clone)]
+struct CancelledConsumer {
+    arrivals: mpsc::UnboundedSender<(String, Instant)>,
+}
+
+#[async_trait]
+impl AsyncConsumer for CancelledConsumer {
+    async fn consume(
+        &mut self,
+        _channel: &Channel,
+        _deliver: Deliver,
+        properties: BasicProperties,
+        _content: Vec<u8>,
+    ) {
+        if let Some(correlation_id) = properties.correlation_id() {
+            let _ = self.arrivals.send((correlation_id.clone(), Instant::now()));
+        }
+    }
+}
+
+async fn publish(
+    channel: &Channel,
+    routing_key: &str,
+    correlation_id: &str,
+    payload: serde_json::Value,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let properties = BasicProperties::default()
+        .with_content_type("application/json")
+        .with_correlation_id(correlation_id)
+        .finish();
+    channel
+        .basic_publish(
+            properties,
+            payload.to_string().into_bytes(),
+            BasicPublishArguments::new("order_exchange", routing_key),
+        )
+        .await?;
+    Ok(())
+}
+
+fn percentile(sorted: &[Duration], percentile: usize) -> Duration {
+    sorted[(sorted.len() - 1) * percentile / 100]
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let host = env::var("RABBITMQ_HOST").unwrap_or_else(|_| "localhost".to_string());
+    let port = env::var("RABBITMQ_PORT")
+        .unwrap_or_else(|_| "5672".to_string())
+        .parse()
+        .unwrap_or(5672);
+    let username = env::var("RABBITMQ_USERNAME").unwrap_or_else(|_| "guest".to_string());
+    let password = env::var("RABBITMQ_PASSWORD").unwrap_or_else(|_| "guest".to_string());
+
+    let connection = Connection::open(&OpenConnectionArguments::new(
+        &host, port, &username, &password,
+    ))
+    .await?;
+    let channel = connection.open_channel(None).await?;
+
+    // Listen for cancellations on a queue of our own
+    let (queue, _, _) = channel
+        .queue_declare(QueueDeclareArguments::exclusive_server_named())
+        .await?
+        .ok_or("queue declaration returned no result")?;
+    channel
+        .queue_bind(QueueBindArguments::new(
+            &queue,
+            "order_update_exchange",
+            "order.cancelled",
+        ))
+        .await?;
+    let (arrivals, mut arrived) = mpsc::unbounded_channel();
+    let mut consume_args = BasicConsumeArguments::new(&queue, "me_load_test");
+    consume_args.manual_ack(false);
+    channel
+        .basic_consume(CancelledConsumer { arrivals }, consume_args)
+        .await?;
+
+    let run_id = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_millis();
+    let sell_stock = format!("load-test-sell-{}", run_id);
+    let buy_stock = format!("load-test-buy-{}", run_id);
+    let shard = args.shard;
+
+    eprintln!("Placing {} limit sell orders", args.cancels);
+    for i in 0..args.cancels {
+        let stock_tx_id = format!("load-sell-{}-{}", run_id, i);
+        let sell = json!({
+            "stock_id": sell_stock,
+            "stock_name": "Load Test",
+            "quantity": 1,
+            "price": 1.0,
+            "stock_tx_id": stock_tx_id,
+            "user_name": "load-test-seller",
+        });
+        let routing_key = format!("order.limit_sell.shard_{}", shard);
+        publish(&channel, &routing_key, &stock_tx_id, sell).await?;
+    }
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    eprintln!("Publishing {} market buy orders", args.buys);
+    let started = Instant::now();
+    for i in 0..args.buys {
+        let stock_tx_id = format!("load-buy-{}-{}", run_id, i);
+        let buy = json!({
+            "stock_id": buy_stock,
+            "quantity": 1,
+            "stock_tx_id": stock_tx_id,
+            "budget": 1.0,
+            "user_name": "load-test-buyer",
+        });
+        let routing_key = format!("order.market_buy.shard_{}", shard);
+        publish(&channel, &routing_key, &stock_tx_id, buy).await?;
+    }
+    eprintln!("Published buys in {:?}", started.elapsed());
+
+    eprintln!("Cancelling the sell orders under buy pressure");
+    let mut sent = HashMap::new();
+    for i in 0..args.cancels {
+        let stock_tx_id = format!("load-sell-{}-{}", run_id, i);
+        let cancel = json!({
+            "stock_id": sell_stock,
+            "quantity": 1,
+            "price": 1.0,
+            "stock_tx_id": stock_tx_id,
+        });
+        let routing_key = format!("order.limit_sell_cancellation.shard_{}", shard);
+        sent.insert(stock_tx_id.clone(), Instant::now());
+        publish(&channel, &routing_key, &stock_tx_id, cancel).await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let mut latencies = Vec::new();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(120);
+    while latencies.len() < sent.len() {
+        let Ok(Some((correlation_id, at))) =
+            tokio::time::timeout_at(deadline, arrived.recv()).await
+        else {
+            break;
+        };
+        if let Some(sent_at) = sent.get(&correlation_id) {
+            latencies.push(at.duration_since(*sent_at));
+        }
+    }
+
+    channel.close().await?;
+    connection.close().await?;
+
+    if latencies.is_empty() {
+        return Err("no cancellations came back within 120 seconds".into());
+    }
+    latencies.sort();
+    println!(
+        "{}",
+        json!({
+            "buys": args.buys,
+            "cancels_sent": sent.len(),
+            "cancels_received": latencies.len(),
+            "latency_ms": {
+                "min": latencies[0].as_secs_f64() * 1000.0,
+                "p50": percentile(&latencies, 50).as_secs_f64() * 1000.0,
+                "p95": percentile(&latencies, 95).as_secs_f64() * 1000.0,
+                "max": latencies[latencies.len() - 1].as_secs_f64() * 1000.0,
+            },
+        })
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run(parse_args()).await {
+        eprintln!("me-load-test failed: {}", e);
+        process::exit(1);
+    }
+}
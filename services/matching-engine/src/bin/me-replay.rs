@@ -0,0 +1,196 @@
+//! Feeds a recorded command log through the matching engine without RabbitMQ and writes
+//! every event that would have been published, one JSON object per line.
+//!
+//! ```bash
+//! cargo run --bin me-replay -- <input.jsonl> [--snapshot <snapshot.json>] [--output <events.jsonl>] [--state-out <state.json>] [--shard-id <id>]
+//! ```
+//!
+//! Input lines are either journal entries (`{"seq", "received_at", "command"}`) or captured
+//! AMQP messages (`{"routing_key": "order.market_buy.shard_0", "payload": {...}, "received_at": 0}`,
+//! where the payload may also be the raw message body as a string). `received_at` drives the
+//! event timestamps, so replays are reproducible.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::PathBuf,
+    process,
+};
+use tracing_subscriber::{EnvFilter, FmtSubscriber};
+
+use matching_engine::{
+    engine::{self, OrderCommand},
+    journal::JournalEntry,
+    snapshot,
+    state::AppState,
+};
+
+#[derive(Deserialize)]
+struct CapturedMessage {
+    routing_key: String,
+    payload: serde_json::Value,
+    #[serde(default)]
+    received_at: u64, // Unix time in milliseconds, used as the engine clock
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InputLine {
+    Journal(JournalEntry),
+    Captured(CapturedMessage),
+}
+
+#[derive(Serialize)]
+struct OutputEvent<'a> {
+    input_seq: u64,
+    exchange: &'a str,
+    routing_key: String,
+    payload: serde_json::Value,
+}
+
+struct Args {
+    input: PathBuf,
+    snapshot: Option<PathBuf>,
+    output: Option<PathBuf>,
+    state_out: Option<PathBuf>,
+    shard_id: u32,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: me-replay <input.jsonl> [--snapshot <snapshot.json>] [--output <events.jsonl>] [--state-out <state.json>] [--shard-id <id>]"
+    );
+    process::exit(2);
+}
+
+fn parse_args() -> Args {
+    let mut input = None;
+    let mut snapshot = None;
+    let mut output = None;
+    let mut state_out = None;
+    let mut shard_id = 0;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--snapshot" => snapshot = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--output" => output = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--state-out" => state_out = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--shard-id" => {
+                shard_id = args
+                    .next()
+                    .and_then(|id| id.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "-h" | "--help" => usage(),
+            _ if input.is_none() && !arg.starts_with("--") => input = Some(arg.into()),
+            _ => usage(),
+        }
+    }
+
+    Args {
+        input: input.unwrap_or_else(|| usage()),
+        snapshot,
+        output,
+        state_out,
+        shard_id,
+    }
+}
+
+/// Turn one input line into the command to apply, along with its journal sequence number
+/// when it has one and the time it was received.
+fn to_command(line: InputLine) -> Result<(Option<u64>, u64, OrderCommand), String> {
+    match line {
+        InputLine::Journal(entry) => Ok((Some(entry.seq), entry.received_at, entry.command)),
+        InputLine::Captured(message) => {
+            let content = match message.payload {
+                serde_json::Value::String(body) => body.into_bytes(),
+                payload => serde_json::to_vec(&payload).map_err(|e| e.to_string())?,
+            };
+            OrderCommand::parse(engine::order_type(&message.routing_key), &content)
+                .map(|command| (None, message.received_at, command))
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let mut state = match &args.snapshot {
+        Some(path) => AppState::from_snapshot(snapshot::read(path)?),
+        None => AppState::new(),
+    };
+    state.shard_id = args.shard_id;
+    let start_seq = state.last_seq;
+
+    let reader = BufReader::new(File::open(&args.input)?);
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+
+    let (mut applied, mut skipped) = (0u64, 0u64);
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line_no = i as u64 + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let parsed = serde_json::from_str::<InputLine>(&line)
+            .map_err(|e| e.to_string())
+            .and_then(to_command);
+        let (journal_seq, received_at, command) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("Skipping line {}: {}", line_no, e);
+                skipped += 1;
+                continue;
+            }
+        };
+
+        // Journal entries already covered by the starting snapshot
+        if journal_seq.is_some_and(|seq| seq <= start_seq) {
+            continue;
+        }
+
+        // Journal entries keep their own sequence; captured messages are numbered by line
+        state.last_seq = journal_seq.unwrap_or(state.last_seq + 1);
+        for event in engine::apply(&mut state, command, received_at) {
+            let output = OutputEvent {
+                input_seq: journal_seq.unwrap_or(line_no),
+                exchange: event.exchange(),
+                routing_key: event.routing_key(),
+                payload: serde_json::from_slice(&event.payload()?)?,
+            };
+            serde_json::to_writer(&mut writer, &output)?;
+            writer.write_all(b"\n")?;
+        }
+        applied += 1;
+    }
+    writer.flush()?;
+
+    if let Some(path) = &args.state_out {
+        let file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(file, &state.to_snapshot())?;
+    }
+
+    eprintln!(
+        "Replayed {} commands ({} skipped), final sequence {}",
+        applied, skipped, state.last_seq
+    );
+    Ok(())
+}
+
+fn main() {
+    let subscriber = FmtSubscriber::builder()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(io::stderr);
+    let _ = subscriber.try_init();
+
+    if let Err(e) = run(parse_args()) {
+        eprintln!("me-replay failed: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,149 @@
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::{
+    outbox::OutboxEntry,
+    transport::{EventSink, TransportError},
+};
+
+/// Message type of the events that are conflated.
+const CONFLATED_TYPE: &str = "stock.price";
+
+#[derive(Default)]
+struct Conflated {
+    last_sent: Option<Instant>,
+    pending: Option<OutboxEntry>, // Newest price held back until the window ends
+}
+
+/// Sends at most one price per stock per `window` to `inner`. A price that comes in sooner
+/// is held back, replacing any price already held for the stock, and sent when the window
+/// ends, so a burst of prices ends with the latest one. Every other event goes straight
+/// through.
+///
+/// Held prices count as published for the outbox. They are market data, so one lost in a
+/// crash is superseded by the next price of the stock.
+pub struct ConflatingSink {
+    inner: Arc<dyn EventSink>,
+    window: Duration,
+    stocks: Arc<Mutex<HashMap<String, Conflated>>>, // By routing key
+}
+
+impl ConflatingSink {
+    pub fn new(inner: Arc<dyn EventSink>, window: Duration) -> Self {
+        Self {
+            inner,
+            window,
+            stocks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Send the prices held back right away, e.g. on shutdown.
+    pub async fn flush(&self) {
+        let pending: Vec<OutboxEntry> = self
+            .stocks
+            .lock()
+            .unwrap()
+            .values_mut()
+            .filter_map(|conflated| conflated.pending.take())
+            .collect();
+        for entry in pending {
+            if let Err(e) = self.inner.publish(&entry).await {
+                warn!(
+                    "Failed to publish conflated {} event {}: {}",
+                    entry.routing_key, entry.seq, e
+                );
+            }
+        }
+    }
+
+    /// Send the price held for `routing_key` at `deadline`, retrying a window later until it
+    /// is sent or a newer one takes over.
+    fn send_later(&self, routing_key: String, deadline: Instant) {
+        let (inner, window, stocks) = (
+            Arc::clone(&self.inner),
+            self.window,
+            Arc::clone(&self.stocks),
+        );
+        tokio::spawn(async move {
+            let mut deadline = deadline;
+            loop {
+                tokio::time::sleep_until(deadline).await;
+                let entry = {
+                    let mut stocks = stocks.lock().unwrap();
+                    let conflated = stocks.entry(routing_key.clone()).or_default();
+                    let Some(entry) = conflated.pending.take() else {
+                        return;
+                    };
+                    conflated.last_sent = Some(Instant::now());
+                    entry
+                };
+
+                match inner.publish(&entry).await {
+                    Ok(()) => {
+                        debug!("Published conflated {} event {}", routing_key, entry.seq);
+                        return;
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to publish conflated {} event {}: {}",
+                            routing_key, entry.seq, e
+                        );
+                        let mut stocks = stocks.lock().unwrap();
+                        let conflated = stocks.entry(routing_key.clone()).or_default();
+                        if conflated.pending.is_some() {
+                            return; // A newer price is already scheduled
+                        }
+                        conflated.pending = Some(entry);
+                        deadline = Instant::now() + window;
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl EventSink for ConflatingSink {
+    async fn publish(&self, entry: &OutboxEntry) -> Result<(), TransportError> {
+        if entry.message_type != CONFLATED_TYPE {
+            return self.inner.publish(entry).await;
+        }
+
+        let deadline = {
+            let mut stocks = self.stocks.lock().unwrap();
+            let conflated = stocks.entry(entry.routing_key.clone()).or_default();
+            let now = Instant::now();
+            match conflated.last_sent {
+                Some(last_sent) if now < last_sent + self.window || conflated.pending.is_some() => {
+                    let scheduled = conflated.pending.replace(entry.clone()).is_some();
+                    debug!(
+                        "Holding back {} event {} until the conflation window ends",
+                        entry.routing_key, entry.seq
+                    );
+                    if scheduled {
+                        return Ok(());
+                    }
+                    Some(last_sent + self.window)
+                }
+                _ => {
+                    conflated.last_sent = Some(now);
+                    None
+                }
+            }
+        };
+
+        match deadline {
+            Some(deadline) => {
+                self.send_later(entry.routing_key.clone(), deadline);
+                Ok(())
+            }
+            None => self.inner.publish(entry).await,
+        }
+    }
+}
@@ -1,288 +1,157 @@
-use amqprs::{
-    channel::{BasicAckArguments, Channel},
-    consumer::AsyncConsumer,
-    BasicProperties, Deliver,
-};
 use async_trait::async_trait;
-use std::{cmp::Reverse, sync::Arc};
-use tokio::sync::RwLock;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{Notify, RwLock, RwLockWriteGuard};
 use tracing::{debug, error, info, warn};
-use tracing_subscriber::field::debug;
 
 use crate::{
-    matching_pq::SellOrder,
-    models::{
-        LimitSellCancelData, LimitSellCancelRequest, LimitSellCancelResponse, LimitSellRequest,
-        MarketBuyData, MarketBuyRequest, MarketBuyResponse, OrderUpdate, StockPrice,
-    },
-    rabbitmq::RabbitMQClient,
+    engine::{self, OrderCommand},
+    envelope::{self, Correlation},
+    journal::JournalEntry,
+    models::{BookSnapshot, PriceSnapshot},
+    outbox::Outbox,
+    persistence::{now_millis, Persistence},
     state::AppState,
+    transport::{Disposition, EventSink, InboundMessage, OrderHandler, OrderSource},
 };
 
-pub struct OrderConsumer {
-    state: Arc<RwLock<AppState>>,
-    rabbitmq_client: Arc<RabbitMQClient>,
+/// How long an order waits for its events to be confirmed before it is requeued.
+const CONFIRM_WAIT: Duration = Duration::from_secs(5);
+
+/// Lets cancellations take the state write lock ahead of other orders waiting for it.
+#[derive(Default)]
+struct PriorityGate {
+    waiting: AtomicUsize, // Cancellations waiting for the state lock
+    cleared: Notify,      // Notified when the last of them got it
 }
 
-#[derive(Debug)]
-struct MarketBuyResult {
-    market_buy_response: MarketBuyResponse,
-    order_updates: Option<Vec<OrderUpdate>>,
-    have_completed_sell: bool, // True if at least one sell order was fully completed
+pub struct OrderConsumer {
+    state: Arc<RwLock<AppState>>,
+    sink: Arc<dyn EventSink>,
+    persistence: Option<Arc<Persistence>>,
+    outbox: Arc<Outbox>,
+    draining: Arc<AtomicBool>,
+    in_flight: Arc<RwLock<()>>, // Held for reading while a message is being handled
+    priority: Arc<PriorityGate>,
+    confirm_wait: Duration,
 }
 
 impl OrderConsumer {
-    pub fn new(state: Arc<RwLock<AppState>>, client: Arc<RabbitMQClient>) -> Self {
+    pub fn new(
+        state: Arc<RwLock<AppState>>,
+        sink: Arc<dyn EventSink>,
+        persistence: Option<Arc<Persistence>>,
+        outbox: Arc<Outbox>,
+    ) -> Self {
         info!("Creating new OrderConsumer instance");
         Self {
             state,
-            rabbitmq_client: client,
+            sink,
+            persistence,
+            outbox,
+            draining: Arc::new(AtomicBool::new(false)),
+            in_flight: Arc::new(RwLock::new(())),
+            priority: Arc::new(PriorityGate::default()),
+            confirm_wait: CONFIRM_WAIT,
         }
     }
 
-    pub async fn setup(&self) -> Result<(), Box<dyn std::error::Error>> {
-        info!("Setting up OrderConsumer");
-        self.rabbitmq_client.setup_consumer(self.clone()).await
+    /// Override how long an order waits for its events to be confirmed before it is requeued.
+    pub fn with_confirm_wait(mut self, confirm_wait: Duration) -> Self {
+        self.confirm_wait = confirm_wait;
+        self
     }
 
-    fn create_mk_buy_fail_result(&self, stock_id: String, stock_tx_id: String) -> MarketBuyResult {
-        warn!(
-            "Creating failed market buy result for stock_id={}, tx_id={}",
-            stock_id, stock_tx_id
-        );
-        MarketBuyResult {
-            market_buy_response: MarketBuyResponse {
-                success: false,
-                data: MarketBuyData {
-                    stock_id: stock_id,
-                    stock_tx_id: stock_tx_id,
-                    price_total: None,
-                    quantity: None,
-                },
-            },
-            order_updates: None,
-            have_completed_sell: false,
-        }
+    /// Stop taking new messages and wait for the ones being handled to finish
+    /// (including publishing their events). Messages delivered after this point are
+    /// requeued for the next instance.
+    pub async fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        let _ = self.in_flight.write().await;
+        info!("All in-flight orders finished");
     }
 
-    /// Helper for performing market buy
-    async fn process_market_buy(&self, request: MarketBuyRequest) -> MarketBuyResult {
-        // Need to have lock the entire time to ensure no other sell occurs
-        // between ensuring we have enough shares and the actual buy/sell process.
-
-        debug!(
-            "Processing market buy request: stock={}, quantity={}, budget={}, user={}",
-            request.stock_id, request.quantity, request.budget, request.user_name
-        );
-
-        let mut state = self.state.write().await;
-
-        // Total number of shares on sale excluding those from the user requesting the buy order
-        let available_shares: u64 = state
-            .matching_pq
-            .get_all_orders(&request.stock_id)
-            .iter()
-            .filter(|sell_order| sell_order.user_name != request.user_name)
-            .map(|sell_order| sell_order.cur_quantity)
-            .sum();
-
-        debug!(
-            "Available shares for stock {}: {} (requested: {})",
-            request.stock_id, available_shares, request.quantity
-        );
+    pub async fn setup(&self, source: &impl OrderSource) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Setting up OrderConsumer");
+        source
+            .start(Arc::new(self.clone()))
+            .await
+            .map_err(|e| e as Box<dyn std::error::Error>)
+    }
 
-        // Check available shares
-        let mut shares_to_buy = request.quantity;
-        if shares_to_buy > available_shares {
-            warn!(
-                "Insufficient shares available for stock {}: available={}, requested={}",
-                request.stock_id, available_shares, shares_to_buy
-            );
-            return self.create_mk_buy_fail_result(request.stock_id, request.stock_tx_id);
+    /// Take the state write lock. Cancellations go first: other orders wait while one is
+    /// waiting, and let go of the lock again if one started waiting while they queued for it.
+    async fn lock_state(&self, command: &OrderCommand) -> RwLockWriteGuard<'_, AppState> {
+        let gate = &self.priority;
+        if matches!(command, OrderCommand::LimitSellCancel(_)) {
+            gate.waiting.fetch_add(1, Ordering::SeqCst);
+            let state = self.state.write().await;
+            if gate.waiting.fetch_sub(1, Ordering::SeqCst) == 1 {
+                gate.cleared.notify_waiters();
+            }
+            return state;
         }
 
-        // Dry-run: Clone the priority queue to calculate total cost without modifying state
-        // OPTIMIZE: This implementation is wildly inefficient but future problem it is!
-        // REFACTOR: Too much duplicate code between the dry run and the actual run.
-        let Some(original_queue) = state
-            .matching_pq
-            .get_stock_queue(&request.stock_id)
-            .cloned()
-        else {
-            return self.create_mk_buy_fail_result(request.stock_id, request.stock_tx_id);
-        };
-
-        let mut cloned_queue = original_queue;
-        let mut total_price_dry = 0.0;
-        let mut remaining_dry = shares_to_buy;
+        loop {
+            let cleared = gate.cleared.notified();
+            tokio::pin!(cleared);
+            cleared.as_mut().enable();
 
-        while remaining_dry > 0 {
-            if let Some(Reverse(order)) = cloned_queue.peek() {
-                if order.user_name == request.user_name {
-                    cloned_queue.pop();
-                    continue;
+            if gate.waiting.load(Ordering::SeqCst) == 0 {
+                let state = self.state.write().await;
+                if gate.waiting.load(Ordering::SeqCst) == 0 {
+                    return state;
                 }
-
-                let take = remaining_dry.min(order.cur_quantity);
-                total_price_dry += take as f64 * order.price;
-                remaining_dry -= take;
-                cloned_queue.pop();
-            } else {
-                break;
             }
+            cleared.await;
         }
+    }
 
-        debug!("Dry run calculation: total_price={}", total_price_dry);
-
-        // Budget validation
-        if total_price_dry > request.budget {
-            warn!(
-                "Insufficient budget for market buy: required={}, available={}",
-                total_price_dry, request.budget
-            );
-            return self.create_mk_buy_fail_result(request.stock_id, request.stock_tx_id);
-        }
-
-        // Proceed with actual purchase processing
-        let mut total_price = 0.0;
-        let mut shares_bought = 0;
-        let mut order_updates: Vec<OrderUpdate> = Vec::new();
-        let mut have_completed_sell = false;
-        while shares_to_buy > 0 {
-            // Assume the sell order always exist due to the above shares quantity check.
-            // If it somehow fails, we will break out early. But it is a critical issue at this point.
-            let Some(mut top_sell_order) = state.matching_pq.pop(&request.stock_id) else {
-                error!(
-                    "Critical: Performing actual buy when there isn't enough valid shares to buy"
-                );
-                break;
-            };
-
-            // Skip if the sell order is from the user requesting the buy order
-            if top_sell_order.user_name == request.user_name {
-                debug!("Skipping sell order from same user: {}", request.user_name);
-                continue;
-            }
-
-            let purchase_all = shares_to_buy >= top_sell_order.cur_quantity;
-            debug!(
-                "Processing sell order: price={}, quantity={}, purchase_all={}",
-                top_sell_order.price, top_sell_order.cur_quantity, purchase_all
-            );
-
-            // Complete the top sell order or perform partial sell on the top sell order
-            if purchase_all {
-                total_price += top_sell_order.cur_quantity as f64 * top_sell_order.price;
-                shares_bought += top_sell_order.cur_quantity;
-                shares_to_buy -= top_sell_order.cur_quantity;
-
-                let sold_qty = top_sell_order.cur_quantity;
-                top_sell_order.cur_quantity = 0;
-
-                order_updates.push(OrderUpdate {
-                    stock_id: top_sell_order.stock_id.clone(),
-                    price: top_sell_order.price,
-                    remaining_quantity: top_sell_order.cur_quantity,
-                    sold_quantity: sold_qty,
-                    stock_tx_id: top_sell_order.stock_tx_id.clone(),
-                    user_name: top_sell_order.user_name.clone(),
-                });
-
-                have_completed_sell = true;
-            } else {
-                total_price += shares_to_buy as f64 * top_sell_order.price;
-                shares_bought += shares_to_buy;
-
-                top_sell_order.cur_quantity -= shares_to_buy;
-                top_sell_order.partially_sold = true;
-
-                let sold_qty = shares_to_buy;
-                shares_to_buy = 0;
-
-                order_updates.push(OrderUpdate {
-                    stock_id: top_sell_order.stock_id.clone(),
-                    price: top_sell_order.price,
-                    remaining_quantity: top_sell_order.cur_quantity,
-                    sold_quantity: sold_qty,
-                    stock_tx_id: top_sell_order.stock_tx_id.clone(),
-                    user_name: top_sell_order.user_name.clone(),
-                });
-
-                // Reinsert the sell order back into the PQ since it is a partial sell
-                state.matching_pq.insert(top_sell_order);
-            };
-
-            debug!(
-                "Processed order: shares_bought={}, remaining_to_buy={}, total_price={}",
-                shares_bought, shares_to_buy, total_price
-            );
-        }
-
-        debug!(
-            "Market buy completed: shares={}, total_price={}, updates={}",
-            shares_bought,
-            total_price,
-            order_updates.len()
-        );
-
-        let response = MarketBuyResponse {
-            success: true,
-            data: MarketBuyData {
-                stock_id: request.stock_id,
-                stock_tx_id: request.stock_tx_id,
-                quantity: Some(shares_bought),
-                price_total: Some(total_price),
+    /// Journal and apply the order under the state write lock, adding its events to the
+    /// outbox. Returns the sequence number of its last event. A redelivered order is not
+    /// applied again, only the sequence number from the first time is returned. An order that
+    /// cannot be journaled is not applied at all; one whose events cannot be written to the
+    /// outbox log is applied, but an error is returned so it is requeued.
+    async fn execute(
+        &self,
+        command: OrderCommand,
+        correlation: Correlation,
+    ) -> std::io::Result<u64> {
+        let mut state = self.lock_state(&command).await;
+        let key = command.key();
+
+        let entry = match &self.persistence {
+            Some(persistence) => persistence.record(&state, command, correlation)?,
+            None => JournalEntry {
+                seq: state.last_seq + 1,
+                received_at: now_millis(),
+                command,
+                correlation: Some(correlation),
             },
         };
 
-        return MarketBuyResult {
-            market_buy_response: response,
-            order_updates: Some(order_updates),
-            have_completed_sell,
-        };
+        state.last_seq = entry.seq;
+        let events = engine::apply(&mut state, entry.command, entry.received_at);
+        self.outbox
+            .push(entry.seq, entry.correlation.as_ref(), &events)?;
+        Ok(state.recent_orders.get(&key).unwrap_or(state.event_seq))
     }
 
-    /// Helper for publishing stock price.
-    /// Send in the latest stock price or `None` (AKA `null`) if it does not exist.
-    async fn publish_stock_price_helper(&self, stock_id: &str) {
-        debug!("Publishing stock price update for {}", stock_id);
-        let payload: StockPrice = {
-            let state = self.state.read().await;
-            if let Some(top_order) = state.matching_pq.peek(stock_id) {
-                debug!(
-                    "Current price for {}: {} ({})",
-                    stock_id, top_order.price, top_order.stock_name
-                );
-                StockPrice {
-                    stock_id: stock_id.to_string(),
-                    stock_name: Some(top_order.stock_name.clone()),
-                    current_price: Some(top_order.price),
-                }
-            } else {
-                warn!("No price available for stock {}", stock_id);
-                StockPrice {
-                    stock_id: stock_id.to_string(),
-                    stock_name: None,
-                    current_price: None,
-                }
-            }
-        };
-
-        if let Err(e) = self
-            .rabbitmq_client
-            .publish_stock_price(payload.clone())
-            .await
-        {
-            error!(
-                "Failed to publish latest stock price for {}: {}",
-                stock_id, e
-            );
-        } else {
-            debug!(
-                "Published stock price update: id={}, name={:?}, price={:?}",
-                payload.stock_id, payload.stock_name, payload.current_price
-            );
+    /// Publish the outbox and wait until every event up to `event_seq` is confirmed. If that
+    /// does not happen within `CONFIRM_WAIT` the retry task takes over and `false` is returned.
+    async fn publish_events(&self, event_seq: u64) -> bool {
+        if self.outbox.flush(self.sink.as_ref()).await {
+            return true;
         }
+        self.outbox.schedule_retry();
+        self.outbox
+            .wait_confirmed(event_seq, self.confirm_wait)
+            .await
     }
 }
 
@@ -290,166 +159,90 @@ impl Clone for OrderConsumer {
     fn clone(&self) -> Self {
         Self {
             state: Arc::clone(&self.state),
-            rabbitmq_client: Arc::clone(&self.rabbitmq_client),
+            sink: Arc::clone(&self.sink),
+            persistence: self.persistence.clone(),
+            outbox: Arc::clone(&self.outbox),
+            draining: Arc::clone(&self.draining),
+            in_flight: Arc::clone(&self.in_flight),
+            priority: Arc::clone(&self.priority),
+            confirm_wait: self.confirm_wait,
         }
     }
 }
 
 #[async_trait]
-impl AsyncConsumer for OrderConsumer {
-    async fn consume(
-        &mut self,
-        channel: &Channel,
-        deliver: Deliver,
-        _: BasicProperties,
-        content: Vec<u8>,
-    ) {
-        let routing_key = deliver.routing_key().to_string();
+impl OrderHandler for OrderConsumer {
+    async fn handle(&self, message: &InboundMessage) -> Disposition {
+        let _in_flight = self.in_flight.read().await;
+        if self.draining.load(Ordering::SeqCst) {
+            debug!(
+                "Shutting down, requeueing message with routing key {}",
+                message.routing_key
+            );
+            return Disposition::Requeue;
+        }
+
+        let routing_key = &message.routing_key;
         debug!(
-            "Received message with routing key: {} (delivery tag: {})",
-            routing_key,
-            deliver.delivery_tag()
+            "Received message with routing key: {} (redelivered: {})",
+            routing_key, message.redelivered
         );
 
-        // Extract the order type from routing key pattern: order.{order_type}.shard_{shard_id}
-        let parts: Vec<&str> = routing_key.split('.').collect();
-
-        // Handle the message based on the order type part of the routing key
-        match parts.get(1) {
-            Some(&"market_buy") => {
-                if let Ok(request) = serde_json::from_slice::<MarketBuyRequest>(&content) {
-                    let stock_id = request.stock_id.clone(); // Save for later
-                    let buy_result = self.process_market_buy(request).await;
-
-                    // Publish buy completion event (as failure or success)
-                    if let Err(e) = self
-                        .rabbitmq_client
-                        .publish_buy_completed(&buy_result.market_buy_response)
-                        .await
-                    {
-                        error!("Failed to publish buy completion event: {}", e);
-                    }
-
-                    // Publish all order updates
-                    match buy_result.order_updates {
-                        Some(order_updates) => {
-                            for order in order_updates {
-                                if let Err(e) =
-                                    self.rabbitmq_client.publish_sale_update(&order).await
-                                {
-                                    error!("Failed to publish order update: {}", e);
-                                }
-                            }
-                        }
-                        None => { /* do nothing */ }
-                    }
-
-                    // Publish latest stock price
-                    if buy_result.have_completed_sell {
-                        self.publish_stock_price_helper(&stock_id).await;
-                    }
-                } else {
-                    error!("Failed to parse market buy order",);
-                    debug!(
-                        "Parse Failure Content: {}",
-                        String::from_utf8_lossy(&content)
+        // Handle the message based on the envelope's type, or the order type part of the
+        // routing key for orders published without an envelope
+        let command =
+            match envelope::parse_order(routing_key, message.envelope.as_ref(), &message.content) {
+                Ok(command) => command,
+                Err(e) => {
+                    // Redelivering it would fail the same way, so park it in the dead-letter queue
+                    error!(
+                        "Dead-lettering message with routing key {}: {}",
+                        routing_key, e
                     );
-                }
-            }
-            Some(&"limit_sell") => {
-                if let Ok(request) = serde_json::from_slice::<LimitSellRequest>(&content) {
-                    let stock_id = request.stock_id.clone(); // Save for later
-                    let sell_order = SellOrder {
-                        stock_id: request.stock_id,
-                        stock_name: request.stock_name,
-                        stock_tx_id: request.stock_tx_id,
-                        price: request.price,
-                        partially_sold: false,
-                        ori_quantity: request.quantity,
-                        cur_quantity: request.quantity,
-                        user_name: request.user_name,
-                    };
-
-                    let mut state = self.state.write().await;
-                    state.matching_pq.insert(sell_order);
-                    drop(state); // Release write lock
-
-                    // Publish latest stock price
-                    // TODO: only publish if price has changed
-                    self.publish_stock_price_helper(&stock_id).await;
-                } else {
-                    error!("Failed to parse limit sell order");
                     debug!(
                         "Parse Failure Content: {}",
-                        String::from_utf8_lossy(&content)
+                        String::from_utf8_lossy(&message.content)
                     );
+                    return Disposition::DeadLetter(e.to_string());
                 }
-            }
-            Some(&"limit_sell_cancellation") => {
-                if let Ok(request) = serde_json::from_slice::<LimitSellCancelRequest>(&content) {
-                    let some_order = {
-                        let mut state = self.state.write().await;
-                        state
-                            .matching_pq
-                            .remove_order(&request.stock_id, &request.stock_tx_id)
-                    }; // Release write lock
-
-                    if let Some(order) = some_order {
-                        let stock_id = order.stock_id.clone(); // Save for later
-
-                        // Publish cancellation response
-                        let response = LimitSellCancelResponse {
-                            success: true,
-                            data: Some(LimitSellCancelData {
-                                stock_id: order.stock_id,
-                                stock_tx_id: order.stock_tx_id,
-                                partially_sold: order.partially_sold,
-                                ori_quantity: order.ori_quantity,
-                                cur_quantity: order.cur_quantity,
-                                sold_quantity: order.ori_quantity - order.cur_quantity,
-                                price: order.price,
-                            }),
-                        };
-
-                        if let Err(e) = self
-                            .rabbitmq_client
-                            .publish_order_cancelled(&response)
-                            .await
-                        {
-                            error!("Failed to publish cancellation response: {}", e);
-                        }
-
-                        // Publish latest stock price
-                        // TODO: only publish if price has changed
-                        self.publish_stock_price_helper(&stock_id).await;
-                    } else {
-                        let err_res = LimitSellCancelResponse {
-                            success: false,
-                            data: None,
-                        };
+            };
 
-                        if let Err(e) = self.rabbitmq_client.publish_order_cancelled(&err_res).await
-                        {
-                            error!("Failed to publish cancellation response: {}", e);
-                        }
-                    }
-                } else {
-                    error!("Failed to parse limit sell cancellation order");
-                    debug!(
-                        "Parse Failure Content: {}",
-                        String::from_utf8_lossy(&content)
-                    );
-                }
-            }
-            _ => {
-                error!("Unknown routing key: {}", routing_key);
+        // Only acknowledge once every resulting event is confirmed by the broker. Otherwise
+        // the order is requeued; the redelivery is recognised by its key and not applied twice.
+        let correlation = Correlation::for_order(message, &command);
+        debug!(
+            "Handling {} (correlation id {})",
+            command.key(),
+            correlation.correlation_id
+        );
+        let event_seq = match self.execute(command, correlation).await {
+            Ok(event_seq) => event_seq,
+            Err(e) => {
+                error!(
+                    "Failed to persist message with routing key {}, requeueing it: {}",
+                    routing_key, e
+                );
+                return Disposition::Requeue;
             }
+        };
+        if self.publish_events(event_seq).await {
+            Disposition::Ack
+        } else {
+            warn!(
+                "Events for message with routing key {} not confirmed, requeueing it",
+                routing_key
+            );
+            Disposition::Requeue
         }
+    }
 
-        // Acknowledge the message
-        let args = BasicAckArguments::new(deliver.delivery_tag(), false);
-        if let Err(e) = channel.basic_ack(args).await {
-            error!("Failed to acknowledge message: {}", e);
-        }
+    async fn book_snapshot(&self, stock_id: &str) -> BookSnapshot {
+        let state = self.state.read().await;
+        engine::book_snapshot(&state, stock_id)
+    }
+
+    async fn price_snapshot(&self) -> PriceSnapshot {
+        let state = self.state.read().await;
+        engine::price_snapshot(&state)
     }
 }
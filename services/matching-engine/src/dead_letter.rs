@@ -0,0 +1,173 @@
+use amqprs::{BasicProperties, FieldName, FieldTable, FieldValue};
+
+/// Headers added when a message is dead-lettered, dropped again when it is re-driven. The
+/// stock price service writes the same ones.
+pub const FAILURE_HEADERS: [&str; 6] = [
+    "x-failure-reason",
+    "x-original-exchange",
+    "x-original-routing-key",
+    "x-shard-id",
+    "x-failed-at",
+    "x-service",
+];
+
+/// Headers the broker adds when it dead-letters a message itself (rejected, expired or
+/// dropped from a full queue), also dropped when it is re-driven.
+pub const DEATH_HEADERS: [&str; 7] = [
+    "x-death",
+    "x-first-death-exchange",
+    "x-first-death-queue",
+    "x-first-death-reason",
+    "x-last-death-exchange",
+    "x-last-death-queue",
+    "x-last-death-reason",
+];
+
+/// Why a message was dead-lettered and where it came from.
+pub struct Failure<'a> {
+    pub reason: &'a str,
+    pub exchange: &'a str,
+    pub routing_key: &'a str,
+    pub shard_id: Option<i64>,
+    pub failed_at: u64, // Unix milliseconds
+    pub service: &'a str,
+}
+
+/// Add the headers describing `failure` to the message's own.
+pub fn add_failure_headers(properties: &mut BasicProperties, failure: &Failure) {
+    let mut headers = properties.headers().cloned().unwrap_or_default();
+    let mut set = |name: &str, value: FieldValue| {
+        if let Ok(name) = name.try_into() {
+            headers.insert(name, value);
+        }
+    };
+    set("x-failure-reason", failure.reason.into());
+    set("x-original-exchange", failure.exchange.into());
+    set("x-original-routing-key", failure.routing_key.into());
+    if let Some(shard_id) = failure.shard_id {
+        set("x-shard-id", FieldValue::l(shard_id));
+    }
+    set("x-failed-at", FieldValue::l(failure.failed_at as i64));
+    set("x-service", failure.service.into());
+    properties.with_headers(headers);
+}
+
+/// Drop the failure headers, leaving the headers the message had before it failed.
+pub fn remove_failure_headers(properties: &mut BasicProperties) {
+    let mut headers = properties.headers().cloned().unwrap_or_default();
+    for name in FAILURE_HEADERS.into_iter().chain(DEATH_HEADERS) {
+        if let Ok(name) = name.try_into() {
+            headers.remove(&name);
+        }
+    }
+    properties.with_headers(headers);
+}
+
+/// The exchange and routing key a dead-lettered message was originally delivered from,
+/// taken from the failure headers or, for a message the broker dead-lettered, the most
+/// recent `x-death` entry.
+pub fn original_destination(properties: &BasicProperties) -> Option<(String, String)> {
+    let headers = properties.headers()?;
+    let field = |table: &FieldTable, name: &str| {
+        let name: FieldName = name.try_into().ok()?;
+        table.get(&name).cloned()
+    };
+
+    if let (Some(exchange), Some(routing_key)) = (
+        field(headers, "x-original-exchange"),
+        field(headers, "x-original-routing-key"),
+    ) {
+        return Some((exchange.to_string(), routing_key.to_string()));
+    }
+
+    // The broker puts the most recent death first
+    let FieldValue::A(deaths) = field(headers, "x-death")? else {
+        return None;
+    };
+    let FieldValue::F(death) = Vec::from(deaths).into_iter().next()? else {
+        return None;
+    };
+    let exchange = field(&death, "exchange")?.to_string();
+    let FieldValue::A(routing_keys) = field(&death, "routing-keys")? else {
+        return None;
+    };
+    let routing_key = Vec::from(routing_keys).into_iter().next()?.to_string();
+    Some((exchange, routing_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redriven_messages_lose_only_the_failure_headers() {
+        let mut headers = FieldTable::new();
+        headers.insert("x-schema-version".try_into().unwrap(), FieldValue::l(1));
+        let mut properties = BasicProperties::default().with_headers(headers).finish();
+        let original = properties.clone();
+        add_failure_headers(
+            &mut properties,
+            &Failure {
+                reason: "unknown order type",
+                exchange: "order_exchange",
+                routing_key: "order.short_sell",
+                shard_id: Some(0),
+                failed_at: 1000,
+                service: "matching-engine",
+            },
+        );
+        assert_eq!(
+            original_destination(&properties),
+            Some(("order_exchange".to_string(), "order.short_sell".to_string()))
+        );
+
+        remove_failure_headers(&mut properties);
+        assert_eq!(properties.headers(), original.headers());
+        assert_eq!(original_destination(&properties), None);
+    }
+
+    #[test]
+    fn broker_dead_letters_go_back_where_their_last_death_came_from() {
+        let array = |values: Vec<FieldValue>| FieldValue::A(values.try_into().unwrap());
+        let death = |exchange: &str, routing_key: &str, reason: &str| {
+            let mut death = FieldTable::new();
+            death.insert("count".try_into().unwrap(), FieldValue::l(1));
+            death.insert("reason".try_into().unwrap(), reason.into());
+            death.insert("queue".try_into().unwrap(), "some_queue".into());
+            death.insert("exchange".try_into().unwrap(), exchange.into());
+            death.insert(
+                "routing-keys".try_into().unwrap(),
+                array(vec![routing_key.into()]),
+            );
+            FieldValue::F(death)
+        };
+
+        let mut headers = FieldTable::new();
+        headers.insert("x-schema-version".try_into().unwrap(), FieldValue::l(1));
+        let original = BasicProperties::default()
+            .with_headers(headers.clone())
+            .finish();
+        headers.insert(
+            "x-death".try_into().unwrap(),
+            array(vec![
+                death("order_exchange", "order.limit_sell.0", "expired"),
+                death("other_exchange", "other.key", "rejected"),
+            ]),
+        );
+        headers.insert(
+            "x-first-death-reason".try_into().unwrap(),
+            "rejected".into(),
+        );
+        let mut properties = BasicProperties::default().with_headers(headers).finish();
+
+        assert_eq!(
+            original_destination(&properties),
+            Some((
+                "order_exchange".to_string(),
+                "order.limit_sell.0".to_string()
+            ))
+        );
+        remove_failure_headers(&mut properties);
+        assert_eq!(properties.headers(), original.headers());
+    }
+}
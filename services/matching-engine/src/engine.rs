@@ -0,0 +1,767 @@
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, fmt};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    matching_pq::SellOrder,
+    models::{
+        BookSnapshot, BookUpdate, EventMeta, Fill, LevelChange, LimitSellCancelData,
+        LimitSellCancelRequest, LimitSellCancelResponse, LimitSellRequest, MarketBuyData,
+        MarketBuyRequest, MarketBuyResponse, OrderUpdate, PriceLevel, PriceSnapshot, Side,
+        StockPrice, Trade, TradingStatus,
+    },
+    state::{AppState, Quote},
+};
+
+/// Levels per side covered by `book_checksum`.
+pub const BOOK_CHECKSUM_DEPTH: usize = 10;
+
+/// An inbound order, tagged with the order type segment of its routing key
+/// (`order.{order_type}.shard_{shard_id}`).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "body", rename_all = "snake_case")]
+pub enum OrderCommand {
+    MarketBuy(MarketBuyRequest),
+    LimitSell(LimitSellRequest),
+    #[serde(rename = "limit_sell_cancellation")]
+    LimitSellCancel(LimitSellCancelRequest),
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    UnknownOrderType(String),
+    Malformed {
+        order_type: String,
+        source: serde_json::Error,
+    },
+    UnsupportedSchemaVersion {
+        message_type: String,
+        version: i32,
+    },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::UnknownOrderType(order_type) => {
+                write!(f, "unknown order type: {}", order_type)
+            }
+            CommandError::Malformed { order_type, source } => {
+                write!(f, "failed to parse {} order: {}", order_type, source)
+            }
+            CommandError::UnsupportedSchemaVersion {
+                message_type,
+                version,
+            } => write!(
+                f,
+                "unsupported schema version {} of {}",
+                version, message_type
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Order type segment of a routing key: `order.{order_type}.shard_{shard_id}`.
+pub fn order_type(routing_key: &str) -> &str {
+    routing_key.split('.').nth(1).unwrap_or_default()
+}
+
+impl OrderCommand {
+    pub fn parse(order_type: &str, content: &[u8]) -> Result<Self, CommandError> {
+        let malformed = |source| CommandError::Malformed {
+            order_type: order_type.to_string(),
+            source,
+        };
+
+        match order_type {
+            "market_buy" => serde_json::from_slice(content)
+                .map(OrderCommand::MarketBuy)
+                .map_err(malformed),
+            "limit_sell" => serde_json::from_slice(content)
+                .map(OrderCommand::LimitSell)
+                .map_err(malformed),
+            "limit_sell_cancellation" => serde_json::from_slice(content)
+                .map(OrderCommand::LimitSellCancel)
+                .map_err(malformed),
+            _ => Err(CommandError::UnknownOrderType(order_type.to_string())),
+        }
+    }
+
+    pub fn stock_id(&self) -> &str {
+        match self {
+            OrderCommand::MarketBuy(request) => &request.stock_id,
+            OrderCommand::LimitSell(request) => &request.stock_id,
+            OrderCommand::LimitSellCancel(request) => &request.stock_id,
+        }
+    }
+
+    pub fn stock_tx_id(&self) -> &str {
+        match self {
+            OrderCommand::MarketBuy(request) => &request.stock_tx_id,
+            OrderCommand::LimitSell(request) => &request.stock_tx_id,
+            OrderCommand::LimitSellCancel(request) => &request.stock_tx_id,
+        }
+    }
+
+    /// Identifies the order across redeliveries: order type plus `stock_tx_id`.
+    pub fn key(&self) -> String {
+        match self {
+            OrderCommand::MarketBuy(request) => format!("market_buy:{}", request.stock_tx_id),
+            OrderCommand::LimitSell(request) => format!("limit_sell:{}", request.stock_tx_id),
+            OrderCommand::LimitSellCancel(request) => {
+                format!("limit_sell_cancellation:{}", request.stock_tx_id)
+            }
+        }
+    }
+}
+
+/// Everything the engine publishes as a result of applying an order.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", content = "body", rename_all = "snake_case")]
+pub enum EngineEvent {
+    BuyCompleted(MarketBuyResponse),
+    SaleUpdate(OrderUpdate),
+    OrderCancelled(LimitSellCancelResponse),
+    Trade(Trade),
+    StockPrice(StockPrice),
+    BookUpdate(BookUpdate),
+}
+
+impl EngineEvent {
+    pub fn exchange(&self) -> &'static str {
+        match self {
+            EngineEvent::StockPrice(_) => "stock_prices_exchange",
+            EngineEvent::BookUpdate(_) => "market_data_exchange",
+            _ => "order_update_exchange",
+        }
+    }
+
+    /// Message type in the envelope the event is published with.
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            EngineEvent::BuyCompleted(_) => "order.buy_completed",
+            EngineEvent::SaleUpdate(_) => "order.sale_update",
+            EngineEvent::OrderCancelled(_) => "order.cancelled",
+            EngineEvent::Trade(_) => "order.trade",
+            EngineEvent::StockPrice(_) => "stock.price",
+            EngineEvent::BookUpdate(_) => "book.update",
+        }
+    }
+
+    pub fn routing_key(&self) -> String {
+        match self {
+            EngineEvent::BuyCompleted(_) => "order.buy_completed".to_string(),
+            EngineEvent::SaleUpdate(_) => "order.sale_update".to_string(),
+            EngineEvent::OrderCancelled(_) => "order.cancelled".to_string(),
+            EngineEvent::Trade(_) => "order.trade".to_string(),
+            EngineEvent::StockPrice(price) => format!("stock.price.{}", price.stock_id),
+            EngineEvent::BookUpdate(update) => format!("book.update.{}", update.stock_id),
+        }
+    }
+
+    pub fn meta(&self) -> &EventMeta {
+        match self {
+            EngineEvent::BuyCompleted(payload) => &payload.meta,
+            EngineEvent::SaleUpdate(payload) => &payload.meta,
+            EngineEvent::OrderCancelled(payload) => &payload.meta,
+            EngineEvent::Trade(payload) => &payload.meta,
+            EngineEvent::StockPrice(payload) => &payload.meta,
+            EngineEvent::BookUpdate(payload) => &payload.meta,
+        }
+    }
+
+    fn meta_mut(&mut self) -> &mut EventMeta {
+        match self {
+            EngineEvent::BuyCompleted(payload) => &mut payload.meta,
+            EngineEvent::SaleUpdate(payload) => &mut payload.meta,
+            EngineEvent::OrderCancelled(payload) => &mut payload.meta,
+            EngineEvent::Trade(payload) => &mut payload.meta,
+            EngineEvent::StockPrice(payload) => &mut payload.meta,
+            EngineEvent::BookUpdate(payload) => &mut payload.meta,
+        }
+    }
+
+    pub fn payload(&self) -> serde_json::Result<Vec<u8>> {
+        match self {
+            EngineEvent::BuyCompleted(payload) => serde_json::to_vec(payload),
+            EngineEvent::SaleUpdate(payload) => serde_json::to_vec(payload),
+            EngineEvent::OrderCancelled(payload) => serde_json::to_vec(payload),
+            EngineEvent::Trade(payload) => serde_json::to_vec(payload),
+            EngineEvent::StockPrice(payload) => serde_json::to_vec(payload),
+            EngineEvent::BookUpdate(payload) => serde_json::to_vec(payload),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MarketBuyResult {
+    market_buy_response: MarketBuyResponse,
+    order_updates: Option<Vec<OrderUpdate>>,
+    trades: Vec<Trade>,
+}
+
+/// Apply a single order to the book and return the events it produced, in publish order.
+/// This is the only place the book is mutated, so the consumer, startup recovery and any
+/// offline tooling all go through the exact same matching code.
+///
+/// `now` is the time the order was received (Unix milliseconds). It is recorded in the
+/// journal, so replaying the journal stamps events exactly as they were the first time.
+///
+/// An order among `state.recent_orders` is a redelivery and produces no events, wherever it
+/// comes from.
+pub fn apply(state: &mut AppState, command: OrderCommand, now: u64) -> Vec<EngineEvent> {
+    let key = command.key();
+    if state.recent_orders.get(&key).is_some() {
+        info!("Order {} was already applied, not applying it again", key);
+        return Vec::new();
+    }
+    let stock_id = command.stock_id().to_string();
+    let levels = state.matching_pq.levels(&stock_id);
+    let mut events = match_order(state, command);
+
+    // Price levels the order changed, if any, last
+    let new_levels = state.matching_pq.levels(&stock_id);
+    let changes = level_changes(&levels, &new_levels);
+    if !changes.is_empty() {
+        events.push(EngineEvent::BookUpdate(BookUpdate {
+            stock_id,
+            book_seq: 0,
+            changes,
+            checksum: book_checksum(&new_levels, &[]),
+            meta: EventMeta::default(),
+        }));
+    }
+
+    // Never let the clock go backwards, even if the wall clock does
+    let timestamp = now.max(state.last_timestamp);
+    state.last_timestamp = timestamp;
+
+    // Complete the quotes, and drop those that say nothing new
+    events.retain_mut(|event| match event {
+        EngineEvent::Trade(trade) => {
+            state
+                .trade_stats
+                .entry(trade.stock_id.clone())
+                .or_default()
+                .record(trade.price, trade.quantity, timestamp);
+            true
+        }
+        EngineEvent::StockPrice(price) => {
+            // Trades of this order come before its price, so they are included
+            add_last_trade(state, price, timestamp);
+
+            let quote = Quote::of(price);
+            if state.last_quotes.get(&price.stock_id) == Some(&quote) {
+                debug!("Quote of {} unchanged, not publishing it", price.stock_id);
+                return false;
+            }
+            state.last_quotes.insert(price.stock_id.clone(), quote);
+            true
+        }
+        _ => true,
+    });
+
+    for event in events.iter_mut() {
+        state.event_seq += 1;
+        *event.meta_mut() = EventMeta {
+            shard_id: state.shard_id,
+            seq: state.event_seq,
+            timestamp,
+        };
+
+        match event {
+            EngineEvent::StockPrice(price) => {
+                let stock_seq = state.price_seqs.entry(price.stock_id.clone()).or_default();
+                *stock_seq += 1;
+                price.stock_seq = *stock_seq;
+                state.price_times.insert(price.stock_id.clone(), timestamp);
+            }
+            EngineEvent::BookUpdate(update) => {
+                let book_seq = state.book_seqs.entry(update.stock_id.clone()).or_default();
+                *book_seq += 1;
+                update.book_seq = *book_seq;
+            }
+            _ => {}
+        }
+    }
+
+    state.recent_orders.insert(key, state.event_seq);
+    events
+}
+
+/// Ask levels that differ between `before` and `after` (both cheapest first), cheapest first.
+fn level_changes(before: &[(f64, u64)], after: &[(f64, u64)]) -> Vec<LevelChange> {
+    let mut changes = Vec::new();
+    let (mut old, mut new) = (before.iter().peekable(), after.iter().peekable());
+    loop {
+        let (price, old_size, new_size) = match (old.peek(), new.peek()) {
+            (Some(&&(old_price, old_size)), Some(&&(new_price, new_size)))
+                if old_price == new_price =>
+            {
+                old.next();
+                new.next();
+                (new_price, old_size, new_size)
+            }
+            (Some(&&(old_price, old_size)), Some(&&(new_price, _))) if old_price < new_price => {
+                old.next();
+                (old_price, old_size, 0)
+            }
+            (Some(&&(old_price, old_size)), None) => {
+                old.next();
+                (old_price, old_size, 0)
+            }
+            (_, Some(&&(new_price, new_size))) => {
+                new.next();
+                (new_price, 0, new_size)
+            }
+            (None, None) => break,
+        };
+        if old_size != new_size {
+            changes.push(LevelChange {
+                side: Side::Ask,
+                price,
+                size: new_size,
+            });
+        }
+    }
+    changes
+}
+
+/// Fill in the last trade and session volume of a quote from the stock's trade stats.
+fn add_last_trade(state: &AppState, price: &mut StockPrice, timestamp: u64) {
+    if let Some(stats) = state.trade_stats.get(&price.stock_id) {
+        price.last_trade_price = Some(stats.last_price);
+        price.last_trade_size = Some(stats.last_quantity);
+        price.last_trade_time = Some(stats.last_timestamp);
+        price.session_volume = stats.session_volume(timestamp);
+    }
+}
+
+/// The current quote of every stock with a book or a published quote, as of the last
+/// published event. Each carries the `stock_seq` and timestamp of the stock's last price
+/// message, so a consumer that already has a newer price keeps it, and a price published
+/// before the snapshot but delivered after it is recognised as stale. Stocks without a
+/// recorded price time, as in snapshots from before they were kept, get the shard's last one.
+pub fn price_snapshot(state: &AppState) -> PriceSnapshot {
+    let mut stock_ids = state.matching_pq.get_all_stocks();
+    stock_ids.extend(state.last_quotes.keys().cloned());
+    stock_ids.sort();
+    stock_ids.dedup();
+
+    let prices = stock_ids
+        .iter()
+        .map(|stock_id| {
+            let timestamp = state
+                .price_times
+                .get(stock_id)
+                .copied()
+                .unwrap_or(state.last_timestamp);
+            let mut price = stock_price(state, stock_id);
+            add_last_trade(state, &mut price, timestamp);
+            price.stock_seq = state.price_seqs.get(stock_id).copied().unwrap_or_default();
+            price.meta = EventMeta {
+                shard_id: state.shard_id,
+                seq: state.event_seq,
+                timestamp,
+            };
+            price
+        })
+        .collect();
+    PriceSnapshot {
+        shard_id: state.shard_id,
+        prices,
+    }
+}
+
+/// The aggregated book of a stock, as of its latest book update.
+pub fn book_snapshot(state: &AppState, stock_id: &str) -> BookSnapshot {
+    let levels = state.matching_pq.levels(stock_id);
+    BookSnapshot {
+        stock_id: stock_id.to_string(),
+        shard_id: state.shard_id,
+        book_seq: state.book_seqs.get(stock_id).copied().unwrap_or_default(),
+        checksum: book_checksum(&levels, &[]),
+        asks: levels
+            .into_iter()
+            .map(|(price, size)| PriceLevel { price, size })
+            .collect(),
+        bids: Vec::new(),
+    }
+}
+
+/// CRC32 of the best `BOOK_CHECKSUM_DEPTH` levels of each side as text: the asks, then `|`,
+/// then the bids, each level `<price>:<size>` with levels separated by `,`. Prices are
+/// written in their shortest form that reads back the same, e.g. `5:10,6.5:3|`.
+pub fn book_checksum(asks: &[(f64, u64)], bids: &[(f64, u64)]) -> u32 {
+    let side = |levels: &[(f64, u64)]| {
+        levels
+            .iter()
+            .take(BOOK_CHECKSUM_DEPTH)
+            .map(|(price, size)| format!("{}:{}", price, size))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    crc32fast::hash(format!("{}|{}", side(asks), side(bids)).as_bytes())
+}
+
+fn match_order(state: &mut AppState, command: OrderCommand) -> Vec<EngineEvent> {
+    let mut events = Vec::new();
+
+    match command {
+        OrderCommand::MarketBuy(request) => {
+            let stock_id = request.stock_id.clone(); // Save for later
+            let buy_result = process_market_buy(state, request);
+
+            // Buy completion event (as failure or success), followed by all order updates
+            // and a trade report per fill
+            let traded = !buy_result.trades.is_empty();
+            events.push(EngineEvent::BuyCompleted(buy_result.market_buy_response));
+            if let Some(order_updates) = buy_result.order_updates {
+                events.extend(order_updates.into_iter().map(EngineEvent::SaleUpdate));
+            }
+            events.extend(buy_result.trades.into_iter().map(EngineEvent::Trade));
+
+            // Latest quote, as the best ask size and last trade changed with any fill
+            if traded {
+                events.push(EngineEvent::StockPrice(stock_price(state, &stock_id)));
+            }
+        }
+        OrderCommand::LimitSell(request) => {
+            let stock_id = request.stock_id.clone(); // Save for later
+            let sell_order = SellOrder {
+                stock_id: request.stock_id,
+                stock_name: request.stock_name,
+                stock_tx_id: request.stock_tx_id,
+                price: request.price,
+                partially_sold: false,
+                ori_quantity: request.quantity,
+                cur_quantity: request.quantity,
+                user_name: request.user_name,
+            };
+            state.matching_pq.insert(sell_order);
+
+            // Latest stock price, if the order changed the best ask
+            events.push(EngineEvent::StockPrice(stock_price(state, &stock_id)));
+        }
+        OrderCommand::LimitSellCancel(request) => {
+            let some_order = state
+                .matching_pq
+                .remove_order(&request.stock_id, &request.stock_tx_id);
+
+            if let Some(order) = some_order {
+                let stock_id = order.stock_id.clone(); // Save for later
+
+                // Cancellation response
+                events.push(EngineEvent::OrderCancelled(LimitSellCancelResponse {
+                    success: true,
+                    data: Some(LimitSellCancelData {
+                        stock_id: order.stock_id,
+                        stock_tx_id: order.stock_tx_id,
+                        partially_sold: order.partially_sold,
+                        ori_quantity: order.ori_quantity,
+                        cur_quantity: order.cur_quantity,
+                        sold_quantity: order.ori_quantity - order.cur_quantity,
+                        price: order.price,
+                    }),
+                    meta: EventMeta::default(),
+                }));
+
+                // Latest stock price, if the order was at the best ask
+                events.push(EngineEvent::StockPrice(stock_price(state, &stock_id)));
+            } else {
+                events.push(EngineEvent::OrderCancelled(LimitSellCancelResponse {
+                    success: false,
+                    data: None,
+                    meta: EventMeta::default(),
+                }));
+            }
+        }
+    }
+
+    events
+}
+
+fn create_mk_buy_fail_result(stock_id: String, stock_tx_id: String) -> MarketBuyResult {
+    warn!(
+        "Creating failed market buy result for stock_id={}, tx_id={}",
+        stock_id, stock_tx_id
+    );
+    MarketBuyResult {
+        market_buy_response: MarketBuyResponse {
+            success: false,
+            data: MarketBuyData {
+                stock_id,
+                stock_tx_id,
+                price_total: None,
+                quantity: None,
+                average_price: None,
+                trade_ids: Vec::new(),
+                fills: Vec::new(),
+            },
+            meta: EventMeta::default(),
+        },
+        order_updates: None,
+        trades: Vec::new(),
+    }
+}
+
+/// Report a fill of `quantity` shares of `sell_order` to the buyer of `request`, with the next
+/// trade id of this shard. Without persistence the counter starts over with every process,
+/// so the id also carries the process's epoch.
+fn new_trade(
+    state: &mut AppState,
+    request: &MarketBuyRequest,
+    sell_order: &SellOrder,
+    quantity: u64,
+) -> Trade {
+    state.trade_seq += 1;
+    let trade_id = match state.trade_epoch {
+        Some(epoch) => format!("trade-{}-{}-{}", state.shard_id, epoch, state.trade_seq),
+        None => format!("trade-{}-{}", state.shard_id, state.trade_seq),
+    };
+    Trade {
+        trade_id,
+        stock_id: sell_order.stock_id.clone(),
+        buy_stock_tx_id: request.stock_tx_id.clone(),
+        sell_stock_tx_id: sell_order.stock_tx_id.clone(),
+        buyer_user_name: request.user_name.clone(),
+        seller_user_name: sell_order.user_name.clone(),
+        quantity,
+        price: sell_order.price,
+        meta: EventMeta::default(),
+    }
+}
+
+/// Helper for performing market buy
+fn process_market_buy(state: &mut AppState, request: MarketBuyRequest) -> MarketBuyResult {
+    // Caller holds the state write lock the entire time to ensure no other sell occurs
+    // between ensuring we have enough shares and the actual buy/sell process.
+
+    debug!(
+        "Processing market buy request: stock={}, quantity={}, budget={}, user={}",
+        request.stock_id, request.quantity, request.budget, request.user_name
+    );
+
+    // Total number of shares on sale excluding those from the user requesting the buy order
+    let available_shares: u64 = state
+        .matching_pq
+        .get_all_orders(&request.stock_id)
+        .iter()
+        .filter(|sell_order| sell_order.user_name != request.user_name)
+        .map(|sell_order| sell_order.cur_quantity)
+        .sum();
+
+    debug!(
+        "Available shares for stock {}: {} (requested: {})",
+        request.stock_id, available_shares, request.quantity
+    );
+
+    // Check available shares
+    let mut shares_to_buy = request.quantity;
+    if shares_to_buy > available_shares {
+        warn!(
+            "Insufficient shares available for stock {}: available={}, requested={}",
+            request.stock_id, available_shares, shares_to_buy
+        );
+        return create_mk_buy_fail_result(request.stock_id, request.stock_tx_id);
+    }
+
+    // Dry-run: Clone the priority queue to calculate total cost without modifying state
+    // OPTIMIZE: This implementation is wildly inefficient but future problem it is!
+    // REFACTOR: Too much duplicate code between the dry run and the actual run.
+    let Some(original_queue) = state
+        .matching_pq
+        .get_stock_queue(&request.stock_id)
+        .cloned()
+    else {
+        return create_mk_buy_fail_result(request.stock_id, request.stock_tx_id);
+    };
+
+    let mut cloned_queue = original_queue;
+    let mut total_price_dry = 0.0;
+    let mut remaining_dry = shares_to_buy;
+
+    while remaining_dry > 0 {
+        if let Some(Reverse(order)) = cloned_queue.peek() {
+            if order.user_name == request.user_name {
+                cloned_queue.pop();
+                continue;
+            }
+
+            let take = remaining_dry.min(order.cur_quantity);
+            total_price_dry += take as f64 * order.price;
+            remaining_dry -= take;
+            cloned_queue.pop();
+        } else {
+            break;
+        }
+    }
+
+    debug!("Dry run calculation: total_price={}", total_price_dry);
+
+    // Budget validation
+    if total_price_dry > request.budget {
+        warn!(
+            "Insufficient budget for market buy: required={}, available={}",
+            total_price_dry, request.budget
+        );
+        return create_mk_buy_fail_result(request.stock_id, request.stock_tx_id);
+    }
+
+    // Proceed with actual purchase processing
+    let mut total_price = 0.0;
+    let mut shares_bought = 0;
+    let mut order_updates: Vec<OrderUpdate> = Vec::new();
+    let mut trades: Vec<Trade> = Vec::new();
+    while shares_to_buy > 0 {
+        // Assume the sell order always exist due to the above shares quantity check.
+        // If it somehow fails, we will break out early. But it is a critical issue at this point.
+        let Some(mut top_sell_order) = state.matching_pq.pop(&request.stock_id) else {
+            error!("Critical: Performing actual buy when there isn't enough valid shares to buy");
+            break;
+        };
+
+        // Skip if the sell order is from the user requesting the buy order
+        if top_sell_order.user_name == request.user_name {
+            debug!("Skipping sell order from same user: {}", request.user_name);
+            continue;
+        }
+
+        let purchase_all = shares_to_buy >= top_sell_order.cur_quantity;
+        debug!(
+            "Processing sell order: price={}, quantity={}, purchase_all={}",
+            top_sell_order.price, top_sell_order.cur_quantity, purchase_all
+        );
+
+        // Complete the top sell order or perform partial sell on the top sell order
+        if purchase_all {
+            total_price += top_sell_order.cur_quantity as f64 * top_sell_order.price;
+            shares_bought += top_sell_order.cur_quantity;
+            shares_to_buy -= top_sell_order.cur_quantity;
+
+            let sold_qty = top_sell_order.cur_quantity;
+            top_sell_order.cur_quantity = 0;
+            trades.push(new_trade(state, &request, &top_sell_order, sold_qty));
+
+            order_updates.push(OrderUpdate {
+                stock_id: top_sell_order.stock_id.clone(),
+                price: top_sell_order.price,
+                remaining_quantity: top_sell_order.cur_quantity,
+                sold_quantity: sold_qty,
+                stock_tx_id: top_sell_order.stock_tx_id.clone(),
+                user_name: top_sell_order.user_name.clone(),
+                meta: EventMeta::default(),
+            });
+        } else {
+            total_price += shares_to_buy as f64 * top_sell_order.price;
+            shares_bought += shares_to_buy;
+
+            top_sell_order.cur_quantity -= shares_to_buy;
+            top_sell_order.partially_sold = true;
+
+            let sold_qty = shares_to_buy;
+            shares_to_buy = 0;
+            trades.push(new_trade(state, &request, &top_sell_order, sold_qty));
+
+            order_updates.push(OrderUpdate {
+                stock_id: top_sell_order.stock_id.clone(),
+                price: top_sell_order.price,
+                remaining_quantity: top_sell_order.cur_quantity,
+                sold_quantity: sold_qty,
+                stock_tx_id: top_sell_order.stock_tx_id.clone(),
+                user_name: top_sell_order.user_name.clone(),
+                meta: EventMeta::default(),
+            });
+
+            // Reinsert the sell order back into the PQ since it is a partial sell
+            state.matching_pq.insert(top_sell_order);
+        };
+
+        debug!(
+            "Processed order: shares_bought={}, remaining_to_buy={}, total_price={}",
+            shares_bought, shares_to_buy, total_price
+        );
+    }
+
+    debug!(
+        "Market buy completed: shares={}, total_price={}, updates={}",
+        shares_bought,
+        total_price,
+        order_updates.len()
+    );
+
+    let response = MarketBuyResponse {
+        success: true,
+        data: MarketBuyData {
+            stock_id: request.stock_id,
+            stock_tx_id: request.stock_tx_id,
+            quantity: Some(shares_bought),
+            price_total: Some(total_price),
+            average_price: (shares_bought > 0).then(|| total_price / shares_bought as f64),
+            trade_ids: trades.iter().map(|trade| trade.trade_id.clone()).collect(),
+            fills: trades
+                .iter()
+                .map(|trade| Fill {
+                    trade_id: trade.trade_id.clone(),
+                    sell_stock_tx_id: trade.sell_stock_tx_id.clone(),
+                    quantity: trade.quantity,
+                    price: trade.price,
+                })
+                .collect(),
+        },
+        meta: EventMeta::default(),
+    };
+
+    MarketBuyResult {
+        market_buy_response: response,
+        order_updates: Some(order_updates),
+        trades,
+    }
+}
+
+/// Latest quote of a stock, with a price of `None` (AKA `null`) if it has no sell orders. The
+/// stock stays listed then, under the name of its last quote.
+/// Sequence numbers and the last trade are filled in by `apply`, which drops the quote if it
+/// is the same as the last one published.
+fn stock_price(state: &AppState, stock_id: &str) -> StockPrice {
+    let mut price = StockPrice {
+        stock_id: stock_id.to_string(),
+        stock_name: state
+            .last_quotes
+            .get(stock_id)
+            .and_then(|quote| quote.stock_name.clone()),
+        current_price: None,
+        trading_status: TradingStatus::ActiveNoQuotes,
+        stock_seq: 0,
+        best_ask: None,
+        best_ask_size: 0,
+        best_bid: None,
+        best_bid_size: 0,
+        last_trade_price: None,
+        last_trade_size: None,
+        last_trade_time: None,
+        session_volume: 0,
+        meta: EventMeta::default(),
+    };
+
+    if let Some(top_order) = state.matching_pq.peek(stock_id) {
+        debug!(
+            "Current price for {}: {} ({})",
+            stock_id, top_order.price, top_order.stock_name
+        );
+        price.stock_name = Some(top_order.stock_name.clone());
+        price.current_price = Some(top_order.price);
+        price.trading_status = TradingStatus::Active;
+        price.best_ask = Some(top_order.price);
+        price.best_ask_size = state
+            .matching_pq
+            .levels(stock_id)
+            .first()
+            .map_or(0, |(_, size)| *size);
+    } else {
+        warn!("No price available for stock {}", stock_id);
+    }
+    price
+}
@@ -0,0 +1,147 @@
+use amqprs::{BasicProperties, FieldName, FieldTable, FieldValue};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    engine::{self, CommandError, OrderCommand},
+    transport::InboundMessage,
+};
+
+/// Envelope schema version written by this engine; newer versions are rejected.
+pub const SCHEMA_VERSION: i32 = 1;
+pub const PRODUCER: &str = "matching-engine";
+
+const SCHEMA_VERSION_HEADER: &str = "x-schema-version";
+const CAUSATION_ID_HEADER: &str = "x-causation-id";
+
+/// Everything about a message except its payload. It travels in the AMQP properties (type,
+/// message id, correlation id, app id, timestamp) and headers (schema version, causation
+/// id), so the body stays the bare payload and consumers that predate it keep working.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Envelope {
+    pub message_type: String, // e.g. `order.limit_sell`, `order.buy_completed`, `stock.price`
+    pub schema_version: i32,
+    pub message_id: String,
+    pub correlation_id: Option<String>,
+    pub causation_id: Option<String>, // Message id of the message that caused this one
+    pub producer: String,
+    pub timestamp: u64, // Unix seconds, as AMQP timestamps are
+}
+
+fn header<'a>(headers: Option<&'a FieldTable>, name: &str) -> Option<&'a FieldValue> {
+    let name: FieldName = name.try_into().ok()?;
+    headers?.get(&name)
+}
+
+fn insert_header(headers: &mut FieldTable, name: &str, value: FieldValue) {
+    if let Ok(name) = name.try_into() {
+        headers.insert(name, value);
+    }
+}
+
+fn as_i64(value: &FieldValue) -> Option<i64> {
+    match value {
+        FieldValue::b(v) => Some(*v as i64),
+        FieldValue::B(v) => Some(*v as i64),
+        FieldValue::s(v) => Some(*v as i64),
+        FieldValue::u(v) => Some(*v as i64),
+        FieldValue::I(v) => Some(*v as i64),
+        FieldValue::i(v) => Some(*v as i64),
+        FieldValue::l(v) => Some(*v),
+        _ => None,
+    }
+}
+
+impl Envelope {
+    /// Read the envelope of a delivery, or `None` for a legacy message published without a
+    /// message type.
+    pub fn from_properties(properties: &BasicProperties) -> Option<Self> {
+        let headers = properties.headers();
+        Some(Self {
+            message_type: properties.message_type()?.clone(),
+            schema_version: header(headers, SCHEMA_VERSION_HEADER)
+                .and_then(as_i64)
+                .map_or(1, |version| version as i32),
+            message_id: properties.message_id().cloned().unwrap_or_default(),
+            correlation_id: properties.correlation_id().cloned(),
+            causation_id: header(headers, CAUSATION_ID_HEADER).map(FieldValue::to_string),
+            producer: properties.app_id().cloned().unwrap_or_default(),
+            timestamp: properties.timestamp().unwrap_or_default(),
+        })
+    }
+
+    pub fn to_properties(&self) -> BasicProperties {
+        let mut headers = FieldTable::new();
+        insert_header(
+            &mut headers,
+            SCHEMA_VERSION_HEADER,
+            FieldValue::I(self.schema_version),
+        );
+        if let Some(causation_id) = &self.causation_id {
+            insert_header(
+                &mut headers,
+                CAUSATION_ID_HEADER,
+                causation_id.as_str().into(),
+            );
+        }
+
+        let mut properties = BasicProperties::default();
+        properties
+            .with_content_type("application/json")
+            .with_message_type(&self.message_type)
+            .with_message_id(&self.message_id)
+            .with_app_id(&self.producer)
+            .with_timestamp(self.timestamp)
+            .with_headers(headers);
+        if let Some(correlation_id) = &self.correlation_id {
+            properties.with_correlation_id(correlation_id);
+        }
+        properties
+    }
+}
+
+/// Ties the events caused by an order back to it. Recorded in the journal and the outbox so
+/// events published after a restart carry the same ids.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Correlation {
+    pub correlation_id: String,
+    pub causation_id: String, // Message id of the order
+}
+
+impl Correlation {
+    /// Take the ids from the order message. Legacy orders without them are correlated by
+    /// their `stock_tx_id` and caused by their order key.
+    pub fn for_order(message: &InboundMessage, command: &OrderCommand) -> Self {
+        Self {
+            correlation_id: message
+                .correlation_id
+                .clone()
+                .or_else(|| message.message_id.clone())
+                .unwrap_or_else(|| command.stock_tx_id().to_string()),
+            causation_id: message.message_id.clone().unwrap_or_else(|| command.key()),
+        }
+    }
+}
+
+/// Parse an order, taking its type from the envelope, or from the second routing key
+/// segment for legacy orders.
+pub fn parse_order(
+    routing_key: &str,
+    envelope: Option<&Envelope>,
+    content: &[u8],
+) -> Result<OrderCommand, CommandError> {
+    let Some(envelope) = envelope else {
+        return OrderCommand::parse(engine::order_type(routing_key), content);
+    };
+
+    if envelope.schema_version > SCHEMA_VERSION {
+        return Err(CommandError::UnsupportedSchemaVersion {
+            message_type: envelope.message_type.clone(),
+            version: envelope.schema_version,
+        });
+    }
+    let order_type = envelope
+        .message_type
+        .strip_prefix("order.")
+        .unwrap_or(&envelope.message_type);
+    OrderCommand::parse(order_type, content)
+}
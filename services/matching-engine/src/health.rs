@@ -0,0 +1,49 @@
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::{error, info};
+
+use crate::rabbitmq::{ConnectionState, RabbitMQClient};
+
+/// `GET /health`: 200 while connected to RabbitMQ, 503 otherwise.
+async fn health(State(client): State<Arc<RabbitMQClient>>) -> (StatusCode, Json<Value>) {
+    let connection_state = client.connection_state();
+    let healthy = connection_state == ConnectionState::Connected;
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(json!({
+            "success": healthy,
+            "data": {
+                "shard_id": client.shard_id(),
+                "rabbitmq": connection_state,
+            }
+        })),
+    )
+}
+
+pub fn spawn_health_server(port: u16, client: Arc<RabbitMQClient>) {
+    let app = Router::new()
+        .route("/health", get(health))
+        .with_state(client);
+
+    tokio::spawn(async move {
+        let endpoint = format!("0.0.0.0:{}", port);
+        let listener = match tokio::net::TcpListener::bind(&endpoint).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind health endpoint on {}: {}", endpoint, e);
+                return;
+            }
+        };
+        info!("Health endpoint listening on {}", endpoint);
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Health endpoint stopped: {}", e);
+        }
+    });
+}
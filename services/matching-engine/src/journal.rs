@@ -0,0 +1,275 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info, warn};
+
+use crate::{engine::OrderCommand, envelope::Correlation};
+
+/// One line of the journal: every order applied to the book, in the order it was applied.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub received_at: u64, // Unix time in milliseconds
+    pub command: OrderCommand,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation: Option<Correlation>,
+}
+
+/// Append-only JSONL log of applied orders, split into segments named after the
+/// first sequence number they may contain (`journal-<seq>.jsonl`). A new segment is
+/// started after every snapshot so old segments can be dropped once no snapshot needs them.
+pub struct Journal {
+    dir: PathBuf,
+    file: File,
+    torn: bool, // The last append failed, possibly partway through its line
+}
+
+impl Journal {
+    /// Open (or create) the segment starting at `next_seq` for appending.
+    pub fn open(dir: &Path, next_seq: u64) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let file = open_segment(dir, next_seq)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            file,
+            torn: false,
+        })
+    }
+
+    /// Append the entry as one line. After a failed append the next entry starts on a new
+    /// line, so whatever part of the failed one was written is skipped as unreadable.
+    pub fn append(&mut self, entry: &JournalEntry) -> std::io::Result<()> {
+        let mut line = Vec::new();
+        if self.torn {
+            line.push(b'\n');
+        }
+        serde_json::to_writer(&mut line, entry)?;
+        line.push(b'\n');
+
+        self.torn = true;
+        self.file.write_all(&line)?;
+        self.torn = false;
+        Ok(())
+    }
+
+    /// Close the current segment and start a new one at `next_seq`.
+    pub fn rotate(&mut self, next_seq: u64) -> std::io::Result<()> {
+        self.file.sync_data()?;
+        self.file = open_segment(&self.dir, next_seq)?;
+        self.torn = false;
+        debug!("Rotated journal to segment starting at {}", next_seq);
+        Ok(())
+    }
+}
+
+fn open_segment(dir: &Path, start_seq: u64) -> std::io::Result<File> {
+    let path = dir.join(format!("journal-{:020}.jsonl", start_seq));
+    let torn = ends_mid_line(&path).unwrap_or(false);
+
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    if torn {
+        // Terminate the torn line so the next entry starts on a line of its own
+        file.write_all(b"\n")?;
+    }
+    Ok(file)
+}
+
+fn ends_mid_line(path: &Path) -> std::io::Result<bool> {
+    let mut file = File::open(path)?;
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
+    }
+    let mut last = [0u8; 1];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
+}
+
+/// Journal segments in `dir` as `(start_seq, path)`, oldest first.
+fn segments(dir: &Path) -> std::io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    if !dir.exists() {
+        return Ok(segments);
+    }
+
+    for dir_entry in fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        let start_seq = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("journal-"))
+            .and_then(|name| name.strip_suffix(".jsonl"))
+            .and_then(|seq| seq.parse::<u64>().ok());
+        if let Some(start_seq) = start_seq {
+            segments.push((start_seq, path));
+        }
+    }
+
+    segments.sort();
+    Ok(segments)
+}
+
+/// Read every entry with a sequence number greater than `after_seq`, skipping segments
+/// that only hold older entries. A torn last line (crash mid-write) is ignored.
+pub fn read_after(dir: &Path, after_seq: u64) -> std::io::Result<Vec<JournalEntry>> {
+    let segments = segments(dir)?;
+    let mut entries = Vec::new();
+
+    for (i, (_, path)) in segments.iter().enumerate() {
+        // Everything in this segment is older than the next segment's start
+        if let Some((next_start, _)) = segments.get(i + 1) {
+            if *next_start <= after_seq + 1 {
+                continue;
+            }
+        }
+
+        let reader = BufReader::new(File::open(path)?);
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<JournalEntry>(&line) {
+                Ok(entry) if entry.seq > after_seq => entries.push(entry),
+                Ok(_) => {}
+                Err(e) => warn!(
+                    "Skipping unreadable journal line {} in {}: {}",
+                    line_no + 1,
+                    path.display(),
+                    e
+                ),
+            }
+        }
+    }
+
+    info!(
+        "Read {} journal entries after sequence {}",
+        entries.len(),
+        after_seq
+    );
+    Ok(entries)
+}
+
+/// Delete segments whose entries are all covered by a snapshot at `covered_seq`.
+pub fn prune(dir: &Path, covered_seq: u64) -> std::io::Result<()> {
+    let segments = segments(dir)?;
+    for (i, (_, path)) in segments.iter().enumerate() {
+        match segments.get(i + 1) {
+            Some((next_start, _)) if *next_start <= covered_seq + 1 => {
+                debug!("Pruning journal segment {}", path.display());
+                fs::remove_file(path)?;
+            }
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::LimitSellRequest, test_dir::TestDir};
+
+    fn entry(seq: u64) -> JournalEntry {
+        JournalEntry {
+            seq,
+            received_at: 1000 + seq,
+            command: OrderCommand::LimitSell(LimitSellRequest {
+                stock_id: "s1".to_string(),
+                stock_name: "Google".to_string(),
+                quantity: 10,
+                price: 5.0,
+                stock_tx_id: format!("tx{}", seq),
+                user_name: "alice".to_string(),
+            }),
+            correlation: None,
+        }
+    }
+
+    fn seqs(entries: &[JournalEntry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.seq).collect()
+    }
+
+    fn segment_starts(dir: &Path) -> Vec<u64> {
+        segments(dir)
+            .unwrap()
+            .into_iter()
+            .map(|(start, _)| start)
+            .collect()
+    }
+
+    #[test]
+    fn reads_entries_after_a_sequence() {
+        let dir = TestDir::new();
+        let mut journal = Journal::open(dir.path(), 1).unwrap();
+        for seq in 1..=4 {
+            journal.append(&entry(seq)).unwrap();
+        }
+
+        assert_eq!(seqs(&read_after(dir.path(), 0).unwrap()), vec![1, 2, 3, 4]);
+        assert_eq!(seqs(&read_after(dir.path(), 2).unwrap()), vec![3, 4]);
+        assert!(read_after(dir.path(), 4).unwrap().is_empty());
+    }
+
+    #[test]
+    fn torn_line_is_terminated_and_skipped() {
+        let dir = TestDir::new();
+        let mut journal = Journal::open(dir.path(), 1).unwrap();
+        journal.append(&entry(1)).unwrap();
+        drop(journal);
+
+        // A crash halfway through the second entry
+        let (_, path) = segments(dir.path()).unwrap().remove(0);
+        let line = serde_json::to_string(&entry(2)).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&line.as_bytes()[..line.len() / 2]).unwrap();
+        drop(file);
+
+        let mut journal = Journal::open(dir.path(), 1).unwrap();
+        journal.append(&entry(2)).unwrap();
+        assert_eq!(seqs(&read_after(dir.path(), 0).unwrap()), vec![1, 2]);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+    }
+
+    #[test]
+    fn only_the_tail_after_a_snapshot_is_read() {
+        let dir = TestDir::new();
+        let mut journal = Journal::open(dir.path(), 1).unwrap();
+        journal.append(&entry(1)).unwrap();
+        journal.append(&entry(2)).unwrap();
+        // Snapshot at 2
+        journal.rotate(3).unwrap();
+        journal.append(&entry(3)).unwrap();
+
+        // The first segment is not even opened
+        let (_, first) = segments(dir.path()).unwrap().remove(0);
+        fs::write(&first, "not json\n").unwrap();
+        assert_eq!(seqs(&read_after(dir.path(), 2).unwrap()), vec![3]);
+    }
+
+    #[test]
+    fn prunes_segments_covered_by_a_snapshot() {
+        let dir = TestDir::new();
+        let mut journal = Journal::open(dir.path(), 1).unwrap();
+        for seq in 1..=6 {
+            if seq == 3 || seq == 5 {
+                journal.rotate(seq).unwrap();
+            }
+            journal.append(&entry(seq)).unwrap();
+        }
+        assert_eq!(segment_starts(dir.path()), vec![1, 3, 5]);
+
+        // Entry 4 is still needed
+        prune(dir.path(), 3).unwrap();
+        assert_eq!(segment_starts(dir.path()), vec![3, 5]);
+        prune(dir.path(), 4).unwrap();
+        assert_eq!(segment_starts(dir.path()), vec![5]);
+        // The segment being written is never pruned
+        prune(dir.path(), 6).unwrap();
+        assert_eq!(segment_starts(dir.path()), vec![5]);
+        assert_eq!(seqs(&read_after(dir.path(), 4).unwrap()), vec![5, 6]);
+    }
+}
@@ -0,0 +1,18 @@
+pub mod conflation;
+pub mod consumers;
+pub mod dead_letter;
+pub mod engine;
+pub mod envelope;
+pub mod health;
+pub mod journal;
+pub mod matching_pq;
+pub mod memory_bus;
+pub mod models;
+pub mod outbox;
+pub mod persistence;
+pub mod rabbitmq;
+pub mod snapshot;
+pub mod state;
+#[cfg(test)]
+mod test_dir;
+pub mod transport;
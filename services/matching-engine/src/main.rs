@@ -1,19 +1,19 @@
 use dotenvy::dotenv;
-use serde::de;
-use std::{env, sync::Arc};
+use std::{env, path::PathBuf, sync::Arc, time::Duration};
 use tokio::sync::RwLock;
-use tracing::{debug, info, Level};
-use tracing_subscriber::{field::debug, EnvFilter, FmtSubscriber};
+use tracing::{error, info, warn};
+use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
-mod consumers;
-mod matching_pq;
-mod models;
-mod rabbitmq;
-mod state;
-
-use consumers::OrderConsumer;
-use rabbitmq::{RabbitMQClient, RabbitMQConfig};
-use state::AppState;
+use matching_engine::{
+    conflation::ConflatingSink,
+    consumers::OrderConsumer,
+    health,
+    outbox::Outbox,
+    persistence::{now_millis, Persistence, PersistenceConfig},
+    rabbitmq::{PrefetchConfig, QueueType, RabbitMQClient, RabbitMQConfig, TopologyConfig},
+    state::AppState,
+    transport::EventSink,
+};
 
 // Function to set up tracing with conditional logging
 pub fn setup_tracing() {
@@ -43,6 +43,37 @@ pub fn setup_tracing() {
     }
 }
 
+/// Resolves on SIGINT (Ctrl+C) or SIGTERM (e.g. `docker stop`).
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logging
@@ -52,9 +83,72 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("Loading environment variables");
     dotenv().ok();
 
-    // Initialize application state
+    let shard_id: u32 = env::var("SHARD_ID")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse()
+        .unwrap_or(0);
+
+    // Initialize application state, restoring it from disk when persistence is enabled
     info!("Initializing application state");
-    let app_state = Arc::new(RwLock::new(AppState::new()));
+    let (persistence, outbox, app_state) = match env::var("DATA_DIR") {
+        Ok(data_dir) => {
+            let persistence_config = PersistenceConfig {
+                data_dir: PathBuf::from(data_dir),
+                snapshot_interval_secs: env::var("SNAPSHOT_INTERVAL_SECS")
+                    .unwrap_or_else(|_| "60".to_string())
+                    .parse()
+                    .unwrap_or(60),
+                snapshot_retain: env::var("SNAPSHOT_RETAIN")
+                    .unwrap_or_else(|_| "3".to_string())
+                    .parse()
+                    .unwrap_or(3),
+            };
+            info!(
+                "Restoring state from {}",
+                persistence_config.data_dir.display()
+            );
+            let outbox = Outbox::open(&persistence_config.data_dir.join("outbox"))?;
+            let (persistence, state) =
+                Persistence::restore(persistence_config, shard_id, &outbox)?;
+            (Some(Arc::new(persistence)), outbox, state)
+        }
+        Err(_) => {
+            info!("DATA_DIR not set, running without journal or snapshots");
+            let mut state = AppState::new();
+            state.shard_id = shard_id;
+            // Trade ids must not repeat after a restart
+            state.trade_epoch = Some(now_millis());
+            (None, Outbox::in_memory(), state)
+        }
+    };
+    let app_state = Arc::new(RwLock::new(app_state));
+    if let Some(persistence) = &persistence {
+        Arc::clone(persistence).spawn_snapshot_task(Arc::clone(&app_state));
+    }
+
+    // How exchanges and queues are declared; has to match what exists on the broker
+    let topology = TopologyConfig {
+        durable_exchanges: env::var("RABBITMQ_DURABLE_EXCHANGES")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse()
+            .unwrap_or(false),
+        durable_queues: env::var("RABBITMQ_DURABLE_QUEUES")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse()
+            .unwrap_or(false),
+        queue_type: match env::var("RABBITMQ_QUEUE_TYPE").as_deref() {
+            Ok("quorum") => QueueType::Quorum,
+            Ok("classic") | Err(_) => QueueType::Classic,
+            Ok(other) => return Err(format!("unknown RABBITMQ_QUEUE_TYPE '{}'", other).into()),
+        },
+        message_ttl_ms: env::var("RABBITMQ_MESSAGE_TTL_MS")
+            .ok()
+            .and_then(|ttl| ttl.parse().ok()),
+        max_length: env::var("RABBITMQ_MAX_LENGTH")
+            .ok()
+            .and_then(|max_length| max_length.parse().ok()),
+    };
+    topology.validate()?;
 
     // Initialize RabbitMQ client with sharding configuration
     let rabbitmq_config = RabbitMQConfig {
@@ -65,10 +159,34 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .unwrap_or(5672),
         username: env::var("RABBITMQ_USERNAME").unwrap_or_else(|_| "guest".to_string()),
         password: env::var("RABBITMQ_PASSWORD").unwrap_or_else(|_| "guest".to_string()),
-        shard_id: env::var("SHARD_ID")
-            .unwrap_or_else(|_| "0".to_string())
+        shard_id,
+        reconnect_initial_delay_ms: env::var("RABBITMQ_RECONNECT_INITIAL_DELAY_MS")
+            .unwrap_or_else(|_| "500".to_string())
+            .parse()
+            .unwrap_or(500),
+        reconnect_max_delay_ms: env::var("RABBITMQ_RECONNECT_MAX_DELAY_MS")
+            .unwrap_or_else(|_| "30000".to_string())
             .parse()
-            .unwrap_or(0),
+            .unwrap_or(30_000),
+        confirm_timeout_ms: env::var("PUBLISH_CONFIRM_TIMEOUT_MS")
+            .unwrap_or_else(|_| "5000".to_string())
+            .parse()
+            .unwrap_or(5000),
+        topology,
+        prefetch: PrefetchConfig {
+            market_buy: env::var("PREFETCH_MARKET_BUY")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()
+                .unwrap_or(10),
+            limit_sell: env::var("PREFETCH_LIMIT_SELL")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()
+                .unwrap_or(10),
+            cancel_sell: env::var("PREFETCH_CANCEL_SELL")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()
+                .unwrap_or(10),
+        },
     };
 
     info!(
@@ -76,19 +194,106 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         rabbitmq_config.host, rabbitmq_config.port, rabbitmq_config.shard_id
     );
 
-    let rabbitmq_client = Arc::new(RabbitMQClient::new(rabbitmq_config).await?);
+    let rabbitmq_client = Arc::new(RabbitMQClient::new(rabbitmq_config));
     info!("RabbitMQ client initialized successfully");
 
+    // Health endpoint reporting the RabbitMQ connection state
+    let health_port: u16 = env::var("HEALTH_PORT")
+        .unwrap_or_else(|_| "3000".to_string())
+        .parse()
+        .unwrap_or(3000);
+    health::spawn_health_server(health_port, Arc::clone(&rabbitmq_client));
+
+    // Publish at most one price per stock per window if PRICE_CONFLATION_MS is set
+    let price_conflation_ms: u64 = env::var("PRICE_CONFLATION_MS")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse()
+        .unwrap_or(0);
+    let conflating_sink = (price_conflation_ms > 0).then(|| {
+        info!("Conflating prices over {}ms", price_conflation_ms);
+        Arc::new(ConflatingSink::new(
+            Arc::clone(&rabbitmq_client) as Arc<dyn EventSink>,
+            Duration::from_millis(price_conflation_ms),
+        ))
+    });
+    let event_sink: Arc<dyn EventSink> = match &conflating_sink {
+        Some(sink) => Arc::clone(sink) as Arc<dyn EventSink>,
+        None => Arc::clone(&rabbitmq_client) as Arc<dyn EventSink>,
+    };
+
+    // Retry events the broker has not confirmed, starting with any left over from before
+    let outbox = Arc::new(outbox);
+    Arc::clone(&outbox).spawn_retry_task(
+        Arc::clone(&event_sink),
+        env::var("OUTBOX_RETRY_INITIAL_DELAY_MS")
+            .unwrap_or_else(|_| "200".to_string())
+            .parse()
+            .unwrap_or(200),
+        env::var("OUTBOX_RETRY_MAX_DELAY_MS")
+            .unwrap_or_else(|_| "10000".to_string())
+            .parse()
+            .unwrap_or(10_000),
+    );
+    if !outbox.is_empty() {
+        outbox.schedule_retry();
+    }
+
     // Initialize and setup order consumer
     info!("Setting up order consumer");
-    let order_consumer = OrderConsumer::new(Arc::clone(&app_state), Arc::clone(&rabbitmq_client));
-    order_consumer.setup().await?;
+    let order_consumer = OrderConsumer::new(
+        Arc::clone(&app_state),
+        Arc::clone(&event_sink),
+        persistence.clone(),
+        Arc::clone(&outbox),
+    );
+    order_consumer.setup(&rabbitmq_client).await?;
     info!("Order consumer setup completed");
 
     // Keep the main thread alive
     info!("Matching engine started and ready to process orders. Press Ctrl+C to exit.");
-    tokio::signal::ctrl_c().await?;
-    info!("Received shutdown signal, cleaning up...");
+    let failure = tokio::select! {
+        _ = shutdown_signal() => {
+            info!("Received shutdown signal, cleaning up...");
+            None
+        }
+        reason = rabbitmq_client.failed() => {
+            error!("Stopping, RabbitMQ setup failed: {}", reason);
+            Some(reason)
+        }
+    };
 
-    Ok(())
+    // Stop new deliveries, then let the current message finish and publish its events
+    rabbitmq_client.stop_consuming().await;
+    order_consumer.drain().await;
+    let flushed = outbox.flush(event_sink.as_ref()).await;
+    if let Some(sink) = &conflating_sink {
+        sink.flush().await;
+    }
+    if !flushed {
+        warn!(
+            "{} events were not confirmed before shutdown{}",
+            outbox.len(),
+            if persistence.is_some() {
+                ", they stay in the outbox"
+            } else {
+                " and are lost"
+            }
+        );
+    }
+
+    if let Some(persistence) = &persistence {
+        if let Err(e) = persistence.take_snapshot(&app_state).await {
+            error!("Failed to take shutdown snapshot: {}", e);
+        }
+    }
+
+    if let Err(e) = rabbitmq_client.close().await {
+        error!("Failed to close RabbitMQ connection cleanly: {}", e);
+    }
+    info!("Matching engine stopped");
+
+    match failure {
+        Some(reason) => Err(reason.into()),
+        None => Ok(()),
+    }
 }
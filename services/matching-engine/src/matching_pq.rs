@@ -1,7 +1,8 @@
+use serde::{Deserialize, Serialize};
 use std::cmp::{Ordering, Reverse};
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SellOrder {
     pub stock_id: String,
     pub stock_name: String,
@@ -10,7 +11,7 @@ pub struct SellOrder {
     pub ori_quantity: u64,
     pub cur_quantity: u64,
     pub price: f64,
-    pub user_name: String, 
+    pub user_name: String,
 }
 
 impl Eq for SellOrder {}
@@ -45,6 +46,32 @@ impl StockMatchingPriorityQueue {
         }
     }
 
+    /// Rebuild the queues from the layout produced by `raw_queues`.
+    pub fn from_raw_queues(queues: BTreeMap<String, Vec<SellOrder>>) -> Self {
+        StockMatchingPriorityQueue {
+            stock_queues: queues
+                .into_iter()
+                .map(|(stock_id, orders)| {
+                    let heap = orders.into_iter().map(Reverse).collect::<Vec<_>>();
+                    (stock_id, BinaryHeap::from(heap))
+                })
+                .collect(),
+        }
+    }
+
+    /// Every stock's orders in the heap's internal layout (including stocks with no orders left).
+    /// Heapifying a vector that is already a valid heap does not move anything, so
+    /// `from_raw_queues` gives back a queue with the exact same pop order, ties included.
+    pub fn raw_queues(&self) -> BTreeMap<String, Vec<SellOrder>> {
+        self.stock_queues
+            .iter()
+            .map(|(stock_id, queue)| {
+                let orders = queue.iter().map(|Reverse(order)| order.clone()).collect();
+                (stock_id.clone(), orders)
+            })
+            .collect()
+    }
+
     pub fn get_stock_queue(&self, stock_id: &str) -> Option<&BinaryHeap<Reverse<SellOrder>>> {
         self.stock_queues.get(stock_id)
     }
@@ -52,7 +79,7 @@ impl StockMatchingPriorityQueue {
     pub fn insert(&mut self, order: SellOrder) {
         self.stock_queues
             .entry(order.stock_id.clone())
-            .or_insert(BinaryHeap::new())
+            .or_default()
             .push(Reverse(order));
     }
 
@@ -83,6 +110,20 @@ impl StockMatchingPriorityQueue {
             .unwrap_or_default()
     }
 
+    /// Price levels of a stock as `(price, total quantity)`, cheapest first.
+    pub fn levels(&self, stock_id: &str) -> Vec<(f64, u64)> {
+        let mut orders = self.get_all_orders(stock_id);
+        orders.sort();
+        let mut levels: Vec<(f64, u64)> = Vec::new();
+        for order in orders {
+            match levels.last_mut() {
+                Some((price, quantity)) if *price == order.price => *quantity += order.cur_quantity,
+                _ => levels.push((order.price, order.cur_quantity)),
+            }
+        }
+        levels
+    }
+
     pub fn get_all_stocks(&self) -> Vec<String> {
         self.stock_queues.keys().cloned().collect()
     }
@@ -0,0 +1,239 @@
+use async_trait::async_trait;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tracing::debug;
+
+use crate::{
+    envelope::{Envelope, SCHEMA_VERSION},
+    models::{BookSnapshot, PriceSnapshot},
+    outbox::OutboxEntry,
+    transport::{
+        Disposition, EventSink, InboundMessage, OrderHandler, OrderSource, TransportError,
+    },
+};
+
+/// An event as it was handed to the bus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishedEvent {
+    pub exchange: String,
+    pub routing_key: String,
+    pub message_type: String,
+    pub correlation_id: Option<String>,
+    pub causation_id: Option<String>,
+    pub payload: serde_json::Value,
+}
+
+/// An order the handler gave up on, with the reason it gave.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    pub routing_key: String,
+    pub reason: String,
+    pub content: Vec<u8>,
+}
+
+/// In-process order source and event sink. Orders are handled one at a time in the order
+/// they were sent, like a shard queue with a prefetch of one; requeued orders go to the back
+/// of the queue and are redelivered. Used to run the engine without a broker, e.g. in tests.
+pub struct MemoryBus {
+    orders: mpsc::UnboundedSender<InboundMessage>,
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<InboundMessage>>>,
+    handler: Mutex<Option<Arc<dyn OrderHandler>>>, // Set once started
+    unsettled: watch::Sender<usize>,               // Orders sent but not yet acked or dead-lettered
+    stopped: watch::Sender<bool>,
+    fail_publishes: AtomicBool,
+    events: Mutex<Vec<PublishedEvent>>,
+    dispositions: Mutex<Vec<Disposition>>,
+    dead_letters: Mutex<Vec<DeadLetter>>,
+}
+
+impl Default for MemoryBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBus {
+    pub fn new() -> Self {
+        let (orders, receiver) = mpsc::unbounded_channel();
+        Self {
+            orders,
+            receiver: Mutex::new(Some(receiver)),
+            handler: Mutex::new(None),
+            unsettled: watch::Sender::new(0),
+            stopped: watch::Sender::new(false),
+            fail_publishes: AtomicBool::new(false),
+            events: Mutex::new(Vec::new()),
+            dispositions: Mutex::new(Vec::new()),
+            dead_letters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Send an order with an envelope, as the order manager does. The message id doubles as
+    /// the correlation id.
+    pub fn send_order(&self, message_type: &str, message_id: &str, payload: serde_json::Value) {
+        let envelope = Envelope {
+            message_type: message_type.to_string(),
+            schema_version: SCHEMA_VERSION,
+            message_id: message_id.to_string(),
+            correlation_id: Some(message_id.to_string()),
+            causation_id: None,
+            producer: "order-manager".to_string(),
+            timestamp: 0,
+        };
+        self.send(InboundMessage {
+            routing_key: message_type.to_string(),
+            envelope: Some(envelope),
+            message_id: Some(message_id.to_string()),
+            correlation_id: Some(message_id.to_string()),
+            redelivered: false,
+            content: payload.to_string().into_bytes(),
+        });
+    }
+
+    /// Send any message as is, e.g. a legacy order without an envelope.
+    pub fn send(&self, message: InboundMessage) {
+        self.unsettled.send_modify(|unsettled| *unsettled += 1);
+        // The receiver lives as long as the bus
+        let _ = self.orders.send(message);
+    }
+
+    /// Request the order book of a stock, as a market data consumer would. `None` if the
+    /// bus has not been started yet.
+    pub async fn book_snapshot(&self, stock_id: &str) -> Option<BookSnapshot> {
+        let handler = self.handler.lock().unwrap().clone()?;
+        Some(handler.book_snapshot(stock_id).await)
+    }
+
+    /// Request the current prices of this shard, as the stock price service does on startup.
+    /// `None` if the bus has not been started yet.
+    pub async fn price_snapshot(&self) -> Option<PriceSnapshot> {
+        let handler = self.handler.lock().unwrap().clone()?;
+        Some(handler.price_snapshot().await)
+    }
+
+    /// Make every publish fail until turned off again, as if the broker were unreachable.
+    pub fn fail_publishes(&self, fail: bool) {
+        self.fail_publishes.store(fail, Ordering::SeqCst);
+    }
+
+    /// Wait up to `timeout` until every order sent so far has been acked or dead-lettered.
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        let mut unsettled = self.unsettled.subscribe();
+        let idle = async {
+            unsettled
+                .wait_for(|unsettled| *unsettled == 0)
+                .await
+                .is_ok()
+        };
+        tokio::time::timeout(timeout, idle).await.unwrap_or(false)
+    }
+
+    /// Wait up to `timeout` until the handler has settled at least `count` deliveries.
+    pub async fn wait_dispositions(&self, count: usize, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while self.dispositions().len() < count {
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        true
+    }
+
+    /// Events published so far, in publish order.
+    pub fn events(&self) -> Vec<PublishedEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Take the events published so far, leaving none behind.
+    pub fn take_events(&self) -> Vec<PublishedEvent> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+
+    /// What the handler decided for every delivery so far, in order.
+    pub fn dispositions(&self) -> Vec<Disposition> {
+        self.dispositions.lock().unwrap().clone()
+    }
+
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters.lock().unwrap().clone()
+    }
+
+    fn settle(&self, message: InboundMessage, disposition: Disposition) {
+        self.dispositions.lock().unwrap().push(disposition.clone());
+        match disposition {
+            Disposition::Ack => self.unsettled.send_modify(|unsettled| *unsettled -= 1),
+            Disposition::Requeue => {
+                let _ = self.orders.send(InboundMessage {
+                    redelivered: true,
+                    ..message
+                });
+            }
+            Disposition::DeadLetter(reason) => {
+                self.dead_letters.lock().unwrap().push(DeadLetter {
+                    routing_key: message.routing_key,
+                    reason,
+                    content: message.content,
+                });
+                self.unsettled.send_modify(|unsettled| *unsettled -= 1);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl OrderSource for Arc<MemoryBus> {
+    async fn start(&self, handler: Arc<dyn OrderHandler>) -> Result<(), TransportError> {
+        let Some(mut receiver) = self.receiver.lock().unwrap().take() else {
+            return Err("memory bus already started".into());
+        };
+        *self.handler.lock().unwrap() = Some(Arc::clone(&handler));
+
+        let bus = Arc::clone(self);
+        let mut stopped = self.stopped.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let message = tokio::select! {
+                    message = receiver.recv() => message,
+                    _ = stopped.wait_for(|stopped| *stopped) => None,
+                };
+                let Some(message) = message else {
+                    break;
+                };
+                let disposition = handler.handle(&message).await;
+                debug!("{} settled as {:?}", message.routing_key, disposition);
+                bus.settle(message, disposition);
+            }
+            debug!("Memory bus stopped delivering orders");
+        });
+        Ok(())
+    }
+
+    async fn stop_consuming(&self) {
+        self.stopped.send_replace(true);
+    }
+}
+
+#[async_trait]
+impl EventSink for MemoryBus {
+    async fn publish(&self, entry: &OutboxEntry) -> Result<(), TransportError> {
+        if self.fail_publishes.load(Ordering::SeqCst) {
+            return Err("memory bus is failing publishes".into());
+        }
+
+        let correlation = entry.correlation.as_ref();
+        self.events.lock().unwrap().push(PublishedEvent {
+            exchange: entry.exchange.clone(),
+            routing_key: entry.routing_key.clone(),
+            message_type: entry.message_type.clone(),
+            correlation_id: correlation.map(|correlation| correlation.correlation_id.clone()),
+            causation_id: correlation.map(|correlation| correlation.causation_id.clone()),
+            payload: serde_json::from_str(&entry.payload)?,
+        });
+        Ok(())
+    }
+}
@@ -1,15 +1,96 @@
 use serde::{Deserialize, Serialize};
 
+// Stamped onto every published message so consumers can order events and detect gaps
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EventMeta {
+    pub shard_id: u32,
+    pub seq: u64,       // Per-shard sequence, incremented for every published message
+    pub timestamp: u64, // Engine wall-clock time in Unix milliseconds, never goes backwards
+}
+
 // Stock prices types
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TradingStatus {
+    #[default]
+    Active, // Has sell orders
+    ActiveNoQuotes, // Listed, but nothing is offered right now
+}
+
 #[derive(Serialize, Debug, Clone)]
 pub struct StockPrice {
     pub stock_id: String,
-    pub stock_name: Option<String>, // None/null if stock is no longer available
-    pub current_price: Option<f64>, // None/null if stock is no longer available
+    pub stock_name: Option<String>, // None/null if the engine never saw a sell order of the stock
+    pub current_price: Option<f64>, // None/null if nothing is offered
+    pub trading_status: TradingStatus,
+    pub stock_seq: u64,             // Per-stock sequence of price messages
+    pub best_ask: Option<f64>, // Same as current_price
+    pub best_ask_size: u64, // Shares offered at the best ask, 0 if there is none
+    pub best_bid: Option<f64>, // Always None/null, market buys never rest on the book
+    pub best_bid_size: u64,
+    pub last_trade_price: Option<f64>, // None/null until the stock first trades
+    pub last_trade_size: Option<u64>,
+    pub last_trade_time: Option<u64>, // Unix milliseconds
+    pub session_volume: u64, // Shares traded since midnight UTC
+    #[serde(flatten)]
+    pub meta: EventMeta,
+}
+
+/// Answer to a price snapshot request: the current quote of every stock the shard has seen.
+#[derive(Serialize, Debug, Clone)]
+pub struct PriceSnapshot {
+    pub shard_id: u32,
+    pub prices: Vec<StockPrice>,
+}
+
+// Order book types
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Ask,
+    Bid, // Not used yet, market buys never rest on the book
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PriceLevel {
+    pub price: f64,
+    pub size: u64, // Total quantity of the orders at this price
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LevelChange {
+    pub side: Side,
+    pub price: f64,
+    pub size: u64, // New total quantity at this price, 0 if the level is gone
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BookUpdate {
+    pub stock_id: String,
+    pub book_seq: u64, // Per-stock sequence of book updates
+    pub changes: Vec<LevelChange>, // Cheapest first
+    pub checksum: u32, // Of the book after the changes, see `engine::book_checksum`
+    #[serde(flatten)]
+    pub meta: EventMeta,
 }
 
-// Market buy types
 #[derive(Deserialize, Debug)]
+pub struct BookSnapshotRequest {
+    pub stock_id: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BookSnapshot {
+    pub stock_id: String,
+    pub shard_id: u32,
+    pub book_seq: u64, // The book as of this update; later updates apply on top of it
+    pub checksum: u32,
+    pub asks: Vec<PriceLevel>, // Cheapest first
+    pub bids: Vec<PriceLevel>, // Always empty, see `Side::Bid`
+}
+
+// Market buy types
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MarketBuyRequest {
     pub stock_id: String,
     pub quantity: u64,
@@ -22,6 +103,8 @@ pub struct MarketBuyRequest {
 pub struct MarketBuyResponse {
     pub success: bool,
     pub data: MarketBuyData,
+    #[serde(flatten)]
+    pub meta: EventMeta,
 }
 
 #[derive(Serialize, Debug)]
@@ -30,10 +113,21 @@ pub struct MarketBuyData {
     pub stock_tx_id: String,
     pub quantity: Option<u64>, // None if success is false
     pub price_total: Option<f64>, // None if success is false
+    pub average_price: Option<f64>, // None if success is false
+    pub trade_ids: Vec<String>, // One per fill, see `Trade`; empty if success is false
+    pub fills: Vec<Fill>, // In the order they were matched; empty if success is false
+}
+
+#[derive(Serialize, Debug)]
+pub struct Fill {
+    pub trade_id: String,
+    pub sell_stock_tx_id: String,
+    pub quantity: u64,
+    pub price: f64,
 }
 
 // Limit sell types
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LimitSellRequest {
     pub stock_id: String,
     pub stock_name: String,
@@ -42,7 +136,7 @@ pub struct LimitSellRequest {
     pub stock_tx_id: String,
     pub user_name: String,
 }
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LimitSellCancelRequest {
     pub stock_id: String,
     pub quantity: u64,
@@ -50,15 +144,12 @@ pub struct LimitSellCancelRequest {
     pub stock_tx_id: String,
 }
 
-#[derive(Serialize, Debug)]
-pub struct LimitSellResponse {
-    pub success: bool,
-}
-
 #[derive(Serialize, Debug)]
 pub struct LimitSellCancelResponse {
     pub success: bool,
     pub data: Option<LimitSellCancelData>,
+    #[serde(flatten)]
+    pub meta: EventMeta,
 }
 
 #[derive(Serialize, Debug)]
@@ -80,4 +171,21 @@ pub struct OrderUpdate {
     pub price: f64,
     pub stock_tx_id: String,
     pub user_name: String,
+    #[serde(flatten)]
+    pub meta: EventMeta,
+}
+
+// One fill of a market buy against a sell order
+#[derive(Serialize, Debug, Clone)]
+pub struct Trade {
+    pub trade_id: String, // Unique across shards: trade-<shard_id>[-<epoch>]-<trade_seq>
+    pub stock_id: String,
+    pub buy_stock_tx_id: String,
+    pub sell_stock_tx_id: String,
+    pub buyer_user_name: String,
+    pub seller_user_name: String,
+    pub quantity: u64,
+    pub price: f64,
+    #[serde(flatten)]
+    pub meta: EventMeta, // Its timestamp is the time of the trade
 }
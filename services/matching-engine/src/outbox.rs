@@ -0,0 +1,480 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tracing::{debug, error, info, warn};
+
+use shared_rabbitmq::backoff_delay;
+
+use crate::{engine::EngineEvent, envelope::Correlation, transport::EventSink};
+
+/// The log is rewritten with only the pending events once it holds this many records
+/// and nothing is waiting to be published.
+const COMPACT_AFTER_RECORDS: usize = 1000;
+
+/// An event waiting for the broker to confirm it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutboxEntry {
+    pub seq: u64,         // Event sequence number, unique per shard
+    pub command_seq: u64, // Journal sequence of the order that produced the event
+    pub exchange: String,
+    pub routing_key: String,
+    pub persistent: bool, // Published as mandatory with delivery mode 2
+    #[serde(default)]
+    pub message_type: String,
+    #[serde(default)]
+    pub timestamp: u64, // Event timestamp (Unix milliseconds)
+    #[serde(default)]
+    pub correlation: Option<Correlation>,
+    pub payload: String,
+}
+
+impl OutboxEntry {
+    pub fn from_event(
+        command_seq: u64,
+        correlation: Option<&Correlation>,
+        event: &EngineEvent,
+    ) -> serde_json::Result<Self> {
+        Ok(Self {
+            seq: event.meta().seq,
+            command_seq,
+            message_type: event.message_type().to_string(),
+            timestamp: event.meta().timestamp,
+            correlation: correlation.cloned(),
+            exchange: event.exchange().to_string(),
+            routing_key: event.routing_key(),
+            // Market data and trade reports may have no queue bound, so they must not be mandatory
+            persistent: !matches!(
+                event,
+                EngineEvent::StockPrice(_) | EngineEvent::Trade(_) | EngineEvent::BookUpdate(_)
+            ),
+            payload: String::from_utf8_lossy(&event.payload()?).into_owned(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum OutboxRecord {
+    Checkpoint { command_seq: u64 },
+    Add(OutboxEntry),
+    Confirm { seq: u64 },
+}
+
+struct OutboxLog {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    records: usize,
+}
+
+impl OutboxLog {
+    fn append(&mut self, record: &OutboxRecord) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.write_all(b"\n")?;
+        self.records += 1;
+        self.writer.flush()
+    }
+
+    /// Write a fresh log holding a checkpoint followed by the events still pending, and
+    /// open it for appending.
+    fn create(
+        path: PathBuf,
+        command_seq: Option<u64>,
+        pending: &VecDeque<OutboxEntry>,
+    ) -> std::io::Result<Self> {
+        let tmp_path = path.with_extension("jsonl.tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            if let Some(command_seq) = command_seq {
+                serde_json::to_writer(&mut writer, &OutboxRecord::Checkpoint { command_seq })?;
+                writer.write_all(b"\n")?;
+            }
+            for entry in pending {
+                serde_json::to_writer(&mut writer, &OutboxRecord::Add(entry.clone()))?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+
+        Ok(Self {
+            writer: BufWriter::new(OpenOptions::new().append(true).open(&path)?),
+            path,
+            records: pending.len() + 1,
+        })
+    }
+
+    fn compact(
+        &mut self,
+        command_seq: Option<u64>,
+        pending: &VecDeque<OutboxEntry>,
+    ) -> std::io::Result<()> {
+        *self = Self::create(self.path.clone(), command_seq, pending)?;
+        debug!("Compacted outbox log to {} pending events", pending.len());
+        Ok(())
+    }
+}
+
+struct OutboxInner {
+    pending: VecDeque<OutboxEntry>,
+    command_seq: Option<u64>, // Last order whose events have been added
+    log: Option<OutboxLog>,
+    stale: bool, // A write failed, so the log may be missing records
+}
+
+impl OutboxInner {
+    /// Append records that are already reflected in memory. After a failed write the log
+    /// is rewritten from memory instead, so the records that did not make it are not lost.
+    fn write(&mut self, records: &[OutboxRecord]) -> std::io::Result<()> {
+        let Some(log) = self.log.as_mut() else {
+            return Ok(());
+        };
+
+        let result = if self.stale {
+            log.compact(self.command_seq, &self.pending)
+        } else {
+            records.iter().try_for_each(|record| log.append(record))
+        };
+        self.stale = result.is_err();
+        result
+    }
+}
+
+/// Events that have been produced but not yet confirmed by the broker, published strictly
+/// in the order they were produced. With a data directory the outbox is also written to
+/// `outbox.jsonl`, so unconfirmed events survive a restart.
+pub struct Outbox {
+    inner: Mutex<OutboxInner>,
+    flushing: tokio::sync::Mutex<()>,
+    retry: Notify,
+    confirmed: Notify,
+}
+
+impl Outbox {
+    pub fn in_memory() -> Self {
+        Self::with_log(VecDeque::new(), None, None)
+    }
+
+    /// Load the outbox log from `dir`, creating it if needed.
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join("outbox.jsonl");
+
+        let mut pending = VecDeque::new();
+        let mut command_seq = None;
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for (line_no, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<OutboxRecord>(&line) {
+                    Ok(OutboxRecord::Checkpoint { command_seq: seq }) => {
+                        command_seq = command_seq.max(Some(seq));
+                    }
+                    Ok(OutboxRecord::Add(entry)) => {
+                        command_seq = command_seq.max(Some(entry.command_seq));
+                        pending.push_back(entry);
+                    }
+                    Ok(OutboxRecord::Confirm { seq }) => pending.retain(|entry| entry.seq != seq),
+                    Err(e) => warn!(
+                        "Skipping unreadable outbox line {} in {}: {}",
+                        line_no + 1,
+                        path.display(),
+                        e
+                    ),
+                }
+            }
+        }
+
+        let log = OutboxLog::create(path, command_seq, &pending)?;
+
+        if !pending.is_empty() {
+            info!(
+                "Loaded {} unconfirmed events from the outbox",
+                pending.len()
+            );
+        }
+        Ok(Self::with_log(pending, command_seq, Some(log)))
+    }
+
+    fn with_log(
+        pending: VecDeque<OutboxEntry>,
+        command_seq: Option<u64>,
+        log: Option<OutboxLog>,
+    ) -> Self {
+        Self {
+            inner: Mutex::new(OutboxInner {
+                pending,
+                command_seq,
+                log,
+                stale: false,
+            }),
+            flushing: tokio::sync::Mutex::new(()),
+            retry: Notify::new(),
+            confirmed: Notify::new(),
+        }
+    }
+
+    /// Journal sequence of the last order whose events made it into the outbox, or `None`
+    /// if the outbox has no history (first start with this data directory).
+    pub fn command_seq(&self) -> Option<u64> {
+        self.inner.lock().unwrap().command_seq
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// True once every event up to and including `event_seq` has been confirmed.
+    pub fn is_confirmed(&self, event_seq: u64) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .pending
+            .front()
+            .is_none_or(|entry| entry.seq > event_seq)
+    }
+
+    /// Wait up to `timeout` for every event up to and including `event_seq` to be confirmed.
+    pub async fn wait_confirmed(&self, event_seq: u64, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let confirmed = self.confirmed.notified();
+            tokio::pin!(confirmed);
+            confirmed.as_mut().enable();
+
+            if self.is_confirmed(event_seq) {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, confirmed).await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    /// Record that every order up to `command_seq` has had its events added.
+    pub fn checkpoint(&self, command_seq: u64) -> std::io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.command_seq = Some(command_seq);
+        inner.write(&[OutboxRecord::Checkpoint { command_seq }])
+    }
+
+    /// Add the events produced by the order at `command_seq`. Must be called while holding
+    /// the state write lock, so events enter the outbox in the order they were produced.
+    /// If they cannot be written to the log they are still published, but the error is
+    /// returned so the order is not acknowledged before they are on disk.
+    pub fn push(
+        &self,
+        command_seq: u64,
+        correlation: Option<&Correlation>,
+        events: &[EngineEvent],
+    ) -> std::io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.command_seq = Some(command_seq);
+
+        let mut records = Vec::with_capacity(events.len());
+        for event in events {
+            match OutboxEntry::from_event(command_seq, correlation, event) {
+                Ok(entry) => {
+                    inner.pending.push_back(entry.clone());
+                    records.push(OutboxRecord::Add(entry));
+                }
+                Err(e) => error!("Failed to serialize {} event: {}", event.routing_key(), e),
+            }
+        }
+        inner.write(&records)
+    }
+
+    fn confirm(&self, seq: u64) -> std::io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.pending.front().is_some_and(|entry| entry.seq == seq) {
+            inner.pending.pop_front();
+        }
+        self.confirmed.notify_waiters();
+        inner.write(&[OutboxRecord::Confirm { seq }])?;
+
+        let inner = &mut *inner;
+        if let Some(log) = inner.log.as_mut() {
+            if inner.pending.is_empty() && log.records >= COMPACT_AFTER_RECORDS {
+                if let Err(e) = log.compact(inner.command_seq, &inner.pending) {
+                    error!("Failed to compact outbox log: {}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Publish pending events in order until the outbox is empty or one of them is not
+    /// confirmed. Returns `true` if nothing is left.
+    pub async fn flush(&self, sink: &dyn EventSink) -> bool {
+        let _flushing = self.flushing.lock().await;
+
+        loop {
+            let Some(entry) = self.inner.lock().unwrap().pending.front().cloned() else {
+                return true;
+            };
+
+            match sink.publish(&entry).await {
+                Ok(()) => {
+                    debug!("Published {} event {}", entry.routing_key, entry.seq);
+                    if let Err(e) = self.confirm(entry.seq) {
+                        // It stays confirmed in memory; the log is rewritten on the next write
+                        error!("Failed to write outbox log: {}", e);
+                        return false;
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to publish {} event {}: {}",
+                        entry.routing_key, entry.seq, e
+                    );
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Wake the retry task so it keeps flushing until the outbox is empty.
+    pub fn schedule_retry(&self) {
+        self.retry.notify_one();
+    }
+
+    pub fn spawn_retry_task(
+        self: Arc<Self>,
+        sink: Arc<dyn EventSink>,
+        initial_delay_ms: u64,
+        max_delay_ms: u64,
+    ) {
+        tokio::spawn(async move {
+            loop {
+                self.retry.notified().await;
+
+                let mut attempt: u32 = 0;
+                loop {
+                    let delay = backoff_delay(initial_delay_ms, max_delay_ms, attempt);
+                    attempt = attempt.saturating_add(1);
+                    tokio::time::sleep(delay).await;
+
+                    if self.flush(sink.as_ref()).await {
+                        info!("Outbox drained after {} retries", attempt);
+                        break;
+                    }
+                    warn!("{} events waiting in the outbox", self.len());
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::{self, OrderCommand},
+        models::LimitSellRequest,
+        state::AppState,
+        test_dir::TestDir,
+    };
+
+    /// The events of a limit sell applied at `command_seq`.
+    fn events(state: &mut AppState, command_seq: u64) -> Vec<EngineEvent> {
+        state.last_seq = command_seq;
+        let command = OrderCommand::LimitSell(LimitSellRequest {
+            stock_id: "s1".to_string(),
+            stock_name: "Google".to_string(),
+            quantity: 10,
+            price: 5.0,
+            stock_tx_id: format!("tx{}", command_seq),
+            user_name: "alice".to_string(),
+        });
+        engine::apply(state, command, 1000 + command_seq)
+    }
+
+    fn pending_seqs(outbox: &Outbox) -> Vec<u64> {
+        let inner = outbox.inner.lock().unwrap();
+        inner.pending.iter().map(|entry| entry.seq).collect()
+    }
+
+    fn confirm_all(outbox: &Outbox) {
+        for seq in pending_seqs(outbox) {
+            outbox.confirm(seq).unwrap();
+        }
+    }
+
+    fn log_lines(dir: &Path) -> usize {
+        let contents = fs::read_to_string(dir.join("outbox.jsonl")).unwrap();
+        contents.lines().count()
+    }
+
+    #[test]
+    fn only_unconfirmed_events_come_back_after_a_restart() {
+        let dir = TestDir::new();
+        let mut state = AppState::new();
+        let outbox = Outbox::open(dir.path()).unwrap();
+        assert_eq!(outbox.command_seq(), None);
+
+        outbox.push(1, None, &events(&mut state, 1)).unwrap();
+        confirm_all(&outbox);
+        outbox.push(2, None, &events(&mut state, 2)).unwrap();
+        let unconfirmed = pending_seqs(&outbox);
+        assert!(!unconfirmed.is_empty());
+        drop(outbox);
+
+        let outbox = Outbox::open(dir.path()).unwrap();
+        assert_eq!(pending_seqs(&outbox), unconfirmed);
+        assert_eq!(outbox.command_seq(), Some(2));
+
+        // A checkpoint without events still moves the command sequence on
+        outbox.checkpoint(3).unwrap();
+        drop(outbox);
+        assert_eq!(Outbox::open(dir.path()).unwrap().command_seq(), Some(3));
+    }
+
+    #[test]
+    fn log_is_compacted_once_everything_is_confirmed() {
+        let dir = TestDir::new();
+        let mut state = AppState::new();
+        let outbox = Outbox::open(dir.path()).unwrap();
+
+        let mut command_seq = 0;
+        while log_lines(dir.path()) < COMPACT_AFTER_RECORDS - 10 {
+            command_seq += 1;
+            outbox
+                .push(command_seq, None, &events(&mut state, command_seq))
+                .unwrap();
+            confirm_all(&outbox);
+        }
+        // Not compacted while events are pending
+        command_seq += 1;
+        outbox
+            .push(command_seq, None, &events(&mut state, command_seq))
+            .unwrap();
+        assert!(!outbox.is_empty());
+        for _ in 0..10 {
+            outbox.checkpoint(command_seq).unwrap();
+        }
+        assert!(log_lines(dir.path()) >= COMPACT_AFTER_RECORDS);
+
+        confirm_all(&outbox);
+        // Only the checkpoint is left
+        assert_eq!(log_lines(dir.path()), 1);
+        drop(outbox);
+
+        let outbox = Outbox::open(dir.path()).unwrap();
+        assert!(outbox.is_empty());
+        assert_eq!(outbox.command_seq(), Some(command_seq));
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+use crate::{
+    engine::{self, OrderCommand},
+    envelope::Correlation,
+    journal::{self, Journal, JournalEntry},
+    outbox::Outbox,
+    snapshot,
+    state::AppState,
+};
+
+pub struct PersistenceConfig {
+    pub data_dir: PathBuf,
+    pub snapshot_interval_secs: u64,
+    pub snapshot_retain: usize,
+}
+
+/// Journal of applied orders plus periodic snapshots of the book. On startup the latest
+/// valid snapshot is loaded and only the journal entries after it are replayed.
+pub struct Persistence {
+    config: PersistenceConfig,
+    journal: Mutex<Journal>,
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl Persistence {
+    /// Rebuild the state from disk and open the journal for new orders. Events of journaled
+    /// orders that never made it into the outbox (crash in between) are added to it.
+    pub fn restore(
+        config: PersistenceConfig,
+        shard_id: u32,
+        outbox: &Outbox,
+    ) -> std::io::Result<(Self, AppState)> {
+        let journal_dir = config.data_dir.join("journal");
+        let snapshot_dir = config.data_dir.join("snapshots");
+
+        let mut state = match snapshot::load_latest(&snapshot_dir)? {
+            Some(snapshot) => AppState::from_snapshot(snapshot),
+            None => {
+                info!("No snapshot found, replaying journal from the beginning");
+                AppState::new()
+            }
+        };
+        state.shard_id = shard_id;
+
+        // Events already in the outbox (or published before it existed) are dropped here
+        let entries = journal::read_after(&journal_dir, state.last_seq)?;
+        let replayed = entries.len();
+        let outbox_seq = outbox.command_seq();
+        let mut recovered = 0;
+        for entry in entries {
+            state.last_seq = entry.seq;
+            let events = engine::apply(&mut state, entry.command, entry.received_at);
+            if outbox_seq.is_some_and(|seq| entry.seq > seq) {
+                recovered += events.len();
+                outbox.push(entry.seq, entry.correlation.as_ref(), &events)?;
+            }
+        }
+        outbox.checkpoint(state.last_seq)?;
+        if recovered > 0 {
+            info!(
+                "Recovered {} unpublished events from the journal",
+                recovered
+            );
+        }
+
+        info!(
+            "Restored state at sequence {} ({} journal entries replayed)",
+            state.last_seq, replayed
+        );
+
+        let journal = Journal::open(&journal_dir, state.last_seq + 1)?;
+        Ok((
+            Self {
+                config,
+                journal: Mutex::new(journal),
+            },
+            state,
+        ))
+    }
+
+    /// Journal the command as the next sequence number. Must be called while holding the
+    /// state write lock, right before applying the command. If that fails the command must
+    /// not be applied, as the state could not be rebuilt from the journal after a restart.
+    pub fn record(
+        &self,
+        state: &AppState,
+        command: OrderCommand,
+        correlation: Correlation,
+    ) -> std::io::Result<JournalEntry> {
+        let entry = JournalEntry {
+            seq: state.last_seq + 1,
+            received_at: now_millis(),
+            command,
+            correlation: Some(correlation),
+        };
+
+        self.journal.lock().unwrap().append(&entry)?;
+        Ok(entry)
+    }
+
+    /// Write a snapshot of the current state and drop snapshots and journal segments
+    /// that are no longer needed.
+    pub async fn take_snapshot(&self, state: &RwLock<AppState>) -> std::io::Result<()> {
+        // Holding the lock keeps orders out while the journal switches to a new segment
+        let snapshot = {
+            let state = state.read().await;
+            self.journal.lock().unwrap().rotate(state.last_seq + 1)?;
+            state.to_snapshot()
+        };
+
+        let journal_dir = self.config.data_dir.join("journal");
+        let snapshot_dir = self.config.data_dir.join("snapshots");
+        let retain = self.config.snapshot_retain;
+        let seq = snapshot.last_seq;
+
+        tokio::task::spawn_blocking(move || {
+            snapshot::write(&snapshot_dir, &snapshot)?;
+            if let Some(oldest_seq) = snapshot::prune(&snapshot_dir, retain)? {
+                journal::prune(&journal_dir, oldest_seq)?;
+            }
+            Ok::<_, std::io::Error>(())
+        })
+        .await??;
+
+        info!("Snapshot taken at sequence {}", seq);
+        Ok(())
+    }
+
+    pub fn spawn_snapshot_task(self: Arc<Self>, state: Arc<RwLock<AppState>>) {
+        let period = Duration::from_secs(self.config.snapshot_interval_secs.max(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.tick().await; // First tick completes immediately
+
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.take_snapshot(&state).await {
+                    error!("Failed to take periodic snapshot: {}", e);
+                }
+            }
+        });
+    }
+}
@@ -1,16 +1,139 @@
 use amqprs::{
-    callbacks::{DefaultChannelCallback, DefaultConnectionCallback},
+    callbacks::{ChannelCallback, ConnectionCallback},
     channel::{
-        BasicConsumeArguments, BasicPublishArguments, Channel, ExchangeDeclareArguments,
-        QueueBindArguments, QueueDeclareArguments,
+        BasicAckArguments, BasicCancelArguments, BasicConsumeArguments, BasicNackArguments,
+        BasicPublishArguments, BasicQosArguments, Channel, ConfirmSelectArguments,
+        ExchangeDeclareArguments, QueueBindArguments, QueueDeclareArguments,
     },
     connection::{Connection, OpenConnectionArguments},
     consumer::AsyncConsumer,
-    BasicProperties,
+    Ack, BasicProperties, Cancel, Close, CloseChannel, Deliver, FieldTable, FieldValue, Nack,
+    Return,
 };
-use std::sync::Arc;
+use async_trait::async_trait;
+use serde::Serialize;
+use shared_rabbitmq::{backoff_delay, declare, TopologyMismatch};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{oneshot, watch, Notify, RwLock};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    dead_letter::{add_failure_headers, Failure},
+    envelope::{Envelope, PRODUCER, SCHEMA_VERSION},
+    models::BookSnapshotRequest,
+    outbox::OutboxEntry,
+    persistence::now_millis,
+    transport::{
+        Disposition, EventSink, InboundMessage, OrderHandler, OrderSource, TransportError,
+    },
+};
+
+/// Exchange shared by every service for messages that could not be processed.
+pub const DEAD_LETTER_EXCHANGE: &str = "dead_letter_exchange";
+
+/// Dead-letter queue of a shard; also the routing key its dead letters are published with.
+pub fn dead_letter_queue(shard_id: u32) -> String {
+    format!("matching_engine_dead_letter_shard_{}", shard_id)
+}
+
+/// Queue a shard takes order book snapshot requests from. Requests are published to
+/// `market_data_exchange` with routing key `book.snapshot_request.shard_<shard_id>`.
+pub fn book_snapshot_queue(shard_id: u32) -> String {
+    format!("book_snapshot_queue_shard_{}", shard_id)
+}
+
+/// Queue a shard takes price snapshot requests from. Requests are published to
+/// `stock_prices_exchange` with routing key `price.snapshot_request.shard_<shard_id>`.
+pub fn price_snapshot_queue(shard_id: u32) -> String {
+    format!("price_snapshot_queue_shard_{}", shard_id)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueType {
+    Classic,
+    Quorum,
+}
 
-use crate::models::{LimitSellCancelResponse, MarketBuyResponse, OrderUpdate, StockPrice};
+/// How the order exchanges and this shard's order queues are declared. Everything that
+/// already exists on the broker must have been declared the same way, otherwise the engine
+/// stops with a `TopologyMismatch` instead of retrying.
+#[derive(Debug, Clone)]
+pub struct TopologyConfig {
+    pub durable_exchanges: bool, // Shared with the other services, they have to agree
+    pub durable_queues: bool,
+    pub queue_type: QueueType,
+    pub message_ttl_ms: Option<u32>, // Expired orders go to the dead-letter queue
+    pub max_length: Option<u32>,     // Publishes beyond this are rejected
+}
+
+impl Default for TopologyConfig {
+    fn default() -> Self {
+        Self {
+            durable_exchanges: false,
+            durable_queues: false,
+            queue_type: QueueType::Classic,
+            message_ttl_ms: None,
+            max_length: None,
+        }
+    }
+}
+
+impl TopologyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.queue_type == QueueType::Quorum && !self.durable_queues {
+            return Err("quorum queues must be durable".to_string());
+        }
+        Ok(())
+    }
+
+    /// `x-` arguments for an order queue whose dead letters go to `dead_letter_queue`.
+    fn queue_arguments(&self, dead_letter_queue: &str) -> FieldTable {
+        let mut arguments = FieldTable::new();
+        let mut set = |name: &str, value: FieldValue| {
+            if let Ok(name) = name.try_into() {
+                arguments.insert(name, value);
+            }
+        };
+        if self.queue_type == QueueType::Quorum {
+            set("x-queue-type", "quorum".into());
+        }
+        if let Some(ttl) = self.message_ttl_ms {
+            set("x-message-ttl", FieldValue::l(ttl as i64));
+        }
+        if let Some(max_length) = self.max_length {
+            set("x-max-length", FieldValue::l(max_length as i64));
+            set("x-overflow", "reject-publish".into());
+        }
+        if self.message_ttl_ms.is_some() {
+            set("x-dead-letter-exchange", DEAD_LETTER_EXCHANGE.into());
+            set("x-dead-letter-routing-key", dead_letter_queue.into());
+        }
+        arguments
+    }
+}
+
+/// How many unacknowledged orders the broker pushes to the engine per queue. Orders of one
+/// type are handled one at a time, so this only bounds what is buffered in memory.
+#[derive(Debug, Clone)]
+pub struct PrefetchConfig {
+    pub market_buy: u16,
+    pub limit_sell: u16,
+    pub cancel_sell: u16,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        Self {
+            market_buy: 10,
+            limit_sell: 10,
+            cancel_sell: 10,
+        }
+    }
+}
 
 pub struct RabbitMQConfig {
     pub host: String,
@@ -18,6 +141,11 @@ pub struct RabbitMQConfig {
     pub username: String,
     pub password: String,
     pub shard_id: u32,
+    pub reconnect_initial_delay_ms: u64,
+    pub reconnect_max_delay_ms: u64,
+    pub confirm_timeout_ms: u64,
+    pub topology: TopologyConfig,
+    pub prefetch: PrefetchConfig,
 }
 
 impl Default for RabbitMQConfig {
@@ -28,18 +156,336 @@ impl Default for RabbitMQConfig {
             username: "guest".to_string(),
             password: "guest".to_string(),
             shard_id: 0,
+            reconnect_initial_delay_ms: 500,
+            reconnect_max_delay_ms: 30_000,
+            confirm_timeout_ms: 5_000,
+            topology: TopologyConfig::default(),
+            prefetch: PrefetchConfig::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Reconnecting,
+    Failed, // Declarations do not match the broker, not retried
+    Closed,
+}
+
+type ConfirmResult = Result<(), String>;
+
+/// Publisher confirms for one channel. In confirm mode the broker numbers publishes from 1
+/// in the order it receives them and acks or nacks each number; an unroutable mandatory
+/// message is returned (matched by message id) right before its ack.
+#[derive(Default)]
+struct ConfirmTracker {
+    published: u64,
+    pending: BTreeMap<u64, (String, oneshot::Sender<ConfirmResult>)>,
+    returned: HashMap<String, String>,
+}
+
+impl ConfirmTracker {
+    fn register(&mut self, message_id: String) -> oneshot::Receiver<ConfirmResult> {
+        let (sender, receiver) = oneshot::channel();
+        self.published += 1;
+        self.pending.insert(self.published, (message_id, sender));
+        receiver
+    }
+
+    fn settle(&mut self, delivery_tag: u64, multiple: bool, acked: bool) {
+        let tags: Vec<u64> = if multiple {
+            self.pending
+                .range(..=delivery_tag)
+                .map(|(tag, _)| *tag)
+                .collect()
+        } else {
+            vec![delivery_tag]
+        };
+
+        for tag in tags {
+            let Some((message_id, sender)) = self.pending.remove(&tag) else {
+                continue;
+            };
+            let result = match self.returned.remove(&message_id) {
+                Some(reason) => Err(format!("returned by broker: {}", reason)),
+                None if !acked => Err("nacked by broker".to_string()),
+                None => Ok(()),
+            };
+            let _ = sender.send(result);
+        }
+    }
+
+    /// Fail everything still waiting, the channel is gone and its confirms with it.
+    fn abandon(&mut self) {
+        self.pending.clear();
+        self.returned.clear();
+    }
+}
+
+/// Connection, channels and consumers that live and die together. A new session is
+/// opened by the supervisor every time the connection or a channel is lost.
+struct Session {
+    connection: Connection,
+    channel: Channel, // Publishing (in confirm mode) and declarations
+    consumers: Vec<(Channel, String)>, // One channel per consumed queue, with its consumer tag
+    lost: Arc<Notify>,
+    confirms: Arc<Mutex<ConfirmTracker>>,
+    publishing: tokio::sync::Mutex<()>, // Keeps publish order in line with confirm numbers
+}
+
+/// Logs broker-initiated closes and wakes the supervisor so it can reconnect. On the
+/// channel it also resolves publisher confirms.
+struct SessionCallback {
+    lost: Arc<Notify>,
+    confirms: Arc<Mutex<ConfirmTracker>>,
+    closed: Arc<Mutex<Option<(u16, String)>>>, // Reply code and text of a broker channel close
+}
+
+#[async_trait]
+impl ConnectionCallback for SessionCallback {
+    async fn close(
+        &mut self,
+        connection: &Connection,
+        close: Close,
+    ) -> Result<(), amqprs::error::Error> {
+        warn!("Connection {} closed by broker: {}", connection, close);
+        self.lost.notify_one();
+        Ok(())
+    }
+
+    async fn blocked(&mut self, connection: &Connection, reason: String) {
+        warn!("Connection {} blocked by broker: {}", connection, reason);
+    }
+
+    async fn unblocked(&mut self, connection: &Connection) {
+        info!("Connection {} unblocked", connection);
+    }
+}
+
+#[async_trait]
+impl ChannelCallback for SessionCallback {
+    async fn close(
+        &mut self,
+        channel: &Channel,
+        close: CloseChannel,
+    ) -> Result<(), amqprs::error::Error> {
+        warn!("Channel {} closed by broker: {}", channel, close);
+        *self.closed.lock().unwrap() = Some((close.reply_code(), close.reply_text().clone()));
+        self.lost.notify_one();
+        Ok(())
+    }
+
+    async fn cancel(
+        &mut self,
+        channel: &Channel,
+        cancel: Cancel,
+    ) -> Result<(), amqprs::error::Error> {
+        warn!(
+            "Consumer {} cancelled by broker on channel {}",
+            cancel.consumer_tag(),
+            channel
+        );
+        self.lost.notify_one();
+        Ok(())
+    }
+
+    async fn flow(
+        &mut self,
+        channel: &Channel,
+        active: bool,
+    ) -> Result<bool, amqprs::error::Error> {
+        info!("Flow control on channel {}: active={}", channel, active);
+        Ok(true)
+    }
+
+    async fn publish_ack(&mut self, _: &Channel, ack: Ack) {
+        self.confirms
+            .lock()
+            .unwrap()
+            .settle(ack.delivery_tag(), ack.mutiple(), true);
+    }
+
+    async fn publish_nack(&mut self, channel: &Channel, nack: Nack) {
+        warn!(
+            "Broker nacked publish {} on channel {}",
+            nack.delivery_tag(),
+            channel
+        );
+        self.confirms
+            .lock()
+            .unwrap()
+            .settle(nack.delivery_tag(), nack.multiple(), false);
+    }
+
+    async fn publish_return(
+        &mut self,
+        channel: &Channel,
+        ret: Return,
+        properties: BasicProperties,
+        _: Vec<u8>,
+    ) {
+        warn!("Message returned on channel {}: {}", channel, ret);
+        if let Some(message_id) = properties.message_id() {
+            self.confirms
+                .lock()
+                .unwrap()
+                .returned
+                .insert(message_id.clone(), ret.reply_text().clone());
         }
     }
 }
 
 pub struct RabbitMQClient {
-    _connection: Connection, // Keep connection alive
-    channel: Arc<Channel>,
     config: RabbitMQConfig,
+    session: RwLock<Option<Session>>,
+    state: watch::Sender<ConnectionState>,
+    shutdown: watch::Sender<bool>,
+    failure: watch::Sender<Option<String>>,
 }
 
 impl RabbitMQClient {
-    pub async fn new(config: RabbitMQConfig) -> Result<Self, Box<dyn std::error::Error>> {
+    /// Create the client without connecting; the supervisor started by `setup_consumer`
+    /// opens the connection and keeps it open.
+    pub fn new(config: RabbitMQConfig) -> Self {
+        Self {
+            config,
+            session: RwLock::new(None),
+            state: watch::Sender::new(ConnectionState::Connecting),
+            shutdown: watch::Sender::new(false),
+            failure: watch::Sender::new(None),
+        }
+    }
+
+    pub fn shard_id(&self) -> u32 {
+        self.config.shard_id
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.state.borrow()
+    }
+
+    /// Resolves with the reason once the supervisor has given up for good.
+    pub async fn failed(&self) -> String {
+        let mut failure = self.failure.subscribe();
+        let reason = failure
+            .wait_for(Option::is_some)
+            .await
+            .ok()
+            .and_then(|reason| reason.clone());
+        match reason {
+            Some(reason) => reason,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Start the connection supervisor. It connects (retrying with exponential backoff),
+    /// declares the exchanges, queues and bindings, registers `consumer` on every order
+    /// queue and `snapshots` on the book snapshot request queue, and does all of that again
+    /// whenever the connection or channel is lost.
+    pub async fn setup_consumer<C, S>(
+        self: &Arc<Self>,
+        consumer: C,
+        snapshots: S,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        C: AsyncConsumer + Clone + Send + Sync + 'static,
+        S: AsyncConsumer + Clone + Send + Sync + 'static,
+    {
+        let client = Arc::clone(self);
+        tokio::spawn(async move { client.supervise(consumer, snapshots).await });
+        Ok(())
+    }
+
+    async fn supervise<C, S>(&self, consumer: C, snapshots: S)
+    where
+        C: AsyncConsumer + Clone + Send + 'static,
+        S: AsyncConsumer + Clone + Send + 'static,
+    {
+        let mut shutdown = self.shutdown.subscribe();
+        let mut attempt: u32 = 0;
+
+        while !*shutdown.borrow() {
+            match self.open_session(consumer.clone(), snapshots.clone()).await {
+                Ok(session) => {
+                    attempt = 0;
+                    let connection = session.connection.clone();
+                    let lost = Arc::clone(&session.lost);
+                    *self.session.write().await = Some(session);
+                    self.state.send_replace(ConnectionState::Connected);
+                    info!(
+                        "Connected to RabbitMQ at {}:{}",
+                        self.config.host, self.config.port
+                    );
+
+                    tokio::select! {
+                        _ = connection.listen_network_io_failure() => {}
+                        _ = lost.notified() => {}
+                        _ = shutdown.changed() => {}
+                    }
+                    if *shutdown.borrow() {
+                        break;
+                    }
+
+                    warn!("Lost RabbitMQ connection, reconnecting");
+                    self.state.send_replace(ConnectionState::Reconnecting);
+                    if let Some(session) = self.session.write().await.take() {
+                        session.confirms.lock().unwrap().abandon();
+                        // Best effort, the broker may already be gone
+                        for (channel, _) in session.consumers {
+                            let _ = channel.close().await;
+                        }
+                        let _ = session.channel.close().await;
+                        let _ = session.connection.close().await;
+                    }
+                }
+                Err(e) if e.is::<TopologyMismatch>() => {
+                    error!("RabbitMQ declarations do not match the broker: {}", e);
+                    self.state.send_replace(ConnectionState::Failed);
+                    self.failure.send_replace(Some(e.to_string()));
+                    break;
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to connect to RabbitMQ at {}:{}: {}",
+                        self.config.host, self.config.port, e
+                    );
+                }
+            }
+
+            let delay = backoff_delay(
+                self.config.reconnect_initial_delay_ms,
+                self.config.reconnect_max_delay_ms,
+                attempt,
+            );
+            attempt = attempt.saturating_add(1);
+            info!("Retrying RabbitMQ connection in {:?}", delay);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.changed() => {}
+            }
+        }
+
+        debug!("RabbitMQ supervisor stopped");
+    }
+
+    async fn open_session<C, S>(
+        &self,
+        consumer: C,
+        snapshots: S,
+    ) -> Result<Session, Box<dyn std::error::Error + Send + Sync>>
+    where
+        C: AsyncConsumer + Clone + Send + 'static,
+        S: AsyncConsumer + Clone + Send + 'static,
+    {
+        let config = &self.config;
+        let topology = &config.topology;
+        let lost = Arc::new(Notify::new());
+        let confirms = Arc::new(Mutex::new(ConfirmTracker::default()));
+        let closed = Arc::new(Mutex::new(None));
+
         // Open connection
         let connection = Connection::open(&OpenConnectionArguments::new(
             &config.host,
@@ -51,196 +497,528 @@ impl RabbitMQClient {
 
         // Register connection callbacks
         connection
-            .register_callback(DefaultConnectionCallback)
+            .register_callback(SessionCallback {
+                lost: Arc::clone(&lost),
+                confirms: Arc::clone(&confirms),
+                closed: Arc::clone(&closed),
+            })
             .await?;
 
-        // Open a channel
+        // Open the publishing channel and register its callbacks
         let channel = connection.open_channel(None).await?;
+        channel
+            .register_callback(SessionCallback {
+                lost: Arc::clone(&lost),
+                confirms: Arc::clone(&confirms),
+                closed: Arc::clone(&closed),
+            })
+            .await?;
 
-        // Register channel callbacks
-        channel.register_callback(DefaultChannelCallback).await?;
+        // Every event published on this channel is confirmed by the broker
+        channel
+            .confirm_select(ConfirmSelectArguments::default())
+            .await?;
 
         // Declare exchanges
-        // Exchange for receiving orders
-        let order_exchange_args = ExchangeDeclareArguments::new("order_exchange", "topic")
-            .durable(false)
-            .finish();
-        channel.exchange_declare(order_exchange_args).await?;
-
-        // Exchange for sending order updates
-        let order_update_exchange_args =
-            ExchangeDeclareArguments::new("order_update_exchange", "direct")
-                .durable(false)
+        for (exchange, exchange_type) in [
+            ("order_exchange", "topic"),         // Exchange for receiving orders
+            ("order_update_exchange", "direct"), // Exchange for sending order updates
+            ("stock_prices_exchange", "topic"),  // Exchange for stock price updates
+            ("market_data_exchange", "topic"),   // Exchange for order book updates and snapshots
+        ] {
+            let args = ExchangeDeclareArguments::new(exchange, exchange_type)
+                .durable(topology.durable_exchanges)
                 .finish();
-        channel.exchange_declare(order_update_exchange_args).await?;
+            declare(
+                format!(
+                    "exchange '{}' (durable={})",
+                    exchange, topology.durable_exchanges
+                ),
+                &closed,
+                channel.exchange_declare(args),
+            )
+            .await?;
+        }
 
-        // Exchange for stock price updates
-        let stock_prices_exchange_args =
-            ExchangeDeclareArguments::new("stock_prices_exchange", "topic")
-                .durable(false)
-                .finish();
-        channel.exchange_declare(stock_prices_exchange_args).await?;
+        let shard_id = config.shard_id;
 
-        Ok(Self {
-            _connection: connection, // Store connection
-            channel: Arc::new(channel),
-            config,
-        })
-    }
+        // Exchange and queue for orders that could not be processed. Unlike the order
+        // queues they are always durable, so nothing parked there is lost if the broker
+        // restarts.
+        let dead_letter_exchange_args =
+            ExchangeDeclareArguments::new(DEAD_LETTER_EXCHANGE, "direct")
+                .durable(true)
+                .finish();
+        declare(
+            format!("exchange '{}' (durable=true)", DEAD_LETTER_EXCHANGE),
+            &closed,
+            channel.exchange_declare(dead_letter_exchange_args),
+        )
+        .await?;
 
-    pub async fn setup_consumer<C: AsyncConsumer + Clone + Send + 'static>(
-        &self,
-        consumer: C,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let shard_id = self.config.shard_id;
-
-        // Declare queue for market buy orders specific to this shard
-        let market_buy_queue = QueueDeclareArguments::new(&format!(
-            "market_buy_queue_shard_{}",
-            shard_id
-        ));
-        let (market_buy_queue_name, _, _) =
-            self.channel.queue_declare(market_buy_queue).await?.unwrap();
-
-        // Bind queue with shard-specific routing pattern
-        self.channel
+        let dead_letter_queue_name = dead_letter_queue(shard_id);
+        declare(
+            format!("queue '{}' (durable=true)", dead_letter_queue_name),
+            &closed,
+            channel.queue_declare(
+                QueueDeclareArguments::new(&dead_letter_queue_name)
+                    .durable(true)
+                    .finish(),
+            ),
+        )
+        .await?;
+        channel
             .queue_bind(QueueBindArguments::new(
-                &market_buy_queue_name,
-                "order_exchange",
-                &format!("order.market_buy.shard_{}", shard_id),
+                &dead_letter_queue_name,
+                DEAD_LETTER_EXCHANGE,
+                &dead_letter_queue_name,
             ))
             .await?;
 
-        // Declare queue for limit sell orders specific to this shard
-        let limit_sell_queue = QueueDeclareArguments::new(&format!(
-            "limit_sell_queue_shard_{}",
-            shard_id
-        ));
-        let (limit_sell_queue_name, _, _) =
-            self.channel.queue_declare(limit_sell_queue).await?.unwrap();
-
-        // Bind queue with shard-specific routing pattern
-        self.channel
-            .queue_bind(QueueBindArguments::new(
-                &limit_sell_queue_name,
-                "order_exchange",
-                &format!("order.limit_sell.shard_{}", shard_id),
-            ))
+        // Declare this shard's order queues, bind them with shard-specific routing keys
+        // and set up a consumer on each. Every consumer gets its own channel with its own
+        // prefetch, so a backlog of one order type is never pushed ahead of the others.
+        let queue_arguments = topology.queue_arguments(&dead_letter_queue_name);
+        let mut consumers = Vec::new();
+        for (name, order_type, prefetch) in [
+            ("market_buy", "market_buy", config.prefetch.market_buy),
+            ("limit_sell", "limit_sell", config.prefetch.limit_sell),
+            (
+                "cancel_sell",
+                "limit_sell_cancellation",
+                config.prefetch.cancel_sell,
+            ),
+        ] {
+            let queue_name = format!("{}_queue_shard_{}", name, shard_id);
+            let args = QueueDeclareArguments::new(&queue_name)
+                .durable(topology.durable_queues)
+                .arguments(queue_arguments.clone())
+                .finish();
+            declare(
+                format!(
+                    "queue '{}' (durable={}, type={:?}, ttl={:?}, max-length={:?})",
+                    queue_name,
+                    topology.durable_queues,
+                    topology.queue_type,
+                    topology.message_ttl_ms,
+                    topology.max_length
+                ),
+                &closed,
+                channel.queue_declare(args),
+            )
             .await?;
 
-        // Declare queue for sell cancellations specific to this shard
-        let cancel_sell_queue = QueueDeclareArguments::new(&format!(
-            "cancel_sell_queue_shard_{}",
-            shard_id
-        ));
-        let (cancel_sell_queue_name, _, _) = self
-            .channel
-            .queue_declare(cancel_sell_queue)
-            .await?
-            .unwrap();
-
-        // Bind queue with shard-specific routing pattern
-        self.channel
-            .queue_bind(QueueBindArguments::new(
-                &cancel_sell_queue_name,
-                "order_exchange",
-                &format!("order.limit_sell_cancellation.shard_{}", shard_id),
-            ))
-            .await?;
+            channel
+                .queue_bind(QueueBindArguments::new(
+                    &queue_name,
+                    "order_exchange",
+                    &format!("order.{}.shard_{}", order_type, shard_id),
+                ))
+                .await?;
 
-        // Set up consumers
-        let market_buy_args = BasicConsumeArguments::new(
-            &market_buy_queue_name,
-            &format!("market_buy_consumer_{}", shard_id),
-        )
-        .finish();
-        self.channel
-            .basic_consume(consumer.clone(), market_buy_args)
-            .await?;
+            let consume_channel = connection.open_channel(None).await?;
+            consume_channel
+                .register_callback(SessionCallback {
+                    lost: Arc::clone(&lost),
+                    confirms: Arc::clone(&confirms),
+                    closed: Arc::clone(&closed),
+                })
+                .await?;
+            consume_channel
+                .basic_qos(BasicQosArguments::new(0, prefetch, false))
+                .await?;
+            let consume_args =
+                BasicConsumeArguments::new(&queue_name, &format!("{}_consumer_{}", name, shard_id))
+                    .finish();
+            let consumer_tag = consume_channel
+                .basic_consume(consumer.clone(), consume_args)
+                .await?;
+            consumers.push((consume_channel, consumer_tag));
+        }
 
-        let limit_sell_args = BasicConsumeArguments::new(
-            &limit_sell_queue_name,
-            &format!("limit_sell_consumer_{}", shard_id),
-        )
-        .finish();
-        self.channel
-            .basic_consume(consumer.clone(), limit_sell_args)
+        // Order book and price snapshot requests. Answers are only useful right away, so the
+        // queues are never durable and requests are not acknowledged.
+        for (snapshot_queue_name, exchange, routing_key, consumer_name) in [
+            (
+                book_snapshot_queue(shard_id),
+                "market_data_exchange",
+                format!("book.snapshot_request.shard_{}", shard_id),
+                "book_snapshot_consumer",
+            ),
+            (
+                price_snapshot_queue(shard_id),
+                "stock_prices_exchange",
+                format!("price.snapshot_request.shard_{}", shard_id),
+                "price_snapshot_consumer",
+            ),
+        ] {
+            declare(
+                format!("queue '{}' (durable=false)", snapshot_queue_name),
+                &closed,
+                channel.queue_declare(QueueDeclareArguments::new(&snapshot_queue_name)),
+            )
             .await?;
+            channel
+                .queue_bind(QueueBindArguments::new(
+                    &snapshot_queue_name,
+                    exchange,
+                    &routing_key,
+                ))
+                .await?;
+            let snapshot_channel = connection.open_channel(None).await?;
+            snapshot_channel
+                .register_callback(SessionCallback {
+                    lost: Arc::clone(&lost),
+                    confirms: Arc::clone(&confirms),
+                    closed: Arc::clone(&closed),
+                })
+                .await?;
+            let snapshot_consume_args = BasicConsumeArguments::new(
+                &snapshot_queue_name,
+                &format!("{}_{}", consumer_name, shard_id),
+            )
+            .manual_ack(false)
+            .finish();
+            let consumer_tag = snapshot_channel
+                .basic_consume(snapshots.clone(), snapshot_consume_args)
+                .await?;
+            consumers.push((snapshot_channel, consumer_tag));
+        }
 
-        let cancel_sell_args = BasicConsumeArguments::new(
-            &cancel_sell_queue_name,
-            &format!("cancel_sell_consumer_{}", shard_id),
-        )
-        .finish();
-        self.channel
-            .basic_consume(consumer, cancel_sell_args)
-            .await?;
+        Ok(Session {
+            connection,
+            channel,
+            consumers,
+            lost,
+            confirms,
+            publishing: tokio::sync::Mutex::new(()),
+        })
+    }
 
-        Ok(())
+    /// Cancel all consumers so the broker stops delivering orders to this shard.
+    /// Unacked orders are requeued by the broker once the channel closes.
+    pub async fn stop_consuming(&self) {
+        let mut session = self.session.write().await;
+        let Some(session) = session.as_mut() else {
+            return;
+        };
+
+        for (channel, consumer_tag) in &session.consumers {
+            match channel
+                .basic_cancel(BasicCancelArguments::new(consumer_tag))
+                .await
+            {
+                Ok(_) => info!("Cancelled consumer {}", consumer_tag),
+                Err(e) => error!("Failed to cancel consumer {}: {}", consumer_tag, e),
+            }
+        }
     }
 
-    pub async fn publish_stock_price(
-        &self,
-        payload: StockPrice,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let routing_key = format!("stock.price.{}", payload.stock_id);
-        let args = BasicPublishArguments::new("stock_prices_exchange", &routing_key);
-
-        self.channel
-            .basic_publish(
-                BasicProperties::default(),
-                serde_json::to_vec(&payload)?,
-                args,
-            )
-            .await?;
+    /// Stop the supervisor, then close the channel and the connection. Closing waits for
+    /// the broker to acknowledge, so everything published before this point has been flushed.
+    pub async fn close(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.shutdown.send_replace(true);
+        self.state.send_replace(ConnectionState::Closed);
 
+        if let Some(session) = self.session.write().await.take() {
+            for (channel, _) in session.consumers {
+                channel.close().await?;
+            }
+            session.channel.close().await?;
+            session.connection.close().await?;
+        }
         Ok(())
     }
 
-    pub async fn publish_order_cancelled(
+    /// Publish an outbox entry and wait for the broker to confirm it. Nacks, returns
+    /// (unroutable mandatory messages), timeouts and connection loss are all errors, the
+    /// entry stays in the outbox and is published again later.
+    pub async fn publish(
         &self,
-        payload: &LimitSellCancelResponse,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        self.publish_order_update("cancelled", &serde_json::to_value(&payload).unwrap())
-            .await
+        entry: &OutboxEntry,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let message_id = format!("{}-{}", self.config.shard_id, entry.seq);
+        let correlation = entry.correlation.as_ref();
+        let mut properties = if entry.message_type.is_empty() {
+            // Written to the outbox before events had an envelope
+            BasicProperties::default()
+                .with_message_id(&message_id)
+                .finish()
+        } else {
+            Envelope {
+                message_type: entry.message_type.clone(),
+                schema_version: SCHEMA_VERSION,
+                message_id,
+                correlation_id: correlation.map(|correlation| correlation.correlation_id.clone()),
+                causation_id: correlation.map(|correlation| correlation.causation_id.clone()),
+                producer: PRODUCER.to_string(),
+                timestamp: entry.timestamp / 1000,
+            }
+            .to_properties()
+        };
+        if entry.persistent {
+            properties.with_delivery_mode(2); // Make message persistent
+        }
+
+        self.publish_confirmed(
+            &entry.exchange,
+            &entry.routing_key,
+            properties,
+            entry.payload.clone().into_bytes(),
+            entry.persistent, // Ensure order updates are routed
+        )
+        .await
     }
 
-    pub async fn publish_buy_completed(
+    /// Park a message that cannot be processed in this shard's dead-letter queue, keeping
+    /// its body and properties and adding headers that describe the failure. Returns once
+    /// the broker has confirmed it, so the original can be acknowledged.
+    pub async fn dead_letter(
         &self,
-        payload: &MarketBuyResponse,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        self.publish_order_update("buy_completed", &serde_json::to_value(&payload).unwrap())
+        deliver: &Deliver,
+        mut properties: BasicProperties,
+        content: Vec<u8>,
+        reason: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        add_failure_headers(
+            &mut properties,
+            &Failure {
+                reason,
+                exchange: deliver.exchange(),
+                routing_key: deliver.routing_key(),
+                shard_id: Some(self.config.shard_id as i64),
+                failed_at: now_millis(),
+                service: "matching-engine",
+            },
+        );
+        properties.with_delivery_mode(2); // Make message persistent
+
+        let queue = dead_letter_queue(self.config.shard_id);
+        self.publish_confirmed(DEAD_LETTER_EXCHANGE, &queue, properties, content, true)
             .await
     }
 
-    pub async fn publish_sale_update(
+    async fn publish_confirmed(
         &self,
-        payload: &OrderUpdate,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        self.publish_order_update("sale_update", &serde_json::to_value(&payload).unwrap())
+        exchange: &str,
+        routing_key: &str,
+        properties: BasicProperties,
+        content: Vec<u8>,
+        mandatory: bool,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let confirmed = {
+            let session = self.session.read().await;
+            let Some(session) = session.as_ref() else {
+                return Err("not connected to RabbitMQ".into());
+            };
+
+            let args = BasicPublishArguments::new(exchange, routing_key)
+                .mandatory(mandatory)
+                .finish();
+            // Returns are matched to their publish by message id
+            let message_id = properties.message_id().cloned().unwrap_or_default();
+
+            let _publishing = session.publishing.lock().await;
+            let confirmed = session.confirms.lock().unwrap().register(message_id);
+            session
+                .channel
+                .basic_publish(properties, content, args)
+                .await?;
+            confirmed
+        };
+
+        let timeout = Duration::from_millis(self.config.confirm_timeout_ms);
+        match tokio::time::timeout(timeout, confirmed).await {
+            Ok(Ok(result)) => result.map_err(Into::into),
+            Ok(Err(_)) => Err("connection lost before the broker confirmed".into()),
+            Err(_) => Err(format!("no confirm from the broker within {:?}", timeout).into()),
+        }
+    }
+}
+
+#[async_trait]
+impl EventSink for RabbitMQClient {
+    async fn publish(&self, entry: &OutboxEntry) -> Result<(), TransportError> {
+        RabbitMQClient::publish(self, entry).await
+    }
+}
+
+#[async_trait]
+impl OrderSource for Arc<RabbitMQClient> {
+    async fn start(&self, handler: Arc<dyn OrderHandler>) -> Result<(), TransportError> {
+        let snapshots = SnapshotRequests {
+            handler: Arc::clone(&handler),
+            client: Arc::clone(self),
+        };
+        let deliveries = OrderDeliveries {
+            handler,
+            client: Arc::clone(self),
+        };
+        self.setup_consumer(deliveries, snapshots)
             .await
+            .map_err(|e| e.to_string().into())
+    }
+
+    async fn stop_consuming(&self) {
+        RabbitMQClient::stop_consuming(self).await
+    }
+}
+
+/// Hands order deliveries to the handler and settles them with the broker as it decides.
+#[derive(Clone)]
+struct OrderDeliveries {
+    handler: Arc<dyn OrderHandler>,
+    client: Arc<RabbitMQClient>,
+}
+
+impl OrderDeliveries {
+    async fn requeue(channel: &Channel, delivery_tag: u64) {
+        let args = BasicNackArguments::new(delivery_tag, false, true);
+        if let Err(e) = channel.basic_nack(args).await {
+            error!("Failed to requeue message: {}", e);
+        }
     }
 
-    async fn publish_order_update(
+    async fn ack(channel: &Channel, delivery_tag: u64) {
+        let args = BasicAckArguments::new(delivery_tag, false);
+        if let Err(e) = channel.basic_ack(args).await {
+            error!("Failed to acknowledge message: {}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncConsumer for OrderDeliveries {
+    async fn consume(
+        &mut self,
+        channel: &Channel,
+        deliver: Deliver,
+        properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        let delivery_tag = deliver.delivery_tag();
+        let message = InboundMessage {
+            routing_key: deliver.routing_key().to_string(),
+            envelope: Envelope::from_properties(&properties),
+            message_id: properties.message_id().cloned(),
+            correlation_id: properties.correlation_id().cloned(),
+            redelivered: deliver.redelivered(),
+            content,
+        };
+
+        match self.handler.handle(&message).await {
+            Disposition::Ack => Self::ack(channel, delivery_tag).await,
+            Disposition::Requeue => Self::requeue(channel, delivery_tag).await,
+            Disposition::DeadLetter(reason) => {
+                match self
+                    .client
+                    .dead_letter(&deliver, properties, message.content, &reason)
+                    .await
+                {
+                    Ok(()) => Self::ack(channel, delivery_tag).await,
+                    Err(e) => {
+                        error!("Failed to dead-letter message, requeueing it: {}", e);
+                        Self::requeue(channel, delivery_tag).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Answers order book snapshot requests (`{"stock_id": ...}`) and price snapshot requests
+/// (any body) on the queue named in their `reply_to`, with their correlation id.
+#[derive(Clone)]
+struct SnapshotRequests {
+    handler: Arc<dyn OrderHandler>,
+    client: Arc<RabbitMQClient>,
+}
+
+impl SnapshotRequests {
+    /// Message type, description for the logs and payload of the answer to a request.
+    async fn answer(
         &self,
-        order_type: &str,
-        payload: &impl serde::Serialize,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let routing_key = format!("order.{}", order_type);
-        let args = BasicPublishArguments::new("order_update_exchange", &routing_key)
-            .mandatory(true) // Ensure messages are routed
-            .finish();
+        routing_key: &str,
+        content: &[u8],
+    ) -> Option<(&'static str, String, Vec<u8>)> {
+        if routing_key.starts_with("price.") {
+            let snapshot = self.handler.price_snapshot().await;
+            let description = format!("price snapshot of {} stocks", snapshot.prices.len());
+            return match serde_json::to_vec(&snapshot) {
+                Ok(payload) => Some(("price.snapshot", description, payload)),
+                Err(e) => {
+                    error!("Failed to serialize price snapshot: {}", e);
+                    None
+                }
+            };
+        }
 
-        self.channel
-            .basic_publish(
-                BasicProperties::default()
-                    .with_delivery_mode(2) // Make message persistent
-                    .finish(),
-                serde_json::to_vec(payload)?,
-                args,
-            )
-            .await?;
+        let request: BookSnapshotRequest = match serde_json::from_slice(content) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Ignoring malformed book snapshot request: {}", e);
+                return None;
+            }
+        };
+        let snapshot = self.handler.book_snapshot(&request.stock_id).await;
+        let description = format!(
+            "book snapshot of {} at book_seq {}",
+            request.stock_id, snapshot.book_seq
+        );
+        match serde_json::to_vec(&snapshot) {
+            Ok(payload) => Some(("book.snapshot", description, payload)),
+            Err(e) => {
+                error!(
+                    "Failed to serialize book snapshot of {}: {}",
+                    request.stock_id, e
+                );
+                None
+            }
+        }
+    }
+}
 
-        Ok(())
+#[async_trait]
+impl AsyncConsumer for SnapshotRequests {
+    async fn consume(
+        &mut self,
+        _channel: &Channel,
+        deliver: Deliver,
+        properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        let Some(reply_to) = properties.reply_to() else {
+            warn!(
+                "Ignoring snapshot request without reply_to ({})",
+                deliver.routing_key()
+            );
+            return;
+        };
+        let Some((message_type, description, payload)) =
+            self.answer(deliver.routing_key(), &content).await
+        else {
+            return;
+        };
+        let now = now_millis();
+        let reply_properties = Envelope {
+            message_type: message_type.to_string(),
+            schema_version: SCHEMA_VERSION,
+            message_id: format!("{}-{}-{}", self.client.shard_id(), message_type, now),
+            correlation_id: properties.correlation_id().cloned(),
+            causation_id: properties.message_id().cloned(),
+            producer: PRODUCER.to_string(),
+            timestamp: now / 1000,
+        }
+        .to_properties();
+
+        // Replies go through the default exchange, which routes by queue name
+        debug!("Answering with {}", description);
+        if let Err(e) = self
+            .client
+            .publish_confirmed("", reply_to, reply_properties, payload, false)
+            .await
+        {
+            warn!("Failed to answer with {}: {}", description, e);
+        }
     }
 }
@@ -0,0 +1,189 @@
+use std::{
+    fs::{self, File},
+    io::{Error, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info, warn};
+
+use crate::state::StateSnapshot;
+
+/// Snapshot files are `snapshot-<seq>.json`, where the first line is the CRC32 of the rest
+/// of the file. A snapshot whose checksum does not match is treated as torn and skipped.
+fn snapshot_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("snapshot-{:020}.json", seq))
+}
+
+/// Snapshot files in `dir` as `(seq, path)`, newest first.
+fn list(dir: &Path) -> std::io::Result<Vec<(u64, PathBuf)>> {
+    let mut snapshots = Vec::new();
+    if !dir.exists() {
+        return Ok(snapshots);
+    }
+
+    for dir_entry in fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        let seq = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("snapshot-"))
+            .and_then(|name| name.strip_suffix(".json"))
+            .and_then(|seq| seq.parse::<u64>().ok());
+        if let Some(seq) = seq {
+            snapshots.push((seq, path));
+        }
+    }
+
+    snapshots.sort_by(|a, b| b.cmp(a));
+    Ok(snapshots)
+}
+
+/// Write the snapshot atomically (temp file, fsync, rename) so a crash never leaves a
+/// half-written file under the final name.
+pub fn write(dir: &Path, snapshot: &StateSnapshot) -> std::io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let body = serde_json::to_vec(snapshot)?;
+    let checksum = crc32fast::hash(&body);
+
+    let path = snapshot_path(dir, snapshot.last_seq);
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = File::create(&tmp_path)?;
+    writeln!(file, "{}", checksum)?;
+    file.write_all(&body)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+
+    debug!(
+        "Wrote snapshot {} ({} bytes, crc32={})",
+        path.display(),
+        body.len(),
+        checksum
+    );
+    Ok(path)
+}
+
+/// Read a single snapshot file, validating its checksum.
+pub fn read(path: &Path) -> std::io::Result<StateSnapshot> {
+    let contents = fs::read(path)?;
+    let newline = contents
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing checksum line"))?;
+    let (header, body) = (&contents[..newline], &contents[newline + 1..]);
+
+    let expected: u32 = std::str::from_utf8(header)
+        .ok()
+        .and_then(|header| header.trim().parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid checksum line"))?;
+    let actual = crc32fast::hash(body);
+    if actual != expected {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("checksum mismatch: expected {}, got {}", expected, actual),
+        ));
+    }
+
+    Ok(serde_json::from_slice(body)?)
+}
+
+/// Load the newest snapshot that passes validation, falling back to older ones.
+pub fn load_latest(dir: &Path) -> std::io::Result<Option<StateSnapshot>> {
+    for (_, path) in list(dir)? {
+        match read(&path) {
+            Ok(snapshot) => {
+                info!(
+                    "Loaded snapshot {} at sequence {}",
+                    path.display(),
+                    snapshot.last_seq
+                );
+                return Ok(Some(snapshot));
+            }
+            Err(e) => warn!("Ignoring invalid snapshot {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Keep the `retain` newest snapshots and return the sequence of the oldest one kept,
+/// which is how far back the journal still needs to go.
+pub fn prune(dir: &Path, retain: usize) -> std::io::Result<Option<u64>> {
+    let snapshots = list(dir)?;
+    let retain = retain.max(1);
+
+    for (_, path) in snapshots.iter().skip(retain) {
+        debug!("Pruning snapshot {}", path.display());
+        fs::remove_file(path)?;
+    }
+
+    let kept = &snapshots[..retain.min(snapshots.len())];
+    Ok(kept.last().map(|(seq, _)| *seq))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{state::AppState, test_dir::TestDir};
+
+    fn snapshot(last_seq: u64) -> StateSnapshot {
+        let mut state = AppState::new();
+        state.last_seq = last_seq;
+        state.to_snapshot()
+    }
+
+    fn seqs(dir: &Path) -> Vec<u64> {
+        list(dir).unwrap().into_iter().map(|(seq, _)| seq).collect()
+    }
+
+    #[test]
+    fn first_line_is_the_crc32_of_the_rest() {
+        let dir = TestDir::new();
+        let path = write(dir.path(), &snapshot(7)).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let (header, body) = contents.split_once('\n').unwrap();
+        assert_eq!(header, crc32fast::hash(body.as_bytes()).to_string());
+        assert_eq!(read(&path).unwrap().last_seq, 7);
+
+        // Any change to the body is caught
+        fs::write(&path, contents.replace("\"last_seq\":7", "\"last_seq\":8")).unwrap();
+        assert_eq!(read(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn falls_back_to_an_older_snapshot_when_the_newest_is_torn() {
+        let dir = TestDir::new();
+        write(dir.path(), &snapshot(1)).unwrap();
+        let newest = write(dir.path(), &snapshot(2)).unwrap();
+        let contents = fs::read(&newest).unwrap();
+        fs::write(&newest, &contents[..contents.len() / 2]).unwrap();
+
+        assert_eq!(load_latest(dir.path()).unwrap().unwrap().last_seq, 1);
+
+        fs::write(&newest, b"").unwrap();
+        assert_eq!(load_latest(dir.path()).unwrap().unwrap().last_seq, 1);
+    }
+
+    #[test]
+    fn no_valid_snapshot_loads_nothing() {
+        let dir = TestDir::new();
+        assert!(load_latest(&dir.path().join("missing")).unwrap().is_none());
+        let path = write(dir.path(), &snapshot(1)).unwrap();
+        fs::write(&path, b"123\n{}").unwrap();
+        assert!(load_latest(dir.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn prune_keeps_the_newest_and_reports_the_oldest_kept() {
+        let dir = TestDir::new();
+        assert_eq!(prune(dir.path(), 2).unwrap(), None);
+        for seq in [1, 5, 9] {
+            write(dir.path(), &snapshot(seq)).unwrap();
+        }
+
+        assert_eq!(prune(dir.path(), 2).unwrap(), Some(5));
+        assert_eq!(seqs(dir.path()), vec![9, 5]);
+        // At least one is always kept
+        assert_eq!(prune(dir.path(), 0).unwrap(), Some(9));
+        assert_eq!(seqs(dir.path()), vec![9]);
+    }
+}
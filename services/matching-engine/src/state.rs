@@ -1,12 +1,201 @@
-use crate::matching_pq::StockMatchingPriorityQueue;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    matching_pq::{SellOrder, StockMatchingPriorityQueue},
+    models::{StockPrice, TradingStatus},
+};
+
+/// How many recently applied orders are remembered to recognise redeliveries.
+const RECENT_ORDERS: usize = 10_000;
+
+/// The most recently applied orders, keyed by `OrderCommand::key`, each with the sequence
+/// number of the last event published up to and including that order.
+#[derive(Default)]
+pub struct RecentOrders {
+    event_seqs: HashMap<String, u64>,
+    keys: VecDeque<String>, // Oldest first
+}
+
+impl RecentOrders {
+    pub fn get(&self, key: &str) -> Option<u64> {
+        self.event_seqs.get(key).copied()
+    }
+
+    pub fn insert(&mut self, key: String, event_seq: u64) {
+        if self.event_seqs.insert(key.clone(), event_seq).is_none() {
+            self.keys.push_back(key);
+        }
+        while self.keys.len() > RECENT_ORDERS {
+            if let Some(oldest) = self.keys.pop_front() {
+                self.event_seqs.remove(&oldest);
+            }
+        }
+    }
+
+    fn entries(&self) -> Vec<(String, u64)> {
+        self.keys
+            .iter()
+            .map(|key| (key.clone(), self.event_seqs[key]))
+            .collect()
+    }
+
+    fn from_entries(entries: Vec<(String, u64)>) -> Self {
+        let mut recent = Self::default();
+        for (key, event_seq) in entries {
+            recent.insert(key, event_seq);
+        }
+        recent
+    }
+}
+
+/// Length of a trading session for `TradeStats::session_volume`, a UTC calendar day.
+const SESSION_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+/// The last trade of a stock and the volume traded in its session.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TradeStats {
+    pub last_price: f64,
+    pub last_quantity: u64,
+    pub last_timestamp: u64, // Unix milliseconds
+    session: u64,            // Days since the Unix epoch
+    session_volume: u64,
+}
+
+impl TradeStats {
+    pub fn record(&mut self, price: f64, quantity: u64, timestamp: u64) {
+        let session = timestamp / SESSION_MILLIS;
+        if session != self.session {
+            self.session = session;
+            self.session_volume = 0;
+        }
+        self.session_volume += quantity;
+        self.last_price = price;
+        self.last_quantity = quantity;
+        self.last_timestamp = timestamp;
+    }
+
+    /// Shares traded in the session `timestamp` falls in.
+    pub fn session_volume(&self, timestamp: u64) -> u64 {
+        if timestamp / SESSION_MILLIS == self.session {
+            self.session_volume
+        } else {
+            0
+        }
+    }
+}
+
+/// What a price message says about a stock, without its sequence numbers. A price is only
+/// published when this differs from the last one published.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Quote {
+    pub stock_name: Option<String>,
+    #[serde(default)]
+    pub trading_status: TradingStatus,
+    pub best_ask: Option<f64>,
+    pub best_ask_size: u64,
+    pub last_trade_price: Option<f64>,
+    pub last_trade_size: Option<u64>,
+    pub last_trade_time: Option<u64>,
+    pub session_volume: u64,
+}
+
+impl Quote {
+    pub fn of(price: &StockPrice) -> Self {
+        Self {
+            stock_name: price.stock_name.clone(),
+            trading_status: price.trading_status,
+            best_ask: price.best_ask,
+            best_ask_size: price.best_ask_size,
+            last_trade_price: price.last_trade_price,
+            last_trade_size: price.last_trade_size,
+            last_trade_time: price.last_trade_time,
+            session_volume: price.session_volume,
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct AppState {
     pub matching_pq: StockMatchingPriorityQueue,
+    pub shard_id: u32,
+    pub last_seq: u64,  // Sequence number of the last order applied to the book
+    pub event_seq: u64, // Sequence number of the last published event
+    pub last_timestamp: u64, // Timestamp of the last published event (Unix milliseconds)
+    pub price_seqs: BTreeMap<String, u64>, // Sequence number of the last price message per stock
+    pub price_times: BTreeMap<String, u64>, // Timestamp of the last price message per stock
+    pub book_seqs: BTreeMap<String, u64>, // Sequence number of the last book update per stock
+    pub recent_orders: RecentOrders,
+    pub trade_seq: u64,           // Sequence number of the last trade
+    pub trade_epoch: Option<u64>, // Process start (Unix milliseconds) if trade_seq is not persisted
+    pub trade_stats: BTreeMap<String, TradeStats>, // Per stock, only for stocks that traded
+    pub last_quotes: BTreeMap<String, Quote>, // Last quote published per stock
+}
+
+/// Serializable copy of the whole `AppState`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StateSnapshot {
+    pub last_seq: u64,
+    #[serde(default)]
+    pub event_seq: u64,
+    #[serde(default)]
+    pub last_timestamp: u64,
+    #[serde(default)]
+    pub price_seqs: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub price_times: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub book_seqs: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub recent_orders: Vec<(String, u64)>,
+    #[serde(default)]
+    pub trade_seq: u64,
+    #[serde(default)]
+    pub trade_stats: BTreeMap<String, TradeStats>,
+    #[serde(default)]
+    pub last_quotes: BTreeMap<String, Quote>,
+    pub stocks: BTreeMap<String, Vec<SellOrder>>,
 }
 
 impl AppState {
     pub fn new() -> Self {
         Self::default()
     }
+
+    pub fn to_snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            last_seq: self.last_seq,
+            event_seq: self.event_seq,
+            last_timestamp: self.last_timestamp,
+            price_seqs: self.price_seqs.clone(),
+            price_times: self.price_times.clone(),
+            book_seqs: self.book_seqs.clone(),
+            recent_orders: self.recent_orders.entries(),
+            trade_seq: self.trade_seq,
+            trade_stats: self.trade_stats.clone(),
+            last_quotes: self.last_quotes.clone(),
+            stocks: self.matching_pq.raw_queues(),
+        }
+    }
+
+    /// Rebuild the state from a snapshot. The shard id is configuration, not state,
+    /// so it is left for the caller to set. A restored trade counter needs no epoch.
+    pub fn from_snapshot(snapshot: StateSnapshot) -> Self {
+        Self {
+            matching_pq: StockMatchingPriorityQueue::from_raw_queues(snapshot.stocks),
+            shard_id: 0,
+            last_seq: snapshot.last_seq,
+            event_seq: snapshot.event_seq,
+            last_timestamp: snapshot.last_timestamp,
+            price_seqs: snapshot.price_seqs,
+            price_times: snapshot.price_times,
+            book_seqs: snapshot.book_seqs,
+            recent_orders: RecentOrders::from_entries(snapshot.recent_orders),
+            trade_seq: snapshot.trade_seq,
+            trade_epoch: None,
+            trade_stats: snapshot.trade_stats,
+            last_quotes: snapshot.last_quotes,
+        }
+    }
 }
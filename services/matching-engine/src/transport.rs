@@ -0,0 +1,62 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+
+use crate::{
+    envelope::Envelope,
+    models::{BookSnapshot, PriceSnapshot},
+    outbox::OutboxEntry,
+};
+
+pub type TransportError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An order as delivered by an `OrderSource`, independent of the transport.
+#[derive(Debug, Clone)]
+pub struct InboundMessage {
+    pub routing_key: String,
+    pub envelope: Option<Envelope>, // None for legacy orders published without one
+    pub message_id: Option<String>,
+    pub correlation_id: Option<String>,
+    pub redelivered: bool,
+    pub content: Vec<u8>,
+}
+
+/// What the source should do with a message once the handler is done with it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Disposition {
+    /// Handled, and every event it caused is confirmed.
+    Ack,
+    /// Not handled this time; deliver it again later.
+    Requeue,
+    /// Can never be handled; park it with the reason instead of dropping it.
+    DeadLetter(String),
+}
+
+#[async_trait]
+pub trait OrderHandler: Send + Sync + 'static {
+    async fn handle(&self, message: &InboundMessage) -> Disposition;
+
+    /// Answer a request for the current order book of a stock.
+    async fn book_snapshot(&self, stock_id: &str) -> BookSnapshot;
+
+    /// Answer a request for the current quote of every stock of this shard.
+    async fn price_snapshot(&self) -> PriceSnapshot;
+}
+
+/// Where orders come from.
+#[async_trait]
+pub trait OrderSource: Send + Sync {
+    /// Start delivering orders, and order book and price snapshot requests, to `handler` in
+    /// the background.
+    async fn start(&self, handler: Arc<dyn OrderHandler>) -> Result<(), TransportError>;
+
+    /// Stop delivering new orders. Orders that were delivered but not settled go back to
+    /// the source.
+    async fn stop_consuming(&self);
+}
+
+/// Where events go.
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    /// Publish one event, returning once the transport has confirmed it.
+    async fn publish(&self, entry: &OutboxEntry) -> Result<(), TransportError>;
+}
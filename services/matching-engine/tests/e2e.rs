@@ -0,0 +1,883 @@
+//! End-to-end tests of the engine running on the in-process bus: orders go in through an
+//! `OrderSource`, and every event that comes out of the `EventSink` is asserted exactly
+//! (except its wall-clock timestamp).
+
+use serde_json::{json, Value};
+use std::{
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
+    time::Duration,
+};
+use tokio::sync::RwLock;
+
+use matching_engine::{
+    conflation::ConflatingSink,
+    consumers::OrderConsumer,
+    engine::{self, OrderCommand},
+    memory_bus::{MemoryBus, PublishedEvent},
+    outbox::Outbox,
+    state::AppState,
+    transport::{Disposition, EventSink, InboundMessage, OrderHandler},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Engine {
+    bus: Arc<MemoryBus>,
+    state: Arc<RwLock<AppState>>,
+}
+
+async fn start_engine() -> Engine {
+    let bus = Arc::new(MemoryBus::new());
+    let state = Arc::new(RwLock::new(AppState::new()));
+    let consumer = OrderConsumer::new(
+        Arc::clone(&state),
+        Arc::clone(&bus) as Arc<dyn EventSink>,
+        None,
+        Arc::new(Outbox::in_memory()),
+    )
+    .with_confirm_wait(Duration::from_millis(50));
+    consumer.setup(&bus).await.unwrap();
+    Engine { bus, state }
+}
+
+/// Events as `(routing_key, correlation_id, payload)` with the wall-clock times removed.
+fn events(bus: &MemoryBus) -> Vec<(String, Option<String>, Value)> {
+    bus.take_events()
+        .into_iter()
+        .map(|event: PublishedEvent| {
+            let mut payload = event.payload;
+            payload.as_object_mut().unwrap().remove("timestamp");
+            payload.as_object_mut().unwrap().remove("last_trade_time");
+            (event.routing_key, event.correlation_id, payload)
+        })
+        .collect()
+}
+
+fn limit_sell(stock_tx_id: &str, user_name: &str, quantity: u64, price: f64) -> Value {
+    json!({
+        "stock_id": "s1",
+        "stock_name": "Google",
+        "quantity": quantity,
+        "price": price,
+        "stock_tx_id": stock_tx_id,
+        "user_name": user_name,
+    })
+}
+
+fn market_buy(stock_tx_id: &str, user_name: &str, quantity: u64, budget: f64) -> Value {
+    json!({
+        "stock_id": "s1",
+        "quantity": quantity,
+        "stock_tx_id": stock_tx_id,
+        "budget": budget,
+        "user_name": user_name,
+    })
+}
+
+/// A quote of `s1` with its best ask as `(price, size)`, for a stock that never traded.
+fn price(stock_seq: u64, seq: u64, best_ask: Option<(f64, u64)>) -> Value {
+    json!({
+        "stock_id": "s1",
+        "stock_name": "Google",
+        "current_price": best_ask.map(|(price, _)| price),
+        "trading_status": if best_ask.is_some() { "active" } else { "active_no_quotes" },
+        "stock_seq": stock_seq,
+        "best_ask": best_ask.map(|(price, _)| price),
+        "best_ask_size": best_ask.map_or(0, |(_, size)| size),
+        "best_bid": null,
+        "best_bid_size": 0,
+        "last_trade_price": null,
+        "last_trade_size": null,
+        "session_volume": 0,
+        "shard_id": 0,
+        "seq": seq,
+    })
+}
+
+/// `quote` after a last trade of `size` shares at `price`, with the volume of the session.
+fn traded(mut quote: Value, price: f64, size: u64, session_volume: u64) -> Value {
+    quote["last_trade_price"] = json!(price);
+    quote["last_trade_size"] = json!(size);
+    quote["session_volume"] = json!(session_volume);
+    quote
+}
+
+/// A book update of `s1` with its changed ask levels as `(price, new size)`, and the book
+/// after it in the text form its checksum is taken of.
+fn book(book_seq: u64, seq: u64, changes: &[(f64, u64)], checksummed: &str) -> Value {
+    json!({
+        "stock_id": "s1",
+        "book_seq": book_seq,
+        "changes": changes
+            .iter()
+            .map(|(price, size)| json!({ "side": "ask", "price": price, "size": size }))
+            .collect::<Vec<_>>(),
+        "checksum": crc32fast::hash(checksummed.as_bytes()),
+        "shard_id": 0,
+        "seq": seq,
+    })
+}
+
+#[tokio::test]
+async fn limit_sell_publishes_the_new_price() {
+    let engine = start_engine().await;
+    engine.bus.send_order(
+        "order.limit_sell",
+        "m1",
+        limit_sell("tx1", "alice", 10, 5.0),
+    );
+    assert!(engine.bus.wait_idle(TIMEOUT).await);
+
+    assert_eq!(
+        events(&engine.bus),
+        vec![
+            (
+                "stock.price.s1".to_string(),
+                Some("m1".to_string()),
+                price(1, 1, Some((5.0, 10))),
+            ),
+            (
+                "book.update.s1".to_string(),
+                Some("m1".to_string()),
+                book(1, 2, &[(5.0, 10)], "5:10|"),
+            ),
+        ]
+    );
+    assert_eq!(engine.bus.dispositions(), vec![Disposition::Ack]);
+}
+
+#[tokio::test]
+async fn orders_behind_the_best_ask_publish_no_price() {
+    let engine = start_engine().await;
+    let bus = &engine.bus;
+    bus.send_order(
+        "order.limit_sell",
+        "m1",
+        limit_sell("tx1", "alice", 10, 5.0),
+    );
+    bus.send_order("order.limit_sell", "m2", limit_sell("tx2", "bob", 10, 6.0));
+    bus.send_order(
+        "order.limit_sell_cancellation",
+        "m3",
+        json!({ "stock_id": "s1", "quantity": 10, "price": 6.0, "stock_tx_id": "tx2" }),
+    );
+    assert!(bus.wait_idle(TIMEOUT).await);
+
+    // Only the book changed with the second sell and its cancellation
+    let routing_keys: Vec<(String, Option<String>)> = events(bus)
+        .into_iter()
+        .map(|(routing_key, correlation_id, _)| (routing_key, correlation_id))
+        .collect();
+    let event = |routing_key: &str, message_id: &str| {
+        (routing_key.to_string(), Some(message_id.to_string()))
+    };
+    assert_eq!(
+        routing_keys,
+        vec![
+            event("stock.price.s1", "m1"),
+            event("book.update.s1", "m1"),
+            event("book.update.s1", "m2"),
+            event("order.cancelled", "m3"),
+            event("book.update.s1", "m3"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn market_buy_fills_against_the_cheapest_sell_orders() {
+    let engine = start_engine().await;
+    let bus = &engine.bus;
+    bus.send_order(
+        "order.limit_sell",
+        "m1",
+        limit_sell("tx1", "alice", 10, 5.0),
+    );
+    bus.send_order("order.limit_sell", "m2", limit_sell("tx2", "bob", 10, 6.0));
+    assert!(bus.wait_idle(TIMEOUT).await);
+    events(bus);
+
+    bus.send_order(
+        "order.market_buy",
+        "m3",
+        market_buy("tx3", "carol", 15, 100.0),
+    );
+    assert!(bus.wait_idle(TIMEOUT).await);
+
+    let m3 = Some("m3".to_string());
+    assert_eq!(
+        events(bus),
+        vec![
+            (
+                "order.buy_completed".to_string(),
+                m3.clone(),
+                json!({
+                    "success": true,
+                    "data": {
+                        "stock_id": "s1",
+                        "stock_tx_id": "tx3",
+                        "quantity": 15,
+                        "price_total": 80.0,
+                        "average_price": 80.0 / 15.0,
+                        "trade_ids": ["trade-0-1", "trade-0-2"],
+                        "fills": [
+                            {
+                                "trade_id": "trade-0-1",
+                                "sell_stock_tx_id": "tx1",
+                                "quantity": 10,
+                                "price": 5.0,
+                            },
+                            {
+                                "trade_id": "trade-0-2",
+                                "sell_stock_tx_id": "tx2",
+                                "quantity": 5,
+                                "price": 6.0,
+                            },
+                        ],
+                    },
+                    "shard_id": 0,
+                    "seq": 4,
+                }),
+            ),
+            (
+                "order.sale_update".to_string(),
+                m3.clone(),
+                json!({
+                    "stock_id": "s1",
+                    "sold_quantity": 10,
+                    "remaining_quantity": 0,
+                    "price": 5.0,
+                    "stock_tx_id": "tx1",
+                    "user_name": "alice",
+                    "shard_id": 0,
+                    "seq": 5,
+                }),
+            ),
+            (
+                "order.sale_update".to_string(),
+                m3.clone(),
+                json!({
+                    "stock_id": "s1",
+                    "sold_quantity": 5,
+                    "remaining_quantity": 5,
+                    "price": 6.0,
+                    "stock_tx_id": "tx2",
+                    "user_name": "bob",
+                    "shard_id": 0,
+                    "seq": 6,
+                }),
+            ),
+            (
+                "order.trade".to_string(),
+                m3.clone(),
+                json!({
+                    "trade_id": "trade-0-1",
+                    "stock_id": "s1",
+                    "buy_stock_tx_id": "tx3",
+                    "sell_stock_tx_id": "tx1",
+                    "buyer_user_name": "carol",
+                    "seller_user_name": "alice",
+                    "quantity": 10,
+                    "price": 5.0,
+                    "shard_id": 0,
+                    "seq": 7,
+                }),
+            ),
+            (
+                "order.trade".to_string(),
+                m3.clone(),
+                json!({
+                    "trade_id": "trade-0-2",
+                    "stock_id": "s1",
+                    "buy_stock_tx_id": "tx3",
+                    "sell_stock_tx_id": "tx2",
+                    "buyer_user_name": "carol",
+                    "seller_user_name": "bob",
+                    "quantity": 5,
+                    "price": 6.0,
+                    "shard_id": 0,
+                    "seq": 8,
+                }),
+            ),
+            (
+                "stock.price.s1".to_string(),
+                m3.clone(),
+                traded(price(2, 9, Some((6.0, 5))), 6.0, 5, 15),
+            ),
+            (
+                "book.update.s1".to_string(),
+                m3,
+                book(3, 10, &[(5.0, 0), (6.0, 5)], "6:5|"),
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn trade_ids_carry_the_epoch_when_the_counter_is_not_persisted() {
+    let engine = start_engine().await;
+    engine.state.write().await.trade_epoch = Some(42);
+    let bus = &engine.bus;
+    bus.send_order(
+        "order.limit_sell",
+        "m1",
+        limit_sell("tx1", "alice", 10, 5.0),
+    );
+    bus.send_order("order.market_buy", "m2", market_buy("tx2", "bob", 5, 100.0));
+    assert!(bus.wait_idle(TIMEOUT).await);
+
+    let trade_ids: Vec<Value> = events(bus)
+        .into_iter()
+        .filter(|(routing_key, _, _)| routing_key == "order.trade")
+        .map(|(_, _, payload)| payload["trade_id"].clone())
+        .collect();
+    assert_eq!(trade_ids, vec![json!("trade-0-42-1")]);
+}
+
+#[tokio::test]
+async fn market_buy_without_enough_shares_fails() {
+    let engine = start_engine().await;
+    let bus = &engine.bus;
+    bus.send_order(
+        "order.limit_sell",
+        "m1",
+        limit_sell("tx1", "alice", 10, 5.0),
+    );
+    bus.send_order(
+        "order.market_buy",
+        "m2",
+        market_buy("tx2", "bob", 11, 100.0),
+    );
+    assert!(bus.wait_idle(TIMEOUT).await);
+
+    let events = events(bus);
+    assert_eq!(
+        events[2],
+        (
+            "order.buy_completed".to_string(),
+            Some("m2".to_string()),
+            json!({
+                "success": false,
+                "data": {
+                    "stock_id": "s1",
+                    "stock_tx_id": "tx2",
+                    "quantity": null,
+                    "price_total": null,
+                    "average_price": null,
+                    "trade_ids": [],
+                    "fills": [],
+                },
+                "shard_id": 0,
+                "seq": 3,
+            }),
+        )
+    );
+    assert_eq!(events.len(), 3);
+}
+
+#[tokio::test]
+async fn cancelling_the_last_sell_order_clears_the_price() {
+    let engine = start_engine().await;
+    let bus = &engine.bus;
+    bus.send_order(
+        "order.limit_sell",
+        "m1",
+        limit_sell("tx1", "alice", 10, 5.0),
+    );
+    assert!(bus.wait_idle(TIMEOUT).await);
+    events(bus);
+
+    bus.send_order(
+        "order.limit_sell_cancellation",
+        "m2",
+        json!({ "stock_id": "s1", "quantity": 10, "price": 5.0, "stock_tx_id": "tx1" }),
+    );
+    bus.send_order(
+        "order.limit_sell_cancellation",
+        "m3",
+        json!({ "stock_id": "s1", "quantity": 10, "price": 5.0, "stock_tx_id": "tx9" }),
+    );
+    assert!(bus.wait_idle(TIMEOUT).await);
+
+    let m2 = Some("m2".to_string());
+    assert_eq!(
+        events(bus),
+        vec![
+            (
+                "order.cancelled".to_string(),
+                m2.clone(),
+                json!({
+                    "success": true,
+                    "data": {
+                        "stock_id": "s1",
+                        "stock_tx_id": "tx1",
+                        "partially_sold": false,
+                        "ori_quantity": 10,
+                        "cur_quantity": 10,
+                        "sold_quantity": 0,
+                        "price": 5.0,
+                    },
+                    "shard_id": 0,
+                    "seq": 3,
+                }),
+            ),
+            ("stock.price.s1".to_string(), m2.clone(), price(2, 4, None)),
+            (
+                "book.update.s1".to_string(),
+                m2,
+                book(2, 5, &[(5.0, 0)], "|")
+            ),
+            (
+                "order.cancelled".to_string(),
+                Some("m3".to_string()),
+                json!({ "success": false, "data": null, "shard_id": 0, "seq": 6 }),
+            ),
+        ]
+    );
+    let state = engine.state.read().await;
+    assert!(state.matching_pq.get_all_orders("s1").is_empty());
+}
+
+#[tokio::test]
+async fn cancellation_reports_what_was_sold() {
+    let engine = start_engine().await;
+    let bus = &engine.bus;
+    bus.send_order(
+        "order.limit_sell",
+        "m1",
+        limit_sell("tx1", "alice", 10, 5.0),
+    );
+    bus.send_order("order.market_buy", "m2", market_buy("tx2", "bob", 4, 100.0));
+    assert!(bus.wait_idle(TIMEOUT).await);
+    events(bus);
+
+    bus.send_order(
+        "order.limit_sell_cancellation",
+        "m3",
+        json!({ "stock_id": "s1", "quantity": 10, "price": 5.0, "stock_tx_id": "tx1" }),
+    );
+    assert!(bus.wait_idle(TIMEOUT).await);
+
+    let m3 = Some("m3".to_string());
+    assert_eq!(
+        events(bus),
+        vec![
+            (
+                "order.cancelled".to_string(),
+                m3.clone(),
+                json!({
+                    "success": true,
+                    "data": {
+                        "stock_id": "s1",
+                        "stock_tx_id": "tx1",
+                        "partially_sold": true,
+                        "ori_quantity": 10,
+                        "cur_quantity": 6,
+                        "sold_quantity": 4,
+                        "price": 5.0,
+                    },
+                    "shard_id": 0,
+                    "seq": 8,
+                }),
+            ),
+            // The buy left the order partially filled, so this is the third quote
+            (
+                "stock.price.s1".to_string(),
+                m3.clone(),
+                traded(price(3, 9, None), 5.0, 4, 4),
+            ),
+            (
+                "book.update.s1".to_string(),
+                m3,
+                book(3, 10, &[(5.0, 0)], "|")
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn unparseable_orders_are_dead_lettered() {
+    let engine = start_engine().await;
+    engine
+        .bus
+        .send_order("order.limit_sell", "m1", json!({ "stock_id": "s1" }));
+    engine.bus.send_order(
+        "order.short_sell",
+        "m2",
+        limit_sell("tx1", "alice", 10, 5.0),
+    );
+    assert!(engine.bus.wait_idle(TIMEOUT).await);
+
+    let dead_letters = engine.bus.dead_letters();
+    assert_eq!(dead_letters.len(), 2);
+    assert_eq!(dead_letters[0].routing_key, "order.limit_sell");
+    assert!(dead_letters[0].reason.contains("limit_sell"));
+    assert_eq!(dead_letters[1].routing_key, "order.short_sell");
+    assert!(dead_letters[1].reason.contains("short_sell"));
+    assert!(events(&engine.bus).is_empty());
+}
+
+#[tokio::test]
+async fn legacy_orders_take_their_type_from_the_routing_key() {
+    let engine = start_engine().await;
+    engine.bus.send(InboundMessage {
+        routing_key: "order.limit_sell".to_string(),
+        envelope: None,
+        message_id: None,
+        correlation_id: None,
+        redelivered: false,
+        content: limit_sell("tx1", "alice", 10, 5.0).to_string().into_bytes(),
+    });
+    assert!(engine.bus.wait_idle(TIMEOUT).await);
+
+    // Correlated by the order's stock_tx_id
+    assert_eq!(
+        events(&engine.bus),
+        vec![
+            (
+                "stock.price.s1".to_string(),
+                Some("tx1".to_string()),
+                price(1, 1, Some((5.0, 10))),
+            ),
+            (
+                "book.update.s1".to_string(),
+                Some("tx1".to_string()),
+                book(1, 2, &[(5.0, 10)], "5:10|"),
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn redelivered_orders_are_not_applied_twice() {
+    let engine = start_engine().await;
+    let order = limit_sell("tx1", "alice", 10, 5.0);
+    engine
+        .bus
+        .send_order("order.limit_sell", "m1", order.clone());
+    engine.bus.send(InboundMessage {
+        routing_key: "order.limit_sell".to_string(),
+        envelope: None,
+        message_id: Some("m1".to_string()),
+        correlation_id: Some("m1".to_string()),
+        redelivered: true,
+        content: order.to_string().into_bytes(),
+    });
+    assert!(engine.bus.wait_idle(TIMEOUT).await);
+
+    assert_eq!(events(&engine.bus).len(), 2);
+    assert_eq!(
+        engine.bus.dispositions(),
+        vec![Disposition::Ack, Disposition::Ack]
+    );
+    let state = engine.state.read().await;
+    assert_eq!(state.matching_pq.get_all_orders("s1").len(), 1);
+}
+
+#[test]
+fn redelivered_orders_are_skipped_on_replay() {
+    // Journal replay and me-replay apply orders without the consumer in between
+    let mut state = AppState::new();
+    let content = limit_sell("tx1", "alice", 10, 5.0).to_string();
+    let command = || OrderCommand::parse("limit_sell", content.as_bytes()).unwrap();
+
+    assert_eq!(engine::apply(&mut state, command(), 1000).len(), 2);
+    assert!(engine::apply(&mut state, command(), 2000).is_empty());
+    assert_eq!(state.event_seq, 2);
+    assert_eq!(state.matching_pq.get_all_orders("s1").len(), 1);
+}
+
+#[tokio::test]
+async fn orders_are_requeued_until_their_events_are_published() {
+    let engine = start_engine().await;
+    engine.bus.fail_publishes(true);
+    engine.bus.send_order(
+        "order.limit_sell",
+        "m1",
+        limit_sell("tx1", "alice", 10, 5.0),
+    );
+    assert!(engine.bus.wait_dispositions(1, TIMEOUT).await);
+    assert_eq!(engine.bus.dispositions()[0], Disposition::Requeue);
+    assert!(engine.bus.events().is_empty());
+
+    engine.bus.fail_publishes(false);
+    assert!(engine.bus.wait_idle(TIMEOUT).await);
+
+    // Published once, and the order is on the book once
+    assert_eq!(
+        events(&engine.bus),
+        vec![
+            (
+                "stock.price.s1".to_string(),
+                Some("m1".to_string()),
+                price(1, 1, Some((5.0, 10))),
+            ),
+            (
+                "book.update.s1".to_string(),
+                Some("m1".to_string()),
+                book(1, 2, &[(5.0, 10)], "5:10|"),
+            ),
+        ]
+    );
+    assert_eq!(engine.bus.dispositions().last(), Some(&Disposition::Ack));
+    let state = engine.state.read().await;
+    assert_eq!(state.matching_pq.get_all_orders("s1").len(), 1);
+}
+
+#[tokio::test]
+async fn book_updates_keep_a_replica_in_line_with_snapshots() {
+    let engine = start_engine().await;
+    let bus = &engine.bus;
+    let snapshot = bus.book_snapshot("s1").await.unwrap();
+    assert_eq!(snapshot.book_seq, 0);
+    assert!(snapshot.asks.is_empty());
+
+    bus.send_order(
+        "order.limit_sell",
+        "m1",
+        limit_sell("tx1", "alice", 10, 5.0),
+    );
+    bus.send_order("order.limit_sell", "m2", limit_sell("tx2", "bob", 5, 5.0));
+    bus.send_order(
+        "order.limit_sell",
+        "m3",
+        limit_sell("tx3", "carol", 10, 6.0),
+    );
+    bus.send_order("order.limit_sell", "m4", limit_sell("tx4", "dave", 3, 7.0));
+    bus.send_order(
+        "order.market_buy",
+        "m5",
+        market_buy("tx5", "erin", 12, 100.0),
+    );
+    bus.send_order(
+        "order.limit_sell_cancellation",
+        "m6",
+        json!({ "stock_id": "s1", "quantity": 3, "price": 7.0, "stock_tx_id": "tx4" }),
+    );
+    assert!(bus.wait_idle(TIMEOUT).await);
+
+    // Apply every update in sequence on top of the empty snapshot
+    let mut replica: Vec<(f64, u64)> = Vec::new();
+    let mut book_seq = snapshot.book_seq;
+    for (routing_key, _, update) in events(bus) {
+        if routing_key != "book.update.s1" {
+            continue;
+        }
+        assert_eq!(update["book_seq"], book_seq + 1);
+        book_seq += 1;
+        for change in update["changes"].as_array().unwrap() {
+            let price = change["price"].as_f64().unwrap();
+            let size = change["size"].as_u64().unwrap();
+            replica.retain(|(level, _)| *level != price);
+            if size > 0 {
+                replica.push((price, size));
+            }
+        }
+        replica.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let checksummed: Vec<String> = replica
+            .iter()
+            .map(|(price, size)| format!("{}:{}", price, size))
+            .collect();
+        let checksum = crc32fast::hash(format!("{}|", checksummed.join(",")).as_bytes());
+        assert_eq!(update["checksum"], checksum);
+    }
+
+    let snapshot = bus.book_snapshot("s1").await.unwrap();
+    assert_eq!(snapshot.book_seq, book_seq);
+    assert_eq!(snapshot.checksum, crc32fast::hash(b"5:3,6:10|"));
+    assert_eq!(book_seq, 6);
+    let asks: Vec<(f64, u64)> = snapshot
+        .asks
+        .iter()
+        .map(|level| (level.price, level.size))
+        .collect();
+    assert_eq!(asks, vec![(5.0, 3), (6.0, 10)]);
+    assert_eq!(replica, asks);
+}
+
+#[tokio::test]
+async fn price_snapshot_has_the_last_published_quotes() {
+    let engine = start_engine().await;
+    let bus = &engine.bus;
+    assert!(bus.price_snapshot().await.unwrap().prices.is_empty());
+
+    bus.send_order(
+        "order.limit_sell",
+        "m1",
+        limit_sell("tx1", "alice", 10, 5.0),
+    );
+    bus.send_order("order.market_buy", "m2", market_buy("tx2", "bob", 4, 100.0));
+    assert!(bus.wait_idle(TIMEOUT).await);
+    let events = bus.take_events();
+    let last_price = events
+        .iter()
+        .rev()
+        .find(|event| event.routing_key == "stock.price.s1")
+        .unwrap();
+    let price_time = last_price.payload["timestamp"].clone();
+    let mut last_price = last_price.payload.clone();
+    last_price.as_object_mut().unwrap().remove("timestamp");
+    last_price
+        .as_object_mut()
+        .unwrap()
+        .remove("last_trade_time");
+
+    // A later order of another stock moves the shard's clock, but not the time of s1's price
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let mut other = limit_sell("tx3", "carol", 1, 9.0);
+    other["stock_id"] = json!("s2");
+    bus.send_order("order.limit_sell", "m3", other);
+    assert!(bus.wait_idle(TIMEOUT).await);
+    let other_events = bus.take_events();
+    // Stamped with the shard's last event, which comes after the price
+    last_price["seq"] = other_events.last().unwrap().payload["seq"].clone();
+
+    let snapshot = bus.price_snapshot().await.unwrap();
+    assert_eq!(snapshot.shard_id, 0);
+    let mut prices = serde_json::to_value(&snapshot.prices).unwrap();
+    assert_eq!(prices[0]["timestamp"], price_time);
+    assert_ne!(prices[1]["timestamp"], price_time);
+    for price in prices.as_array_mut().unwrap() {
+        price.as_object_mut().unwrap().remove("timestamp");
+        price.as_object_mut().unwrap().remove("last_trade_time");
+    }
+    assert_eq!(prices[0], last_price);
+    assert_eq!(prices[0], traded(price(2, 9, Some((5.0, 6))), 5.0, 4, 4));
+    assert_eq!(prices[1]["stock_id"], "s2");
+}
+
+fn inbound(message_type: &str, message_id: &str, payload: Value) -> InboundMessage {
+    InboundMessage {
+        routing_key: message_type.to_string(),
+        envelope: None,
+        message_id: Some(message_id.to_string()),
+        correlation_id: Some(message_id.to_string()),
+        redelivered: false,
+        content: payload.to_string().into_bytes(),
+    }
+}
+
+/// Start handling `message` and run it until it has to wait, which is for the state lock
+/// while the test holds it. The returned future carries on from there.
+async fn handle_until_blocked(
+    consumer: &Arc<OrderConsumer>,
+    message: InboundMessage,
+) -> Pin<Box<dyn Future<Output = Disposition> + Send>> {
+    let consumer = Arc::clone(consumer);
+    let mut handling = Box::pin(async move { consumer.handle(&message).await });
+    // Unconstrained, so the task budget cannot make it yield before it gets there
+    tokio::task::unconstrained(poll_fn(|cx| {
+        assert!(handling.as_mut().poll(cx).is_pending());
+        Poll::Ready(())
+    }))
+    .await;
+    handling
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn cancellations_overtake_queued_market_buys() {
+    let bus = Arc::new(MemoryBus::new());
+    let state = Arc::new(RwLock::new(AppState::new()));
+    let consumer = Arc::new(OrderConsumer::new(
+        Arc::clone(&state),
+        Arc::clone(&bus) as Arc<dyn EventSink>,
+        None,
+        Arc::new(Outbox::in_memory()),
+    ));
+    let sell = inbound(
+        "order.limit_sell",
+        "m0",
+        limit_sell("tx0", "alice", 1000, 5.0),
+    );
+    assert_eq!(consumer.handle(&sell).await, Disposition::Ack);
+    bus.take_events();
+
+    // Hold the book while a backlog of market buys queues up behind it, then cancel. Each
+    // order is run until it waits for the book before the next one starts, so they queue in
+    // exactly this order.
+    let book = state.write().await;
+    let mut handles = Vec::new();
+    for i in 1..=50 {
+        let buy = inbound(
+            "order.market_buy",
+            &format!("m{}", i),
+            market_buy(&format!("tx{}", i), "bob", 10, 100.0),
+        );
+        handles.push(tokio::spawn(handle_until_blocked(&consumer, buy).await));
+    }
+    let cancel = inbound(
+        "order.limit_sell_cancellation",
+        "cancel",
+        json!({ "stock_id": "s1", "quantity": 1000, "price": 5.0, "stock_tx_id": "tx0" }),
+    );
+    let cancel_handle = tokio::spawn(handle_until_blocked(&consumer, cancel).await);
+    drop(book);
+
+    assert_eq!(cancel_handle.await.unwrap(), Disposition::Ack);
+    for handle in handles {
+        assert_eq!(handle.await.unwrap(), Disposition::Ack);
+    }
+
+    // The cancellation was applied before any of the buys, so nothing had been sold
+    let events = events(&bus);
+    assert_eq!(events[0].0, "order.cancelled");
+    assert_eq!(events[0].2["data"]["sold_quantity"], 0);
+    assert_eq!(
+        events
+            .iter()
+            .filter(|(routing_key, _, payload)| {
+                routing_key == "order.buy_completed" && payload["success"] == false
+            })
+            .count(),
+        50
+    );
+}
+
+#[tokio::test]
+async fn price_bursts_are_conflated_to_the_latest_price() {
+    let bus = Arc::new(MemoryBus::new());
+    let sink = ConflatingSink::new(
+        Arc::clone(&bus) as Arc<dyn EventSink>,
+        Duration::from_millis(200),
+    );
+    let consumer = OrderConsumer::new(
+        Arc::new(RwLock::new(AppState::new())),
+        Arc::new(sink),
+        None,
+        Arc::new(Outbox::in_memory()),
+    );
+    consumer.setup(&bus).await.unwrap();
+
+    for (i, price) in [7.0, 6.0, 5.0].into_iter().enumerate() {
+        let tx = format!("tx{}", i + 1);
+        bus.send_order(
+            "order.limit_sell",
+            &format!("m{}", i + 1),
+            limit_sell(&tx, "alice", 10, price),
+        );
+    }
+    assert!(bus.wait_idle(TIMEOUT).await);
+
+    // The first price goes out at once, the rest is held back; book updates are not
+    let prices = |events: Vec<(String, Option<String>, Value)>| {
+        events
+            .into_iter()
+            .filter(|(routing_key, _, _)| routing_key == "stock.price.s1")
+            .map(|(_, _, payload)| payload)
+            .collect::<Vec<_>>()
+    };
+    let sent = events(&bus);
+    assert_eq!(
+        sent.iter()
+            .filter(|(routing_key, _, _)| routing_key == "book.update.s1")
+            .count(),
+        3
+    );
+    assert_eq!(prices(sent), vec![price(1, 1, Some((7.0, 10)))]);
+
+    // When the window ends only the latest of them is sent
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(prices(events(&bus)), vec![price(3, 5, Some((5.0, 10)))]);
+}
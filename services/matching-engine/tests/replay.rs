@@ -0,0 +1,127 @@
+//! Runs the `me-replay` binary over a journal, with and without a starting snapshot.
+
+use serde_json::{json, Value};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+use matching_engine::{snapshot, state::StateSnapshot};
+
+fn entry(seq: u64, command_type: &str, body: Value) -> Value {
+    json!({
+        "seq": seq,
+        "received_at": 1000 * seq,
+        "command": { "type": command_type, "body": body },
+    })
+}
+
+fn limit_sell(seq: u64, stock_tx_id: &str, quantity: u64, price: f64) -> Value {
+    let body = json!({
+        "stock_id": "s1",
+        "stock_name": "Google",
+        "quantity": quantity,
+        "price": price,
+        "stock_tx_id": stock_tx_id,
+        "user_name": "alice",
+    });
+    entry(seq, "limit_sell", body)
+}
+
+fn market_buy(seq: u64, stock_tx_id: &str, quantity: u64) -> Value {
+    let body = json!({
+        "stock_id": "s1",
+        "quantity": quantity,
+        "stock_tx_id": stock_tx_id,
+        "budget": 1000.0,
+        "user_name": "bob",
+    });
+    entry(seq, "market_buy", body)
+}
+
+fn write_jsonl(path: &Path, entries: &[Value]) {
+    let lines: Vec<String> = entries.iter().map(Value::to_string).collect();
+    fs::write(path, lines.join("\n") + "\n").unwrap();
+}
+
+/// Replay `input` and return the event output and final state, both as written.
+fn replay(dir: &Path, name: &str, input: &Path, snapshot: Option<&Path>) -> (String, String) {
+    let events = dir.join(format!("{}-events.jsonl", name));
+    let state = dir.join(format!("{}-state.json", name));
+    let mut command = Command::new(env!("CARGO_BIN_EXE_me-replay"));
+    command
+        .arg(input)
+        .arg("--output")
+        .arg(&events)
+        .arg("--state-out")
+        .arg(&state);
+    if let Some(snapshot) = snapshot {
+        command.arg("--snapshot").arg(snapshot);
+    }
+    let output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    (
+        fs::read_to_string(events).unwrap(),
+        fs::read_to_string(state).unwrap(),
+    )
+}
+
+fn input_seq(event: &str) -> u64 {
+    serde_json::from_str::<Value>(event).unwrap()["input_seq"]
+        .as_u64()
+        .unwrap()
+}
+
+#[test]
+fn replaying_from_a_snapshot_matches_replaying_everything() {
+    let dir: PathBuf = std::env::temp_dir().join(format!("me-replay-test-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let journal = vec![
+        limit_sell(1, "tx1", 10, 5.0),
+        limit_sell(2, "tx2", 10, 6.0),
+        limit_sell(3, "tx3", 5, 5.5),
+        market_buy(4, "tx4", 12),
+        // After the snapshot
+        limit_sell(5, "tx5", 10, 5.0),
+        market_buy(6, "tx6", 8),
+        entry(
+            7,
+            "limit_sell_cancellation",
+            json!({ "stock_id": "s1", "quantity": 10, "price": 6.0, "stock_tx_id": "tx2" }),
+        ),
+        // Redelivered from before the snapshot, so not applied again
+        market_buy(8, "tx4", 12),
+        market_buy(9, "tx9", 3),
+    ];
+    let journal_path = dir.join("journal.jsonl");
+    write_jsonl(&journal_path, &journal);
+    let head_path = dir.join("head.jsonl");
+    write_jsonl(&head_path, &journal[..4]);
+
+    // A snapshot of the state after the first four entries
+    let (_, head_state) = replay(&dir, "head", &head_path, None);
+    let head_state: StateSnapshot = serde_json::from_str(&head_state).unwrap();
+    assert_eq!(head_state.last_seq, 4);
+    let snapshot_path = snapshot::write(&dir.join("snapshots"), &head_state).unwrap();
+
+    let (events, state) = replay(&dir, "full", &journal_path, None);
+    let (tail_events, tail_state) = replay(&dir, "tail", &journal_path, Some(&snapshot_path));
+
+    assert_eq!(tail_state, state);
+    // The events of the entries the snapshot covers are not published again
+    let expected: Vec<&str> = events
+        .lines()
+        .filter(|event| input_seq(event) > 4)
+        .collect();
+    assert!(!expected.is_empty());
+    assert_eq!(tail_events.lines().collect::<Vec<_>>(), expected);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
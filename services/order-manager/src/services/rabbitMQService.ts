@@ -2,6 +2,9 @@ import amqp from "amqplib";
 
 const ORDER_EXCHANGE = "order_exchange";
 const ME_INSTANCES = parseInt(Bun.env.ME_INSTANCES || "4");
+const SCHEMA_VERSION = 1;
+// Must match how the matching engine declares the exchange
+const DURABLE_EXCHANGES = Bun.env.RABBITMQ_DURABLE_EXCHANGES === "true";
 
 let channel: amqp.Channel;
 
@@ -13,7 +16,7 @@ export async function initializeRabbitMQ(RABBITMQ_URL: string) {
   try {
     const connection = await amqp.connect(RABBITMQ_URL);
     channel = await connection.createChannel();
-    await channel.assertExchange(ORDER_EXCHANGE, "topic", { durable: false });
+    await channel.assertExchange(ORDER_EXCHANGE, "topic", { durable: DURABLE_EXCHANGES });
   } catch (error) {
     throw error;
   }
@@ -44,11 +47,22 @@ function generateRoutingKey(orderType: string, stockId: string): string {
  * Generates routing key by creating a shard given the orderType and the stockID
  * Publishes a message to the specified exchange with a given routing key.
  * The message is serialized into a JSON buffer before being sent to the exchange.
+ * The message envelope (type, schema version, ids, producer, timestamp) goes in the
+ * AMQP properties; the order's stock_tx_id is its correlation id.
  */
 export async function publishToQueue(orderType: string, message: any) {
   try {
     const routingKey = generateRoutingKey(orderType, message.stock_id);
-    await channel.publish(ORDER_EXCHANGE, routingKey, Buffer.from(JSON.stringify(message)));
+    await channel.publish(ORDER_EXCHANGE, routingKey, Buffer.from(JSON.stringify(message)), {
+      type: orderType,
+      messageId: crypto.randomUUID(),
+      correlationId: message.stock_tx_id,
+      appId: "order-manager",
+      timestamp: Math.floor(Date.now() / 1000),
+      contentType: "application/json",
+      persistent: true, // Survives a broker restart if the engine's queues are durable
+      headers: { "x-schema-version": SCHEMA_VERSION },
+    });
   } catch (error) {
     throw error;
   }
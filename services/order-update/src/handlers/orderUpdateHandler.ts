@@ -10,6 +10,13 @@ type SalesUpdateData = {
   user_name: string;
 };
 
+type BuyFill = {
+  trade_id: string;
+  sell_stock_tx_id: string;
+  quantity: number;
+  price: number;
+};
+
 type BuyCompleteData =
   | {
       success: true;
@@ -18,6 +25,9 @@ type BuyCompleteData =
         stock_tx_id: string;
         quantity: number;
         price_total: number;
+        // Not sent by matching engines older than the fill breakdown
+        average_price?: number;
+        fills?: BuyFill[];
       };
     }
   | {
@@ -143,6 +143,7 @@ export default {
   handleBuyCompletion: async ({
     price_total,
     quantity,
+    average_price,
     stock_id,
     stock_tx_id,
   }: {
@@ -150,6 +151,7 @@ export default {
     stock_tx_id: string;
     quantity: number;
     price_total: number;
+    average_price?: number;
   }) => {
     // Find the original stock order transaction
     let oriStockTx: StockTransaction | null = null;
@@ -193,7 +195,7 @@ export default {
       oriStockTx = await db.stockTxRepo.save({
         ...oriStockTx,
         order_status: ORDER_STATUS.COMPLETED,
-        stock_price: price_total / quantity, // Avg price per share
+        stock_price: average_price ?? price_total / quantity, // Avg price per share
         wallet_tx_id: walletTxId,
       });
     } catch (error) {
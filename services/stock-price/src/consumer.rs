@@ -1,58 +1,262 @@
-use crate::state::{AppState, StockPrice};
-use amqprs::{BasicProperties, Deliver, channel::Channel, consumer::AsyncConsumer};
+use crate::dead_letter::{DeadLetterChannel, Failure, add_failure_headers};
+use crate::price_history::{PriceHistory, now_millis};
+use crate::price_stream::PriceFeed;
+use crate::state::{AppState, PriceEvent, StockPrice, TradingStatus};
+use amqprs::{
+    BasicProperties, Deliver, FieldValue,
+    channel::{BasicAckArguments, BasicNackArguments, Channel},
+    consumer::AsyncConsumer,
+};
 use async_trait::async_trait;
 use tracing::{debug, error, info};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
+
+/// Newest envelope schema version this consumer understands.
+const SCHEMA_VERSION: i64 = 1;
+
+/// Check the envelope carried in the message properties, if there is one. Messages published
+/// without an envelope are accepted as they always were.
+fn check_envelope(properties: &BasicProperties) -> Result<(), String> {
+    let Some(message_type) = properties.message_type() else {
+        return Ok(());
+    };
+    if message_type != "stock.price" {
+        return Err(format!("unexpected message type {}", message_type));
+    }
+
+    let version = "x-schema-version"
+        .try_into()
+        .ok()
+        .and_then(|name| properties.headers()?.get(&name))
+        .and_then(|value| match value {
+            FieldValue::I(version) => Some(*version as i64),
+            FieldValue::l(version) => Some(*version),
+            _ => None,
+        })
+        .unwrap_or(1);
+    if version > SCHEMA_VERSION {
+        return Err(format!("unsupported schema version {} of {}", version, message_type));
+    }
+    Ok(())
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct PriceUpdate {
+pub struct PriceUpdate {
     pub stock_id: String,
     pub stock_name: Option<String>,
     pub current_price: Option<f64>,
+    // Set by the matching engine; absent on messages from older producers
+    pub trading_status: Option<TradingStatus>,
+    pub best_ask: Option<f64>,
+    pub best_ask_size: Option<u64>,
+    pub best_bid: Option<f64>,
+    pub best_bid_size: Option<u64>,
+    pub last_trade_price: Option<f64>,
+    pub last_trade_size: Option<u64>,
+    pub last_trade_time: Option<u64>,
+    pub session_volume: Option<u64>,
+    pub stock_seq: Option<u64>,
+    pub timestamp: Option<u64>,
+    pub shard_id: Option<u32>,
+    pub seq: Option<u64>,
 }
 
+#[derive(Clone)]
 pub struct PriceConsumer {
     pub state: Arc<RwLock<AppState>>,
+    pub history: Arc<Mutex<PriceHistory>>,
+    pub feed: PriceFeed, // Changes are pushed to streaming clients
+    pub dead_letters: Option<Arc<DeadLetterChannel>>, // Set for each session
 }
 
 impl PriceConsumer {
-    pub fn new(state: Arc<RwLock<AppState>>) -> Self {
+    pub fn new(
+        state: Arc<RwLock<AppState>>,
+        history: Arc<Mutex<PriceHistory>>,
+        feed: PriceFeed,
+    ) -> Self {
         info!("Initializing PriceConsumer");
-        Self { state }
+        Self { state, history, feed, dead_letters: None }
+    }
+
+    /// A copy that dead-letters through `dead_letters`, for one session.
+    pub fn with_dead_letters(&self, dead_letters: DeadLetterChannel) -> Self {
+        Self { dead_letters: Some(Arc::new(dead_letters)), ..self.clone() }
+    }
+}
+
+async fn ack(channel: &Channel, deliver: &Deliver) {
+    let args = BasicAckArguments::new(deliver.delivery_tag(), false);
+    if let Err(e) = channel.basic_ack(args).await {
+        error!("Failed to acknowledge price update message: {}", e);
+    }
+}
+
+/// Park a message that cannot be processed in the dead-letter queue, keeping its body and
+/// properties and adding headers that describe the failure. The message is only
+/// acknowledged once the broker confirms the dead letter, otherwise it is requeued.
+async fn dead_letter(
+    dead_letters: Option<&DeadLetterChannel>,
+    channel: &Channel,
+    deliver: &Deliver,
+    mut properties: BasicProperties,
+    content: Vec<u8>,
+    reason: &str,
+) {
+    // The shard is only known if the body is at least valid JSON
+    let shard_id = serde_json::from_slice::<serde_json::Value>(&content)
+        .ok()
+        .and_then(|body| body.get("shard_id")?.as_i64());
+    add_failure_headers(&mut properties, &Failure {
+        reason,
+        exchange: deliver.exchange(),
+        routing_key: deliver.routing_key(),
+        shard_id,
+        failed_at: now_millis(),
+        service: "stock-price",
+    });
+    properties.with_delivery_mode(2); // Make message persistent
+
+    let published = match dead_letters {
+        Some(dead_letters) => dead_letters.publish(properties, content).await,
+        None => Err("no dead-letter channel".to_string()),
+    };
+    match published {
+        Ok(()) => ack(channel, deliver).await,
+        Err(e) => {
+            error!("Failed to dead-letter price update message, requeueing it: {}", e);
+            let args = BasicNackArguments::new(deliver.delivery_tag(), false, true);
+            if let Err(e) = channel.basic_nack(args).await {
+                error!("Failed to requeue price update message: {}", e);
+            }
+        }
     }
 }
 
 #[async_trait]
 impl AsyncConsumer for PriceConsumer {
-    async fn consume(&mut self, _: &Channel, _: Deliver, _: BasicProperties, content: Vec<u8>) {
+    async fn consume(
+        &mut self,
+        channel: &Channel,
+        deliver: Deliver,
+        properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        if let Err(reason) = check_envelope(&properties) {
+            error!("Rejected price update message, dead-lettering it: {}", reason);
+            let dead_letters = self.dead_letters.as_deref();
+            dead_letter(dead_letters, channel, &deliver, properties, content, &reason).await;
+            return;
+        }
+
         let price_update: PriceUpdate = match serde_json::from_slice(&content) {
             Ok(pu) => {
-                debug!("Received price update message: {:?}", pu);
+                debug!(
+                    "Received price update message (correlation id {:?}): {:?}",
+                    properties.correlation_id(),
+                    pu
+                );
                 pu
             }
             Err(e) => {
-                error!("Failed to deserialize price update message: {}", e);
+                error!("Failed to deserialize price update message, dead-lettering it: {}", e);
+                let dead_letters = self.dead_letters.as_deref();
+                dead_letter(dead_letters, channel, &deliver, properties, content, &e.to_string())
+                    .await;
                 return;
             }
         };
+        self.apply(price_update).await;
+        ack(channel, &deliver).await;
+    }
+}
 
+impl PriceConsumer {
+    /// Apply a price from the stream or from a shard's price snapshot.
+    pub async fn apply(&self, price_update: PriceUpdate) {
         let mut state = self.state.write().await;
-        if price_update.current_price.is_some() && price_update.stock_name.is_some() {
+
+        // Drop prices older than the one we already have. The timestamp comes first so a
+        // restarted engine, whose per-stock sequence starts over, is still accepted.
+        if let (Some(stock_seq), Some(timestamp)) = (price_update.stock_seq, price_update.timestamp) {
+            let version = (timestamp, stock_seq);
+            let stale = state
+                .price_versions
+                .get(&price_update.stock_id)
+                .is_some_and(|last_version| version <= *last_version);
+            if stale {
+                debug!(
+                    "Discarding stale price for stock {} (stock_seq={}, shard={:?}, seq={:?})",
+                    price_update.stock_id, stock_seq, price_update.shard_id, price_update.seq
+                );
+                return;
+            }
+            state
+                .price_versions
+                .insert(price_update.stock_id.clone(), version);
+        }
+
+        // A stock with a status is listed. Only older producers, without one, remove a
+        // stock, with a null name or price, which newer ones send for a listed stock with
+        // nothing offered.
+        let listed = price_update.trading_status.is_some()
+            || (price_update.current_price.is_some() && price_update.stock_name.is_some());
+        // Every quote counts towards the history, even one of a stock that is not listed
+        let offered = price_update.best_ask.or(price_update.current_price);
+        self.history.lock().await.record(
+            &price_update.stock_id,
+            price_update.timestamp.unwrap_or_else(now_millis),
+            offered.filter(|_| listed),
+            price_update.session_volume,
+        );
+
+        // A quote without a name keeps the one the stock is listed under
+        let stock_name = price_update.stock_name.clone().or_else(|| {
+            state
+                .stock_prices
+                .get(&price_update.stock_id)
+                .map(|stock_price| stock_price.stock_name.clone())
+        });
+
+        if let (true, Some(stock_name)) = (listed, stock_name) {
+            let current_price = price_update.current_price;
             let stock_price = StockPrice {
                 stock_id: price_update.stock_id.clone(),
-                stock_name: price_update.stock_name.unwrap(),
-                current_price: price_update.current_price.unwrap().round() as i64,
+                stock_name,
+                current_price: current_price.map(|price| price.round() as i64),
+                trading_status: price_update.trading_status.unwrap_or_default(),
+                best_ask: price_update.best_ask.or(current_price),
+                best_ask_size: price_update.best_ask_size.unwrap_or_default(),
+                best_bid: price_update.best_bid,
+                best_bid_size: price_update.best_bid_size.unwrap_or_default(),
+                last_trade_price: price_update.last_trade_price,
+                last_trade_size: price_update.last_trade_size,
+                last_trade_time: price_update.last_trade_time,
+                session_volume: price_update.session_volume.unwrap_or_default(),
             };
-            debug!("Updating price for stock {}: ${:.2}", price_update.stock_id, price_update.current_price.unwrap());
+            debug!(
+                "Updating price for stock {}: {:?} ({:?})",
+                price_update.stock_id, current_price, stock_price.trading_status
+            );
             state
                 .stock_prices
-                .insert(price_update.stock_id.clone(), stock_price);
+                .insert(price_update.stock_id.clone(), stock_price.clone());
+            // Sent under the state lock, so streams see changes in the order they were applied
+            let change = state.record_change(PriceEvent::Price(stock_price));
+            let _ = self.feed.send(change);
             debug!("Successfully updated price for stock {}", price_update.stock_id);
+        } else if listed {
+            debug!("Not listing stock {} before its name is known", price_update.stock_id);
         } else {
             debug!("Removing stock {} from price tracking", price_update.stock_id);
-            state.stock_prices.remove(&price_update.stock_id);
+            if state.stock_prices.remove(&price_update.stock_id).is_some() {
+                let change = state.record_change(PriceEvent::Removed {
+                    stock_id: price_update.stock_id,
+                });
+                let _ = self.feed.send(change);
+            }
         }
     }
 }
@@ -0,0 +1,150 @@
+use amqprs::{
+    BasicProperties, FieldValue,
+    channel::{BasicPublishArguments, Channel},
+};
+use std::time::Duration;
+use tokio::sync::{Mutex, mpsc};
+
+use crate::rabbitmq::{DEAD_LETTER_EXCHANGE, DEAD_LETTER_QUEUE};
+
+/// How long a dead letter waits for the broker to confirm it.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A publisher confirm as `(delivery_tag, multiple, acked)`.
+pub type Confirm = (u64, bool, bool);
+
+// The same headers as the matching engine's dead letters, so its `me-dead-letters` tool can
+// re-drive ours too. The engine's helper is part of its library, so this is a copy of it.
+
+/// Why a message was dead-lettered and where it came from.
+pub struct Failure<'a> {
+    pub reason: &'a str,
+    pub exchange: &'a str,
+    pub routing_key: &'a str,
+    pub shard_id: Option<i64>,
+    pub failed_at: u64, // Unix milliseconds
+    pub service: &'a str,
+}
+
+/// Add the headers describing `failure` to the message's own.
+pub fn add_failure_headers(properties: &mut BasicProperties, failure: &Failure) {
+    let mut headers = properties.headers().cloned().unwrap_or_default();
+    let mut set = |name: &str, value: FieldValue| {
+        if let Ok(name) = name.try_into() {
+            headers.insert(name, value);
+        }
+    };
+    set("x-failure-reason", failure.reason.into());
+    set("x-original-exchange", failure.exchange.into());
+    set("x-original-routing-key", failure.routing_key.into());
+    if let Some(shard_id) = failure.shard_id {
+        set("x-shard-id", FieldValue::l(shard_id));
+    }
+    set("x-failed-at", FieldValue::l(failure.failed_at as i64));
+    set("x-service", failure.service.into());
+    properties.with_headers(headers);
+}
+
+/// A channel in confirm mode that only carries dead letters, so a message can be
+/// acknowledged once the broker has taken its dead-lettered copy.
+pub struct DeadLetterChannel {
+    channel: Channel,
+    confirms: Mutex<(u64, mpsc::UnboundedReceiver<Confirm>)>, // Publishes so far, and their confirms
+}
+
+impl DeadLetterChannel {
+    pub fn new(channel: Channel, confirms: mpsc::UnboundedReceiver<Confirm>) -> Self {
+        Self {
+            channel,
+            confirms: Mutex::new((0, confirms)),
+        }
+    }
+
+    /// Publish to the dead-letter queue and wait for the broker to confirm it.
+    pub async fn publish(
+        &self,
+        properties: BasicProperties,
+        content: Vec<u8>,
+    ) -> Result<(), String> {
+        let mut confirms = self.confirms.lock().await;
+        let (published, receiver) = &mut *confirms;
+
+        let args = BasicPublishArguments::new(DEAD_LETTER_EXCHANGE, DEAD_LETTER_QUEUE);
+        self.channel
+            .basic_publish(properties, content, args)
+            .await
+            .map_err(|e| e.to_string())?;
+        // The broker numbers publishes on the channel from 1
+        *published += 1;
+        let tag = *published;
+
+        // Confirms for earlier publishes that timed out are skipped
+        let confirmed = async {
+            while let Some((delivery_tag, multiple, acked)) = receiver.recv().await {
+                if delivery_tag == tag || (multiple && delivery_tag > tag) {
+                    return if acked {
+                        Ok(())
+                    } else {
+                        Err("nacked by broker".to_string())
+                    };
+                }
+            }
+            Err("channel closed".to_string())
+        };
+        tokio::time::timeout(CONFIRM_TIMEOUT, confirmed)
+            .await
+            .map_err(|_| "not confirmed in time".to_string())?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amqprs::FieldName;
+
+    fn header(properties: &BasicProperties, name: &str) -> Option<String> {
+        let name: FieldName = name.try_into().unwrap();
+        properties.headers()?.get(&name).map(FieldValue::to_string)
+    }
+
+    #[test]
+    fn failure_headers_are_added_to_the_message_headers() {
+        let mut headers = amqprs::FieldTable::new();
+        headers.insert("x-schema-version".try_into().unwrap(), FieldValue::l(1));
+        let mut properties = BasicProperties::default().with_headers(headers).finish();
+        add_failure_headers(
+            &mut properties,
+            &Failure {
+                reason: "bad json",
+                exchange: "stock_prices_exchange",
+                routing_key: "stock.price.appl",
+                shard_id: None,
+                failed_at: 1000,
+                service: "stock-price",
+            },
+        );
+
+        assert_eq!(
+            header(&properties, "x-schema-version").as_deref(),
+            Some("1")
+        );
+        assert_eq!(
+            header(&properties, "x-failure-reason").as_deref(),
+            Some("bad json")
+        );
+        assert_eq!(
+            header(&properties, "x-original-exchange").as_deref(),
+            Some("stock_prices_exchange")
+        );
+        assert_eq!(
+            header(&properties, "x-original-routing-key").as_deref(),
+            Some("stock.price.appl")
+        );
+        assert_eq!(header(&properties, "x-shard-id"), None);
+        assert_eq!(header(&properties, "x-failed-at").as_deref(), Some("1000"));
+        assert_eq!(
+            header(&properties, "x-service").as_deref(),
+            Some("stock-price")
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+use tracing::debug;
+
+use crate::order_book::OrderBooks;
+use crate::state::AppState;
+
+const DEFAULT_DEPTH: usize = 10;
+
+#[derive(Deserialize, Debug)]
+pub struct DepthQuery {
+    pub depth: Option<usize>,
+}
+
+#[derive(Clone)]
+pub struct OrderBookState {
+    pub books: Arc<Mutex<OrderBooks>>,
+    pub prices: Arc<RwLock<AppState>>, // To tell listed stocks without a book yet from unknown ones
+}
+
+/// `GET /orderBook/{stock_id}?depth=N`: the aggregated levels of a stock from this
+/// instance's replica of the matching engine's book.
+pub async fn get_order_book(
+    State(state): State<OrderBookState>,
+    Path(stock_id): Path<String>,
+    Query(query): Query<DepthQuery>,
+) -> (StatusCode, Json<Value>) {
+    let depth = query.depth.unwrap_or(DEFAULT_DEPTH);
+    debug!("Retrieving order book of {} (depth {})", stock_id, depth);
+    let listed = state
+        .prices
+        .read()
+        .await
+        .stock_prices
+        .contains_key(&stock_id);
+    let Some(view) = state
+        .books
+        .lock()
+        .await
+        .view(&stock_id, depth, listed)
+        .await
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "success": false,
+                "data": { "error": format!("Unknown stock {}", stock_id) }
+            })),
+        );
+    };
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "data": view,
+        })),
+    )
+}
@@ -0,0 +1,72 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::price_history::{Candle, PriceHistory, now_millis, parse_interval};
+
+const DEFAULT_INTERVAL: &str = "1m";
+/// Candles returned when `from` is not given.
+const DEFAULT_CANDLES: u64 = 100;
+
+#[derive(Deserialize, Debug)]
+pub struct HistoryQuery {
+    pub interval: Option<String>,
+    pub from: Option<u64>, // Unix milliseconds
+    pub to: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PriceHistoryData {
+    pub stock_id: String,
+    pub interval: String,
+    pub candles: Vec<Candle>,
+}
+
+/// `GET /stockPrices/{stock_id}/history?interval=1m&from=&to=`: candles of the best ask and
+/// the volume traded, oldest first.
+pub async fn get_price_history(
+    State(history): State<Arc<Mutex<PriceHistory>>>,
+    Path(stock_id): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> (StatusCode, Json<Value>) {
+    let interval = query
+        .interval
+        .unwrap_or_else(|| DEFAULT_INTERVAL.to_string());
+    let Some(width) = parse_interval(&interval) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "success": false,
+                "data": { "error": format!("Unknown interval {}", interval) }
+            })),
+        );
+    };
+    let to = query.to.unwrap_or_else(now_millis);
+    let from = query
+        .from
+        .unwrap_or_else(|| to.saturating_sub(DEFAULT_CANDLES * width));
+
+    debug!(
+        "Retrieving {} price history of {} from {} to {}",
+        interval, stock_id, from, to
+    );
+    let candles = history.lock().await.candles(&stock_id, width, from, to);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "data": PriceHistoryData {
+                stock_id,
+                interval,
+                candles,
+            },
+        })),
+    )
+}
@@ -13,8 +13,8 @@ pub async fn get_stock_prices(
 
     let mut prices: Vec<StockPrice> = prices
         .stock_prices
-        .iter()
-        .map(|(_, stock_price)| stock_price.clone())
+        .values()
+        .cloned()
         .collect();
 
     // Sort by stock_name in descending order (case-insensitive)
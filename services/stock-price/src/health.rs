@@ -0,0 +1,51 @@
+use axum::{Json, extract::State, http::StatusCode};
+use serde_json::{Value, json};
+use std::sync::Arc;
+
+use crate::price_snapshot::PriceSnapshots;
+use crate::rabbitmq::{ConnectionState, RabbitMQSupervisor};
+
+#[derive(Clone)]
+pub struct HealthState {
+    pub supervisor: Arc<RabbitMQSupervisor>,
+    pub snapshots: PriceSnapshots,
+}
+
+/// `GET /health`: 200 while the price consumer is connected to RabbitMQ, 503 otherwise.
+pub async fn health(State(state): State<HealthState>) -> (StatusCode, Json<Value>) {
+    let connection_state = state.supervisor.connection_state();
+    let healthy = connection_state == ConnectionState::Connected;
+    status_response(healthy, connection_state, state.snapshots.is_ready())
+}
+
+/// `GET /ready`: 200 once connected and every matching engine shard has sent its prices,
+/// 503 until then.
+pub async fn ready(State(state): State<HealthState>) -> (StatusCode, Json<Value>) {
+    let connection_state = state.supervisor.connection_state();
+    let ready = state.snapshots.is_ready();
+    status_response(
+        connection_state == ConnectionState::Connected && ready,
+        connection_state,
+        ready,
+    )
+}
+
+fn status_response(
+    ok: bool,
+    connection_state: ConnectionState,
+    ready: bool,
+) -> (StatusCode, Json<Value>) {
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(json!({
+            "success": ok,
+            "data": { "rabbitmq": connection_state, "ready": ready }
+        })),
+    )
+}
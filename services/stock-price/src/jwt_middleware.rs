@@ -2,7 +2,7 @@ use axum::{
     Json,
     body::Body,
     extract::Request,
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
 };
@@ -36,35 +36,71 @@ pub async fn jwt_middleware(
     }
     let token = token_header.unwrap();
 
+    match verify_token(token) {
+        Ok(claims) => {
+            // Insert claims into request extensions for later use.
+            req.extensions_mut().insert(claims);
+            Ok(next.run(req).await)
+        }
+        Err(resp_text) => Ok((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "success": false,
+                "data": { "error": resp_text }
+            })),
+        )
+            .into_response()),
+    }
+}
+
+/// `?token=` for streaming routes, as browsers cannot set headers on a WebSocket or
+/// `EventSource`.
+#[derive(Deserialize, Debug)]
+pub struct TokenQuery {
+    pub token: Option<String>,
+}
+
+/// Verify the token of a streaming request, from the `token` header or else the query. The
+/// error is the response to send, the same as `jwt_middleware`'s.
+pub fn verify_request(
+    headers: &HeaderMap,
+    query: &TokenQuery,
+) -> Result<Claims, (StatusCode, Json<serde_json::Value>)> {
+    let token = headers
+        .get("token")
+        .and_then(|value| value.to_str().ok())
+        .or(query.token.as_deref());
+    let verified = match token {
+        Some(token) => verify_token(token),
+        None => Err("Token not included"),
+    };
+    verified.map_err(|error| {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "success": false,
+                "data": { "error": error }
+            })),
+        )
+    })
+}
+
+/// Decode and verify a token, or say why it was rejected.
+pub fn verify_token(token: &str) -> Result<Claims, &'static str> {
     // Use JWT_SECRET environment variable, defaulting to "secret" if not set.
     let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "secret".to_string());
     let validation = Validation::default();
 
     // Verify the token.
-    match decode::<Claims>(
+    decode::<Claims>(
         token,
         &DecodingKey::from_secret(secret.as_ref()),
         &validation,
-    ) {
-        Ok(token_data) => {
-            // Insert claims into request extensions for later use.
-            req.extensions_mut().insert(token_data.claims);
-            Ok(next.run(req).await)
-        }
-        Err(err) => {
-            let resp_text = match *err.kind() {
-                ErrorKind::InvalidToken => "Invalid token included",
-                ErrorKind::ExpiredSignature => "Token expired",
-                _ => "Unauthorized",
-            };
-            Ok((
-                StatusCode::UNAUTHORIZED,
-                Json(json!({
-                    "success": false,
-                    "data": { "error": resp_text }
-                })),
-            )
-                .into_response())
-        }
-    }
+    )
+    .map(|token_data| token_data.claims)
+    .map_err(|err| match *err.kind() {
+        ErrorKind::InvalidToken => "Invalid token included",
+        ErrorKind::ExpiredSignature => "Token expired",
+        _ => "Unauthorized",
+    })
 }
@@ -1,18 +1,37 @@
 mod consumer;
+mod dead_letter;
+mod get_order_book;
+mod get_price_history;
 mod get_stock_prices;
+mod health;
 mod jwt_middleware;
+mod metrics;
+mod order_book;
+mod price_events;
+mod price_history;
+mod price_snapshot;
+mod price_stream;
 mod rabbitmq;
 mod state;
 
-use amqprs::channel::BasicConsumeArguments;
 use axum::{Router, middleware::from_fn, routing::get};
 use std::{env, sync::Arc};
-use tracing::{info, debug};
+use tracing::{debug, error, info};
 use tracing_subscriber::{FmtSubscriber, EnvFilter};
 
 use crate::consumer::PriceConsumer;
+use crate::get_order_book::{OrderBookState, get_order_book};
+use crate::get_price_history::get_price_history;
 use crate::get_stock_prices::get_stock_prices;
+use crate::health::{HealthState, health, ready};
 use crate::jwt_middleware::jwt_middleware;
+use crate::metrics::metrics;
+use crate::order_book::{BookConsumer, OrderBooks};
+use crate::price_events::stream_price_events;
+use crate::price_history::PriceHistory;
+use crate::price_snapshot::PriceSnapshots;
+use crate::price_stream::{PriceStreamState, price_feed, price_stream};
+use crate::rabbitmq::{ConnectionState, RabbitMQConfig, RabbitMQSupervisor};
 use crate::state::AppState;
 
 pub fn setup_tracing() {
@@ -49,31 +68,81 @@ async fn main() {
 
     // Setup RabbitMQ connection and channel
     let exchange_name = "stock_prices_exchange";
-    let queue_name = "stock_prices_queue";
     let consumer_tag = "stock_price_consumer";
     let binding_key = "stock.price.*";
 
-    debug!("Setting up RabbitMQ with exchange: {}, queue: {}", exchange_name, queue_name);
-    let (connection, channel) =
-        rabbitmq::setup_rabbitmq(exchange_name, queue_name, binding_key).await;
+    debug!("Setting up RabbitMQ with exchange: {}", exchange_name);
+    let rabbitmq_config =
+        match RabbitMQConfig::from_env(exchange_name, binding_key, consumer_tag) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Invalid RabbitMQ configuration: {}", e);
+                std::process::exit(1);
+            }
+        };
+    info!("Expecting {} matching engine shards", rabbitmq_config.me_instances);
+    let rabbitmq_supervisor = Arc::new(RabbitMQSupervisor::new(rabbitmq_config));
 
     let app_state = Arc::new(tokio::sync::RwLock::new(AppState::new()));
-    let price_consumer = PriceConsumer::new(app_state.clone());
+    let price_history = Arc::new(tokio::sync::Mutex::new(PriceHistory::from_env()));
+    PriceHistory::spawn_maintenance(price_history.clone());
+    let feed = price_feed();
+    let price_consumer = PriceConsumer::new(app_state.clone(), price_history.clone(), feed.clone());
+    let order_books = Arc::new(tokio::sync::Mutex::new(OrderBooks::new()));
+    let book_consumer = BookConsumer::new(order_books.clone());
+    let price_snapshots = PriceSnapshots::new(price_consumer.clone());
+    rabbitmq_supervisor.spawn(price_consumer, book_consumer, price_snapshots.clone());
 
-    let mut consume_args = BasicConsumeArguments::new(queue_name, consumer_tag);
-    consume_args.manual_ack(false);
-    channel
-        .basic_consume(price_consumer, consume_args)
-        .await
-        .unwrap();
-
-    // Build router with JWT middleware applied to /stockPrices route
+    // Build router with JWT middleware applied to /stockPrices, its history and /orderBook routes
     let app = Router::new()
         .route(
             "/stockPrices",
             get(get_stock_prices).layer(from_fn(jwt_middleware)),
         )
-        .with_state(app_state);
+        .with_state(app_state.clone())
+        .merge(
+            // Check the token themselves, as browsers can only pass it as a query parameter
+            Router::new()
+                .route("/stockPrices/ws", get(price_stream))
+                .route("/stockPrices/stream", get(stream_price_events))
+                .with_state(PriceStreamState {
+                    prices: app_state.clone(),
+                    feed,
+                }),
+        )
+        .merge(
+            Router::new()
+                .route(
+                    "/stockPrices/{stock_id}/history",
+                    get(get_price_history).layer(from_fn(jwt_middleware)),
+                )
+                .with_state(price_history.clone()),
+        )
+        .merge(
+            Router::new()
+                .route(
+                    "/orderBook/{stock_id}",
+                    get(get_order_book).layer(from_fn(jwt_middleware)),
+                )
+                .with_state(OrderBookState {
+                    books: order_books.clone(),
+                    prices: app_state,
+                }),
+        )
+        .merge(
+            Router::new()
+                .route("/metrics", get(metrics))
+                .with_state(order_books),
+        )
+        .merge(
+            Router::new()
+                .route("/health", get(health))
+                .route("/ready", get(ready))
+                .with_state(HealthState {
+                    supervisor: rabbitmq_supervisor.clone(),
+                    snapshots: price_snapshots,
+                }),
+        );
     let port: String = env::var("PORT").unwrap_or("3000".to_string());
     let server_endpoint = format!("0.0.0.0:{port}");
     let listener = tokio::net::TcpListener::bind(server_endpoint.clone())
@@ -81,9 +150,61 @@ async fn main() {
         .unwrap();
     info!("Stock Prices Service listening on {server_endpoint}");
 
-    // Store connection and channel in variables that won't go out of scope
-    // This prevents them from being dropped
-    let _connection = connection;
-    let _channel = channel;
-    axum::serve(listener, app).await.unwrap();
+    // Finish in-flight requests and stop accepting new ones on SIGINT/SIGTERM, or when the
+    // broker's declarations turn out not to match ours
+    let stop = {
+        let supervisor = rabbitmq_supervisor.clone();
+        async move {
+            tokio::select! {
+                _ = shutdown_signal() => {}
+                reason = supervisor.failed() => {
+                    error!("Stopping, RabbitMQ setup failed: {}", reason);
+                }
+            }
+        }
+    };
+    axum::serve(listener, app)
+        .with_graceful_shutdown(stop)
+        .await
+        .unwrap();
+    info!("HTTP server stopped, closing RabbitMQ consumer");
+    let failed = rabbitmq_supervisor.connection_state() == ConnectionState::Failed;
+    rabbitmq_supervisor.close().await;
+    PriceHistory::save(&price_history).await;
+    info!("Stock price service stopped");
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Resolves on SIGINT (Ctrl+C) or SIGTERM (e.g. `docker stop`).
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Received shutdown signal");
 }
@@ -0,0 +1,22 @@
+use axum::{extract::State, http::header};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::order_book::OrderBooks;
+
+/// `GET /metrics`: counters in the Prometheus text format.
+pub async fn metrics(
+    State(books): State<Arc<Mutex<OrderBooks>>>,
+) -> ([(header::HeaderName, &'static str); 1], String) {
+    let metrics = books.lock().await.metrics();
+    let body = format!(
+        "# HELP stock_price_book_checksum_mismatches_total Book replicas found off the matching engine's checksum.\n\
+         # TYPE stock_price_book_checksum_mismatches_total counter\n\
+         stock_price_book_checksum_mismatches_total {}\n\
+         # HELP stock_price_book_resyncs_total Book snapshots requested from the matching engine.\n\
+         # TYPE stock_price_book_resyncs_total counter\n\
+         stock_price_book_resyncs_total {}\n",
+        metrics.checksum_mismatches, metrics.resyncs
+    );
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
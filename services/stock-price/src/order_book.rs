@@ -0,0 +1,562 @@
+use amqprs::{
+    BasicProperties, Deliver,
+    channel::{BasicPublishArguments, Channel},
+    consumer::AsyncConsumer,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+/// Exchange the matching engine publishes book updates on and takes snapshot requests from.
+pub const MARKET_DATA_EXCHANGE: &str = "market_data_exchange";
+
+/// Levels per side the matching engine's book checksum covers.
+const CHECKSUM_DEPTH: usize = 10;
+
+/// A snapshot request without a reply by then is sent again.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Ask,
+    Bid,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PriceLevel {
+    pub price: f64,
+    pub size: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct LevelChange {
+    side: Side,
+    price: f64,
+    size: u64, // 0 if the level is gone
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct BookUpdate {
+    stock_id: String,
+    book_seq: u64,
+    changes: Vec<LevelChange>,
+    checksum: Option<u32>, // Of the book after the changes
+}
+
+#[derive(Deserialize, Debug)]
+struct BookSnapshot {
+    stock_id: String,
+    book_seq: u64,
+    checksum: Option<u32>,
+    asks: Vec<PriceLevel>,
+    bids: Vec<PriceLevel>,
+}
+
+/// Shard of the matching engine that owns a stock; the same hash the order manager routes
+/// orders with.
+pub fn shard_of(stock_id: &str, instances: u32) -> u32 {
+    let mut hash: i32 = 0;
+    for unit in stock_id.encode_utf16() {
+        hash = hash
+            .wrapping_shl(5)
+            .wrapping_sub(hash)
+            .wrapping_add(unit as i32);
+    }
+    ((hash as i64).abs() % instances.max(1) as i64) as u32
+}
+
+/// Why replaying buffered updates onto a snapshot stopped short.
+#[derive(Debug, PartialEq)]
+enum ReplayError {
+    Gap,              // An update is missing
+    ChecksumMismatch, // An update left the book off its checksum
+}
+
+/// Replica of one stock's aggregated book.
+#[derive(Debug, Default)]
+struct Book {
+    asks: Vec<PriceLevel>, // Cheapest first
+    bids: Vec<PriceLevel>, // Highest first
+    book_seq: u64,
+    syncing: Option<Instant>,  // When the pending snapshot was requested
+    buffered: Vec<BookUpdate>, // Updates received while syncing
+}
+
+impl Book {
+    /// CRC32 of the best `CHECKSUM_DEPTH` levels per side, in the matching engine's format:
+    /// `price:size` pairs joined by `,`, the asks then `|` then the bids.
+    fn checksum(&self) -> u32 {
+        let side = |levels: &[PriceLevel]| {
+            levels
+                .iter()
+                .take(CHECKSUM_DEPTH)
+                .map(|level| format!("{}:{}", level.price, level.size))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        crc32fast::hash(format!("{}|{}", side(&self.asks), side(&self.bids)).as_bytes())
+    }
+
+    /// Whether the book matches a checksum from the engine; one that did not send any
+    /// is trusted.
+    fn matches(&self, checksum: Option<u32>) -> bool {
+        checksum.is_none_or(|checksum| checksum == self.checksum())
+    }
+
+    fn apply(&mut self, update: &BookUpdate) {
+        for change in &update.changes {
+            let levels = match change.side {
+                Side::Ask => &mut self.asks,
+                Side::Bid => &mut self.bids,
+            };
+            levels.retain(|level| level.price != change.price);
+            if change.size > 0 {
+                levels.push(PriceLevel {
+                    price: change.price,
+                    size: change.size,
+                });
+            }
+        }
+        self.asks.sort_by(|a, b| a.price.total_cmp(&b.price));
+        self.bids.sort_by(|a, b| b.price.total_cmp(&a.price));
+        self.book_seq = update.book_seq;
+    }
+
+    /// Apply the buffered updates that follow the current `book_seq`.
+    fn apply_buffered(&mut self) -> Result<(), ReplayError> {
+        let mut buffered = std::mem::take(&mut self.buffered);
+        buffered.sort_by_key(|update| update.book_seq);
+        for update in buffered {
+            if update.book_seq <= self.book_seq {
+                continue;
+            }
+            if update.book_seq != self.book_seq + 1 {
+                return Err(ReplayError::Gap);
+            }
+            self.apply(&update);
+            if !self.matches(update.checksum) {
+                return Err(ReplayError::ChecksumMismatch);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where snapshot requests go for the current RabbitMQ session.
+pub struct SnapshotRequester {
+    pub channel: Channel,
+    pub reply_to: String, // This instance's book queue
+    pub instances: u32,   // Number of matching engine shards
+}
+
+impl SnapshotRequester {
+    async fn request(&self, stock_id: &str) {
+        let routing_key = format!(
+            "book.snapshot_request.shard_{}",
+            shard_of(stock_id, self.instances)
+        );
+        let properties = BasicProperties::default()
+            .with_content_type("application/json")
+            .with_message_type("book.snapshot_request")
+            .with_correlation_id(stock_id)
+            .with_reply_to(&self.reply_to)
+            .with_app_id("stock-price")
+            .finish();
+        let payload = json!({ "stock_id": stock_id }).to_string().into_bytes();
+        let args = BasicPublishArguments::new(MARKET_DATA_EXCHANGE, &routing_key);
+        if let Err(e) = self.channel.basic_publish(properties, payload, args).await {
+            error!("Failed to request book snapshot of {}: {}", stock_id, e);
+        } else {
+            debug!("Requested book snapshot of {} on {}", stock_id, routing_key);
+        }
+    }
+}
+
+/// A replica's view of one book, as served by `/orderBook`.
+#[derive(Serialize, Debug)]
+pub struct OrderBookView {
+    pub stock_id: String,
+    pub book_seq: u64,
+    pub stale: bool, // Resyncing, the levels may be behind the engine
+    pub asks: Vec<PriceLevel>,
+    pub bids: Vec<PriceLevel>,
+}
+
+/// Counts of replica repairs since startup, as served by `/metrics`.
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct BookMetrics {
+    pub checksum_mismatches: u64, // Updates or snapshots that left a book off its checksum
+    pub resyncs: u64,             // Snapshot requests sent
+}
+
+/// Replicas of every book this instance has seen, kept in line with the matching engine by
+/// its book updates and resynced from a snapshot whenever an update is missed or a book no
+/// longer matches the engine's checksum.
+#[derive(Default)]
+pub struct OrderBooks {
+    books: HashMap<String, Book>,
+    requester: Option<SnapshotRequester>,
+    metrics: BookMetrics,
+}
+
+impl OrderBooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A new session started. Updates may have been missed while disconnected, so every
+    /// book is resynced.
+    pub async fn connected(&mut self, requester: SnapshotRequester) {
+        self.requester = Some(requester);
+        let stock_ids: Vec<String> = self.books.keys().cloned().collect();
+        for stock_id in stock_ids {
+            self.resync(&stock_id).await;
+        }
+    }
+
+    async fn resync(&mut self, stock_id: &str) {
+        let book = self.books.entry(stock_id.to_string()).or_default();
+        book.syncing = Some(Instant::now());
+        self.metrics.resyncs += 1;
+        if let Some(requester) = &self.requester {
+            requester.request(stock_id).await;
+        }
+    }
+
+    /// Request the snapshot again if the last request went unanswered.
+    async fn retry_stale_request(&mut self, stock_id: &str) {
+        let timed_out = self
+            .books
+            .get(stock_id)
+            .and_then(|book| book.syncing)
+            .is_some_and(|requested| requested.elapsed() >= SNAPSHOT_TIMEOUT);
+        if timed_out {
+            warn!("No book snapshot of {} yet, requesting it again", stock_id);
+            self.resync(stock_id).await;
+        }
+    }
+
+    async fn update(&mut self, update: BookUpdate) {
+        let stock_id = update.stock_id.clone();
+        let book = self.books.entry(stock_id.clone()).or_default();
+
+        if book.syncing.is_some() {
+            book.buffered.push(update);
+            self.retry_stale_request(&stock_id).await;
+        } else if update.book_seq <= book.book_seq {
+            debug!(
+                "Discarding old book update of {} (book_seq={})",
+                stock_id, update.book_seq
+            );
+        } else if update.book_seq == book.book_seq + 1 {
+            book.apply(&update);
+            if !book.matches(update.checksum) {
+                warn!(
+                    "Book of {} off its checksum at book_seq {}, resyncing",
+                    stock_id, update.book_seq
+                );
+                self.metrics.checksum_mismatches += 1;
+                self.resync(&stock_id).await;
+            }
+        } else {
+            // A book first seen in the middle of its sequence lands here as well
+            warn!(
+                "Missed book updates of {} (have {}, got {}), resyncing",
+                stock_id, book.book_seq, update.book_seq
+            );
+            book.buffered.push(update);
+            self.resync(&stock_id).await;
+        }
+    }
+
+    async fn snapshot(&mut self, snapshot: BookSnapshot) {
+        let stock_id = snapshot.stock_id.clone();
+        let book = self.books.entry(stock_id.clone()).or_default();
+        if book.syncing.is_none() && snapshot.book_seq <= book.book_seq {
+            debug!("Discarding unneeded book snapshot of {}", stock_id);
+            return;
+        }
+
+        book.asks = snapshot.asks;
+        book.bids = snapshot.bids;
+        book.book_seq = snapshot.book_seq;
+        if !book.matches(snapshot.checksum) {
+            warn!(
+                "Book snapshot of {} off its checksum at book_seq {}, resyncing",
+                stock_id, snapshot.book_seq
+            );
+            self.metrics.checksum_mismatches += 1;
+            self.resync(&stock_id).await;
+        } else {
+            match book.apply_buffered() {
+                Ok(()) => {
+                    book.syncing = None;
+                    info!("Book of {} in sync at book_seq {}", stock_id, book.book_seq);
+                }
+                Err(ReplayError::Gap) => {
+                    warn!(
+                        "Book updates of {} still missing after snapshot, resyncing",
+                        stock_id
+                    );
+                    self.resync(&stock_id).await;
+                }
+                Err(ReplayError::ChecksumMismatch) => {
+                    warn!(
+                        "Book of {} off its checksum at book_seq {} after snapshot, resyncing",
+                        stock_id, book.book_seq
+                    );
+                    self.metrics.checksum_mismatches += 1;
+                    self.resync(&stock_id).await;
+                }
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> BookMetrics {
+        self.metrics
+    }
+
+    /// The book of a stock with at most `depth` levels per side. A `listed` stock (one with
+    /// a price) without a book yet is synced now and reported empty and stale until the
+    /// snapshot arrives; any other stock this instance has not seen has no book.
+    pub async fn view(
+        &mut self,
+        stock_id: &str,
+        depth: usize,
+        listed: bool,
+    ) -> Option<OrderBookView> {
+        if self.books.contains_key(stock_id) {
+            self.retry_stale_request(stock_id).await;
+        } else if listed {
+            self.resync(stock_id).await;
+        } else {
+            return None;
+        }
+
+        let book = &self.books[stock_id];
+        Some(OrderBookView {
+            stock_id: stock_id.to_string(),
+            book_seq: book.book_seq,
+            stale: book.syncing.is_some(),
+            asks: book.asks.iter().take(depth).cloned().collect(),
+            bids: book.bids.iter().take(depth).cloned().collect(),
+        })
+    }
+}
+
+/// Feeds book updates and snapshot replies from this instance's book queue into the replicas.
+#[derive(Clone)]
+pub struct BookConsumer {
+    pub books: Arc<Mutex<OrderBooks>>,
+}
+
+impl BookConsumer {
+    pub fn new(books: Arc<Mutex<OrderBooks>>) -> Self {
+        info!("Initializing BookConsumer");
+        Self { books }
+    }
+}
+
+#[async_trait]
+impl AsyncConsumer for BookConsumer {
+    async fn consume(
+        &mut self,
+        _channel: &Channel,
+        _deliver: Deliver,
+        properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        let message_type = properties.message_type().cloned().unwrap_or_default();
+        let mut books = self.books.lock().await;
+        match message_type.as_str() {
+            "book.update" => match serde_json::from_slice(&content) {
+                Ok(update) => books.update(update).await,
+                Err(e) => error!("Failed to deserialize book update: {}", e),
+            },
+            "book.snapshot" => match serde_json::from_slice(&content) {
+                Ok(snapshot) => books.snapshot(snapshot).await,
+                Err(e) => error!("Failed to deserialize book snapshot: {}", e),
+            },
+            other => warn!(
+                "Ignoring unexpected message type '{}' on the book queue",
+                other
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(price: f64, size: u64) -> PriceLevel {
+        PriceLevel { price, size }
+    }
+
+    fn checksum(asks: &[PriceLevel], bids: &[PriceLevel]) -> Option<u32> {
+        let book = Book {
+            asks: asks.to_vec(),
+            bids: bids.to_vec(),
+            ..Book::default()
+        };
+        Some(book.checksum())
+    }
+
+    /// An update setting one ask level, carrying the checksum of the asks it leaves.
+    fn ask_update(book_seq: u64, price: f64, size: u64, asks: &[PriceLevel]) -> BookUpdate {
+        BookUpdate {
+            stock_id: "appl".to_string(),
+            book_seq,
+            changes: vec![LevelChange {
+                side: Side::Ask,
+                price,
+                size,
+            }],
+            checksum: checksum(asks, &[]),
+        }
+    }
+
+    fn snapshot(book_seq: u64, asks: &[PriceLevel]) -> BookSnapshot {
+        BookSnapshot {
+            stock_id: "appl".to_string(),
+            book_seq,
+            checksum: checksum(asks, &[]),
+            asks: asks.to_vec(),
+            bids: vec![],
+        }
+    }
+
+    async fn view(books: &mut OrderBooks) -> OrderBookView {
+        books.view("appl", 10, true).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn applies_updates_in_sequence() {
+        let mut books = OrderBooks::new();
+        books
+            .update(ask_update(1, 100.0, 5, &[level(100.0, 5)]))
+            .await;
+        books
+            .update(ask_update(2, 99.0, 3, &[level(99.0, 3), level(100.0, 5)]))
+            .await;
+
+        let view = view(&mut books).await;
+        assert!(!view.stale);
+        assert_eq!(view.book_seq, 2);
+        assert_eq!(view.asks, vec![level(99.0, 3), level(100.0, 5)]);
+        assert_eq!(books.metrics().resyncs, 0);
+    }
+
+    #[tokio::test]
+    async fn gap_resyncs_and_replays_buffered_updates() {
+        let mut books = OrderBooks::new();
+        books
+            .update(ask_update(1, 100.0, 5, &[level(100.0, 5)]))
+            .await;
+        // book_seq 2 is missed
+        books
+            .update(ask_update(3, 101.0, 2, &[level(99.0, 3), level(101.0, 2)]))
+            .await;
+        assert!(view(&mut books).await.stale);
+        assert_eq!(books.metrics().resyncs, 1);
+
+        books.snapshot(snapshot(2, &[level(99.0, 3)])).await;
+        let view = view(&mut books).await;
+        assert!(!view.stale);
+        assert_eq!(view.book_seq, 3);
+        assert_eq!(view.asks, vec![level(99.0, 3), level(101.0, 2)]);
+        assert_eq!(books.metrics().resyncs, 1);
+    }
+
+    #[tokio::test]
+    async fn gap_after_snapshot_resyncs_again() {
+        let mut books = OrderBooks::new();
+        books
+            .update(ask_update(1, 100.0, 5, &[level(100.0, 5)]))
+            .await;
+        books
+            .update(ask_update(4, 101.0, 2, &[level(100.0, 5), level(101.0, 2)]))
+            .await;
+
+        // The snapshot is at book_seq 2, book_seq 3 is still missing
+        books.snapshot(snapshot(2, &[level(100.0, 5)])).await;
+        assert!(view(&mut books).await.stale);
+        assert_eq!(books.metrics().resyncs, 2);
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch_resyncs() {
+        let mut books = OrderBooks::new();
+        books
+            .update(ask_update(1, 100.0, 5, &[level(100.0, 5)]))
+            .await;
+        books
+            .update(ask_update(2, 99.0, 3, &[level(100.0, 4)]))
+            .await;
+        assert!(view(&mut books).await.stale);
+        let metrics = books.metrics();
+        assert_eq!(metrics.checksum_mismatches, 1);
+        assert_eq!(metrics.resyncs, 1);
+
+        books
+            .snapshot(snapshot(2, &[level(99.0, 3), level(100.0, 5)]))
+            .await;
+        let view = view(&mut books).await;
+        assert!(!view.stale);
+        assert_eq!(view.asks, vec![level(99.0, 3), level(100.0, 5)]);
+    }
+
+    #[tokio::test]
+    async fn snapshot_off_its_checksum_resyncs() {
+        let mut books = OrderBooks::new();
+        books
+            .update(ask_update(2, 100.0, 5, &[level(100.0, 5)]))
+            .await;
+        let mut bad = snapshot(1, &[level(100.0, 5)]);
+        bad.checksum = checksum(&[level(100.0, 6)], &[]);
+        books.snapshot(bad).await;
+
+        assert!(view(&mut books).await.stale);
+        let metrics = books.metrics();
+        assert_eq!(metrics.checksum_mismatches, 1);
+        assert_eq!(metrics.resyncs, 2);
+    }
+
+    #[tokio::test]
+    async fn old_updates_and_snapshots_are_discarded() {
+        let mut books = OrderBooks::new();
+        books
+            .update(ask_update(1, 100.0, 5, &[level(100.0, 5)]))
+            .await;
+        books
+            .update(ask_update(1, 100.0, 9, &[level(100.0, 9)]))
+            .await;
+        books.snapshot(snapshot(1, &[level(100.0, 9)])).await;
+
+        let view = view(&mut books).await;
+        assert_eq!(view.asks, vec![level(100.0, 5)]);
+        assert_eq!(books.metrics().resyncs, 0);
+    }
+
+    #[tokio::test]
+    async fn unknown_stock_has_no_book() {
+        let mut books = OrderBooks::new();
+        assert!(books.view("nope", 10, false).await.is_none());
+        assert!(books.books.is_empty());
+        assert_eq!(books.metrics().resyncs, 0);
+
+        // A listed stock without updates yet is synced on request
+        let view = books.view("appl", 10, true).await.unwrap();
+        assert!(view.stale);
+        assert!(view.asks.is_empty());
+        assert_eq!(books.metrics().resyncs, 1);
+    }
+}
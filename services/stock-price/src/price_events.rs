@@ -0,0 +1,152 @@
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use futures_util::stream;
+use std::{collections::VecDeque, sync::Arc};
+use tokio::sync::{RwLock, broadcast};
+use tracing::{debug, info, warn};
+
+use crate::jwt_middleware::{TokenQuery, verify_request};
+use crate::price_stream::PriceStreamState;
+use crate::state::{AppState, PriceChange, PriceEvent, StockPrice};
+
+/// Event id of a change: the process epoch and the change id, so an id from before a
+/// restart is never taken for one of ours.
+fn event_id(epoch: u64, id: u64) -> String {
+    format!("{}-{}", epoch, id)
+}
+
+/// The change id of a `Last-Event-ID` this process handed out.
+fn parse_event_id(epoch: u64, event_id: &str) -> Option<u64> {
+    let (event_epoch, id) = event_id.split_once('-')?;
+    if event_epoch.parse::<u64>().ok()? != epoch {
+        return None;
+    }
+    id.parse().ok()
+}
+
+fn change_event(epoch: u64, change: &PriceChange) -> Result<Event, axum::Error> {
+    let event = Event::default().id(event_id(epoch, change.id));
+    match &change.event {
+        PriceEvent::Price(price) => event.event("price").json_data(price),
+        PriceEvent::Removed { stock_id } => event
+            .event("removed")
+            .json_data(serde_json::json!({ "stock_id": stock_id })),
+    }
+}
+
+/// Every current price, with the id of the last change it includes.
+async fn snapshot_event(prices: &RwLock<AppState>) -> (u64, Result<Event, axum::Error>) {
+    let prices = prices.read().await;
+    let snapshot: Vec<&StockPrice> = prices.stock_prices.values().collect();
+    let event = Event::default()
+        .id(event_id(prices.epoch, prices.change_id))
+        .event("snapshot")
+        .json_data(snapshot);
+    (prices.change_id, event)
+}
+
+struct Stream {
+    prices: Arc<RwLock<AppState>>,
+    feed: broadcast::Receiver<PriceChange>,
+    epoch: u64,
+    last_id: u64, // Changes up to this one have been sent
+    queued: VecDeque<Result<Event, axum::Error>>,
+}
+
+/// `GET /stockPrices/stream`: Server-Sent Events of every change to `/stockPrices`. The first
+/// event is a `snapshot` of all prices, unless the client reconnects with a `Last-Event-ID`
+/// whose later changes are still kept, in which case those are replayed instead.
+pub async fn stream_price_events(
+    State(state): State<PriceStreamState>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let claims = match verify_request(&headers, &query) {
+        Ok(claims) => claims,
+        Err(response) => return response.into_response(),
+    };
+
+    // Subscribed first, so no change falls between the snapshot or replay and the feed
+    let feed = state.feed.subscribe();
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok());
+
+    let replay = {
+        let prices = state.prices.read().await;
+        last_event_id
+            .and_then(|event_id| parse_event_id(prices.epoch, event_id))
+            .and_then(|id| Some((id, prices.changes_since(id)?)))
+            .map(|(id, changes)| (prices.epoch, id, changes))
+    };
+    let (epoch, last_id, queued) = match replay {
+        Some((epoch, id, changes)) => {
+            info!(
+                "Opening price event stream for {}, replaying {} changes",
+                claims.username,
+                changes.len()
+            );
+            let last_id = changes.last().map_or(id, |change| change.id);
+            let queued = changes
+                .iter()
+                .map(|change| change_event(epoch, change))
+                .collect();
+            (epoch, last_id, queued)
+        }
+        None => {
+            if let Some(event_id) = last_event_id {
+                debug!(
+                    "Changes after {} are no longer kept, sending a snapshot",
+                    event_id
+                );
+            }
+            info!("Opening price event stream for {}", claims.username);
+            let epoch = state.prices.read().await.epoch;
+            let (last_id, snapshot) = snapshot_event(&state.prices).await;
+            (epoch, last_id, VecDeque::from([snapshot]))
+        }
+    };
+
+    let stream = Stream {
+        prices: state.prices,
+        feed,
+        epoch,
+        last_id,
+        queued,
+    };
+    let events = stream::unfold(stream, |mut stream| async move {
+        loop {
+            if let Some(event) = stream.queued.pop_front() {
+                return Some((event, stream));
+            }
+            match stream.feed.recv().await {
+                Ok(change) if change.id <= stream.last_id => continue, // Already sent
+                Ok(change) => {
+                    stream.last_id = change.id;
+                    let event = change_event(stream.epoch, &change);
+                    return Some((event, stream));
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!(
+                        "Price event stream missed {} changes, sending a snapshot",
+                        missed
+                    );
+                    let (last_id, snapshot) = snapshot_event(&stream.prices).await;
+                    stream.last_id = last_id;
+                    stream.queued.push_back(snapshot);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
@@ -0,0 +1,445 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    env, fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+const MINUTE: u64 = 60 * 1000;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
+/// Held while saving, so saves reach the file in the order their content was taken.
+static SAVING: Mutex<()> = Mutex::const_new(());
+
+/// Widths of the candles kept, finest first. Candles move to the next tier as they age.
+const TIER_WIDTHS: [u64; 3] = [MINUTE, HOUR, DAY];
+
+/// Candle widths the history can be queried at.
+const INTERVALS: [(&str, u64); 6] = [
+    ("1m", MINUTE),
+    ("5m", 5 * MINUTE),
+    ("15m", 15 * MINUTE),
+    ("1h", HOUR),
+    ("4h", 4 * HOUR),
+    ("1d", DAY),
+];
+
+/// Width in milliseconds of a named interval such as `1m` or `4h`.
+pub fn parse_interval(name: &str) -> Option<u64> {
+    INTERVALS
+        .iter()
+        .find(|(interval, _)| *interval == name)
+        .map(|(_, width)| *width)
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Open, high, low and close of the best ask over one interval, with the shares traded in it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Candle {
+    pub start: u64, // Unix milliseconds, a multiple of the interval
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: u64,
+}
+
+impl Candle {
+    fn new(start: u64, price: f64) -> Self {
+        Self {
+            start,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: 0,
+        }
+    }
+
+    fn update(&mut self, price: f64, volume: u64) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume += volume;
+    }
+
+    /// Fold in a candle that follows this one.
+    fn merge(&mut self, later: &Candle) {
+        self.high = self.high.max(later.high);
+        self.low = self.low.min(later.low);
+        self.close = later.close;
+        self.volume += later.volume;
+    }
+}
+
+/// Add `candle` to `candles` (oldest first) under the `width` bucket it falls in.
+fn push_into(candles: &mut VecDeque<Candle>, candle: &Candle, width: u64) {
+    let start = candle.start - candle.start % width;
+    match candles.back_mut() {
+        Some(last) if last.start == start => last.merge(candle),
+        _ => candles.push_back(Candle {
+            start,
+            ..candle.clone()
+        }),
+    }
+}
+
+/// How long candles are kept at each width, in milliseconds.
+#[derive(Debug, Clone)]
+pub struct Retention {
+    pub minutes: u64,
+    pub hours: u64,
+    pub days: u64,
+}
+
+impl Retention {
+    pub fn from_env() -> Self {
+        let setting = |name: &str, default: u64| {
+            env::var(name)
+                .unwrap_or_else(|_| default.to_string())
+                .parse()
+                .unwrap_or(default)
+        };
+        Self {
+            minutes: setting("PRICE_HISTORY_MINUTE_RETENTION_HOURS", 24) * HOUR,
+            hours: setting("PRICE_HISTORY_HOUR_RETENTION_DAYS", 30) * DAY,
+            days: setting("PRICE_HISTORY_DAY_RETENTION_DAYS", 365) * DAY,
+        }
+    }
+
+    fn of_tier(&self, tier: usize) -> u64 {
+        [self.minutes, self.hours, self.days][tier]
+    }
+}
+
+/// Candles of one stock, one queue per tier, oldest first. A tier only holds candles newer
+/// than those of the tier after it.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Series {
+    tiers: [VecDeque<Candle>; 3],
+    last_price: Option<f64>, // Carried into candles of quotes with nothing offered
+    session_volume: Option<u64>, // Of the last quote, to turn it into per-candle volume
+}
+
+impl Series {
+    fn record(&mut self, timestamp: u64, price: Option<f64>, session_volume: Option<u64>) {
+        // The engine resets the session volume at midnight UTC
+        let volume = match (self.session_volume, session_volume) {
+            (Some(last), Some(volume)) if volume >= last => volume - last,
+            (Some(_), Some(volume)) => volume,
+            _ => 0,
+        };
+        self.session_volume = session_volume.or(self.session_volume);
+        if price.is_some() {
+            self.last_price = price;
+        }
+        let Some(price) = self.last_price else {
+            return; // Nothing to chart before the first offer
+        };
+
+        let start = timestamp - timestamp % MINUTE;
+        let minutes = &mut self.tiers[0];
+        match minutes.back_mut() {
+            Some(last) if last.start == start => last.update(price, volume),
+            Some(last) if last.start > start => {
+                match minutes.iter_mut().find(|candle| candle.start == start) {
+                    Some(candle) => candle.update(price, volume),
+                    None => debug!("Not recording a price from before the history"),
+                }
+            }
+            _ => {
+                let mut candle = Candle::new(start, price);
+                candle.volume = volume;
+                minutes.push_back(candle);
+            }
+        }
+    }
+
+    /// Fold candles older than their tier's retention into the next tier, dropping those
+    /// past the last one.
+    fn downsample(&mut self, now: u64, retention: &Retention) {
+        for (tier, width) in TIER_WIDTHS.iter().enumerate() {
+            let cutoff = now.saturating_sub(retention.of_tier(tier));
+            while let Some(candle) = self.tiers[tier].front() {
+                if candle.start + width > cutoff {
+                    break;
+                }
+                let candle = self.tiers[tier].pop_front().unwrap();
+                if let Some(coarser) = TIER_WIDTHS.get(tier + 1) {
+                    push_into(&mut self.tiers[tier + 1], &candle, *coarser);
+                }
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tiers.iter().all(VecDeque::is_empty)
+    }
+
+    /// Candles of `interval` width starting in `from..to`, from every tier fine enough for it.
+    fn candles(&self, interval: u64, from: u64, to: u64) -> Vec<Candle> {
+        let from = from - from % interval;
+        let mut candles = VecDeque::new();
+        // Coarsest first, as that is the oldest data
+        for (tier, width) in TIER_WIDTHS.iter().enumerate().rev() {
+            if !interval.is_multiple_of(*width) {
+                continue;
+            }
+            for candle in &self.tiers[tier] {
+                let start = candle.start - candle.start % interval;
+                if start >= from && start < to {
+                    push_into(&mut candles, candle, interval);
+                }
+            }
+        }
+        candles.into()
+    }
+}
+
+/// Bounded candle history of every stock, fed by the price consumer. Candles are kept per
+/// minute, then per hour, then per day as they age, and dropped after the daily retention.
+pub struct PriceHistory {
+    series: HashMap<String, Series>,
+    retention: Retention,
+    file: Option<PathBuf>, // Saved to and loaded from when set
+}
+
+impl PriceHistory {
+    /// History configured from the environment, loaded from `PRICE_HISTORY_FILE` if it is
+    /// set and the file exists.
+    pub fn from_env() -> Self {
+        let mut history = Self {
+            series: HashMap::new(),
+            retention: Retention::from_env(),
+            file: env::var("PRICE_HISTORY_FILE").ok().map(PathBuf::from),
+        };
+        if let Some(file) = &history.file {
+            match fs::read(file) {
+                Ok(content) => match serde_json::from_slice(&content) {
+                    Ok(series) => {
+                        history.series = series;
+                        info!(
+                            "Loaded price history of {} stocks from {}",
+                            history.series.len(),
+                            file.display()
+                        );
+                    }
+                    Err(e) => error!(
+                        "Ignoring unreadable price history {}: {}",
+                        file.display(),
+                        e
+                    ),
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    info!("No price history at {} yet", file.display());
+                }
+                Err(e) => error!("Failed to read price history {}: {}", file.display(), e),
+            }
+        }
+        history
+    }
+
+    pub fn record(
+        &mut self,
+        stock_id: &str,
+        timestamp: u64,
+        price: Option<f64>,
+        session_volume: Option<u64>,
+    ) {
+        self.series.entry(stock_id.to_string()).or_default().record(
+            timestamp,
+            price,
+            session_volume,
+        );
+    }
+
+    pub fn candles(&self, stock_id: &str, interval: u64, from: u64, to: u64) -> Vec<Candle> {
+        self.series
+            .get(stock_id)
+            .map(|series| series.candles(interval, from, to))
+            .unwrap_or_default()
+    }
+
+    pub fn downsample(&mut self, now: u64) {
+        let retention = self.retention.clone();
+        self.series.retain(|_, series| {
+            series.downsample(now, &retention);
+            !series.is_empty()
+        });
+    }
+
+    /// Write the history to its file, if it has one, replacing the previous one atomically.
+    /// Only serialising it holds the lock; the file is written after releasing it.
+    pub async fn save(history: &Mutex<Self>) {
+        let _saving = SAVING.lock().await;
+        let (file, content) = {
+            let history = history.lock().await;
+            let Some(file) = history.file.clone() else {
+                return;
+            };
+            (file, serde_json::to_vec(&history.series))
+        };
+        let tmp_file = file.with_extension("json.tmp");
+        let result = match content {
+            Ok(content) => match tokio::fs::write(&tmp_file, content).await {
+                Ok(()) => tokio::fs::rename(&tmp_file, &file).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(()) => debug!("Saved price history to {}", file.display()),
+            Err(e) => warn!("Failed to save price history to {}: {}", file.display(), e),
+        }
+    }
+
+    /// Downsample, and save if there is a file, every `PRICE_HISTORY_SAVE_INTERVAL_SECS`
+    /// (default 60).
+    pub fn spawn_maintenance(history: Arc<Mutex<Self>>) {
+        let interval_secs: u64 = env::var("PRICE_HISTORY_SAVE_INTERVAL_SECS")
+            .unwrap_or_else(|_| "60".to_string())
+            .parse()
+            .unwrap_or(60);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(interval_secs.max(1)));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                history.lock().await.downsample(now_millis());
+                Self::save(&history).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RETENTION: Retention = Retention {
+        minutes: HOUR,
+        hours: DAY,
+        days: 2 * DAY,
+    };
+
+    fn starts(candles: &VecDeque<Candle>) -> Vec<u64> {
+        candles.iter().map(|candle| candle.start).collect()
+    }
+
+    #[test]
+    fn volume_is_the_increase_of_the_session_volume() {
+        let mut series = Series::default();
+        series.record(0, Some(10.0), Some(100)); // First quote, nothing to compare with
+        series.record(1000, Some(11.0), Some(130));
+        series.record(2000, None, None); // Older producer, volume unknown
+        series.record(MINUTE, Some(9.0), Some(150));
+        series.record(MINUTE + 1000, Some(9.0), Some(20)); // Reset at midnight
+
+        let minutes = &series.tiers[0];
+        assert_eq!(minutes.len(), 2);
+        assert_eq!(minutes[0].volume, 30);
+        assert_eq!(minutes[1].volume, 20 + 20);
+    }
+
+    #[test]
+    fn quotes_with_nothing_offered_keep_the_last_price() {
+        let mut series = Series::default();
+        series.record(0, None, Some(5));
+        assert!(series.is_empty());
+
+        series.record(1000, Some(10.0), Some(5));
+        series.record(MINUTE, None, Some(8));
+        let minutes = &series.tiers[0];
+        assert_eq!(
+            minutes[1],
+            Candle {
+                start: MINUTE,
+                open: 10.0,
+                high: 10.0,
+                low: 10.0,
+                close: 10.0,
+                volume: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn downsample_moves_candles_at_the_tier_boundary() {
+        let mut series = Series::default();
+        let now = 10 * DAY;
+        let cutoff = now - RETENTION.minutes;
+        // Ends exactly at the cutoff, ends just after it
+        series.record(cutoff - MINUTE, Some(10.0), None);
+        series.record(cutoff, Some(11.0), None);
+        series.downsample(now, &RETENTION);
+
+        assert_eq!(starts(&series.tiers[0]), vec![cutoff]);
+        assert_eq!(starts(&series.tiers[1]), vec![cutoff - HOUR]);
+
+        // A day later both have gone through the hour tier into the day tier
+        series.downsample(now + DAY, &RETENTION);
+        assert!(series.tiers[0].is_empty());
+        assert!(series.tiers[1].is_empty());
+        assert_eq!(starts(&series.tiers[2]), vec![now - DAY]);
+        assert_eq!(series.tiers[2][0].close, 11.0);
+
+        // Dropped once the day ends past the last tier's retention
+        series.downsample(now + RETENTION.days - 1, &RETENTION);
+        assert!(!series.is_empty());
+        series.downsample(now + RETENTION.days, &RETENTION);
+        assert!(series.is_empty());
+    }
+
+    #[test]
+    fn candles_merge_into_the_interval_across_tiers() {
+        let mut series = Series::default();
+        series.record(0, Some(10.0), Some(0));
+        series.record(1000, Some(14.0), Some(4));
+        series.record(MINUTE, Some(8.0), Some(6));
+        series.record(2 * MINUTE, Some(12.0), Some(7));
+        series.record(HOUR, Some(20.0), Some(10));
+        // The first minute moves to the hour tier, the rest stays per minute
+        series.downsample(MINUTE + RETENTION.minutes, &RETENTION);
+        assert_eq!(starts(&series.tiers[1]), vec![0]);
+
+        let hours = series.candles(HOUR, 0, 2 * HOUR);
+        assert_eq!(
+            hours,
+            vec![
+                Candle {
+                    start: 0,
+                    open: 10.0,
+                    high: 14.0,
+                    low: 8.0,
+                    close: 12.0,
+                    volume: 7,
+                },
+                Candle {
+                    start: HOUR,
+                    open: 20.0,
+                    high: 20.0,
+                    low: 20.0,
+                    close: 20.0,
+                    volume: 3,
+                },
+            ]
+        );
+
+        // Minute candles only come from the minute tier
+        let minutes = series.candles(MINUTE, 0, HOUR);
+        assert_eq!(starts(&minutes.into()), vec![MINUTE, 2 * MINUTE]);
+        // The range is in interval starts
+        assert_eq!(series.candles(HOUR, 1000, HOUR).len(), 1);
+    }
+}
@@ -0,0 +1,174 @@
+use amqprs::{
+    BasicProperties, Deliver,
+    channel::{BasicPublishArguments, Channel},
+    consumer::AsyncConsumer,
+};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::{
+    collections::BTreeSet,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+use crate::consumer::{PriceConsumer, PriceUpdate};
+
+/// Shards that have not answered by then are asked again.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A shard's answer to a price snapshot request: the current quote of every stock it has
+/// seen, stamped so that prices already received from the stream are not overwritten.
+#[derive(Deserialize, Debug)]
+struct PriceSnapshot {
+    shard_id: u32,
+    prices: Vec<PriceUpdate>,
+}
+
+/// Where price snapshot requests go for the current RabbitMQ session.
+pub struct PriceSnapshotRequester {
+    pub channel: Channel,
+    pub exchange: String, // The price exchange, where each shard takes requests
+    pub reply_to: String, // This instance's price snapshot queue
+    pub instances: u32,   // Number of matching engine shards
+}
+
+impl PriceSnapshotRequester {
+    async fn request(&self, shard_id: u32) {
+        let routing_key = format!("price.snapshot_request.shard_{}", shard_id);
+        let properties = BasicProperties::default()
+            .with_content_type("application/json")
+            .with_message_type("price.snapshot_request")
+            .with_correlation_id(&format!("shard_{}", shard_id))
+            .with_reply_to(&self.reply_to)
+            .with_app_id("stock-price")
+            .finish();
+        let args = BasicPublishArguments::new(&self.exchange, &routing_key);
+        if let Err(e) = self
+            .channel
+            .basic_publish(properties, b"{}".to_vec(), args)
+            .await
+        {
+            error!(
+                "Failed to request price snapshot of shard {}: {}",
+                shard_id, e
+            );
+        } else {
+            debug!("Requested price snapshot on {}", routing_key);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Bootstrap {
+    requester: Option<PriceSnapshotRequester>,
+    session: u64,           // Sessions so far, so retries of an old one stop
+    pending: BTreeSet<u32>, // Shards that have not answered this session
+}
+
+/// Fills the prices from every matching engine shard whenever a RabbitMQ session starts, as
+/// the engine only publishes a price when it changes. The service is ready once every shard
+/// has answered; prices missed while disconnected are caught up the same way.
+#[derive(Clone)]
+pub struct PriceSnapshots {
+    prices: PriceConsumer,
+    bootstrap: Arc<Mutex<Bootstrap>>,
+    ready: Arc<AtomicBool>,
+}
+
+impl PriceSnapshots {
+    pub fn new(prices: PriceConsumer) -> Self {
+        Self {
+            prices,
+            bootstrap: Arc::new(Mutex::new(Bootstrap::default())),
+            ready: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether every shard has answered since the current session started.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// A new session started: ask every shard for its prices, and again every
+    /// `SNAPSHOT_TIMEOUT` those that have not answered yet.
+    pub async fn connected(&self, requester: PriceSnapshotRequester) {
+        let session = {
+            let mut bootstrap = self.bootstrap.lock().await;
+            self.ready.store(false, Ordering::SeqCst);
+            bootstrap.session += 1;
+            bootstrap.pending = (0..requester.instances.max(1)).collect();
+            for shard_id in &bootstrap.pending {
+                requester.request(*shard_id).await;
+            }
+            bootstrap.requester = Some(requester);
+            bootstrap.session
+        };
+
+        let bootstrap = Arc::clone(&self.bootstrap);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(SNAPSHOT_TIMEOUT).await;
+                let bootstrap = bootstrap.lock().await;
+                if bootstrap.session != session || bootstrap.pending.is_empty() {
+                    return;
+                }
+                let Some(requester) = &bootstrap.requester else {
+                    return;
+                };
+                warn!(
+                    "No price snapshot from shards {:?} yet, requesting it again",
+                    bootstrap.pending
+                );
+                for shard_id in &bootstrap.pending {
+                    requester.request(*shard_id).await;
+                }
+            }
+        });
+    }
+
+    async fn snapshot(&self, snapshot: PriceSnapshot) {
+        info!(
+            "Received price snapshot of {} stocks from shard {}",
+            snapshot.prices.len(),
+            snapshot.shard_id
+        );
+        for price in snapshot.prices {
+            self.prices.apply(price).await;
+        }
+
+        let mut bootstrap = self.bootstrap.lock().await;
+        if bootstrap.pending.remove(&snapshot.shard_id) && bootstrap.pending.is_empty() {
+            info!("Prices in sync with every matching engine shard, ready");
+            self.ready.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncConsumer for PriceSnapshots {
+    async fn consume(
+        &mut self,
+        _channel: &Channel,
+        _deliver: Deliver,
+        properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        let message_type = properties.message_type().cloned().unwrap_or_default();
+        if message_type != "price.snapshot" {
+            warn!(
+                "Ignoring unexpected message type '{}' on the price snapshot queue",
+                message_type
+            );
+            return;
+        }
+        match serde_json::from_slice(&content) {
+            Ok(snapshot) => self.snapshot(snapshot).await,
+            Err(e) => error!("Failed to deserialize price snapshot: {}", e),
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use axum::{
+    extract::{
+        Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use futures_util::{SinkExt, StreamExt, stream::SplitSink};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{Notify, RwLock, broadcast};
+use tracing::{debug, info, warn};
+
+use crate::jwt_middleware::{TokenQuery, verify_request};
+use crate::state::{AppState, PriceChange, PriceEvent, StockPrice};
+
+/// Replies a client may leave unread before its connection is closed.
+const MAX_QUEUED_REPLIES: usize = 64;
+
+/// Fans price changes out from the consumer to every streaming connection.
+pub type PriceFeed = broadcast::Sender<PriceChange>;
+
+/// A feed buffering up to `PRICE_STREAM_BUFFER` (default 1024) events per connection.
+pub fn price_feed() -> PriceFeed {
+    let capacity: usize = env::var("PRICE_STREAM_BUFFER")
+        .unwrap_or_else(|_| "1024".to_string())
+        .parse()
+        .unwrap_or(1024);
+    broadcast::channel(capacity.max(1)).0
+}
+
+#[derive(Clone)]
+pub struct PriceStreamState {
+    pub prices: Arc<RwLock<AppState>>,
+    pub feed: PriceFeed,
+}
+
+/// A message from the client.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe {
+        #[serde(default)]
+        stock_ids: Vec<String>,
+        #[serde(default)]
+        all: bool,
+    },
+    Unsubscribe {
+        #[serde(default)]
+        stock_ids: Vec<String>,
+        #[serde(default)]
+        all: bool,
+    },
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    all: bool,
+    stock_ids: HashSet<String>,
+}
+
+impl Subscriptions {
+    fn includes(&self, stock_id: &str) -> bool {
+        self.all || self.stock_ids.contains(stock_id)
+    }
+}
+
+/// What is waiting to be written to a client. Replies go out in order, then a pending
+/// snapshot; of the price events only the latest per stock is kept, so a client that cannot
+/// keep up skips intermediate prices instead of falling further behind.
+#[derive(Default)]
+struct Outgoing {
+    replies: VecDeque<String>,
+    snapshot: Option<String>, // Replaces every price the client has
+    events: HashMap<String, PriceEvent>, // By stock id
+    order: VecDeque<String>,  // Stock ids with an event, oldest first
+    closed: bool,
+}
+
+impl Outgoing {
+    fn push_event(&mut self, event: PriceEvent) {
+        let stock_id = event.stock_id().to_string();
+        if self.events.insert(stock_id.clone(), event).is_none() {
+            self.order.push_back(stock_id);
+        }
+    }
+
+    /// Replace everything not yet sent but the replies with a snapshot.
+    fn set_snapshot(&mut self, snapshot: String) {
+        self.snapshot = Some(snapshot);
+        self.events.clear();
+        self.order.clear();
+    }
+
+    /// Drop the events of stocks the client is no longer subscribed to.
+    fn retain(&mut self, subscriptions: &Subscriptions) {
+        self.events
+            .retain(|stock_id, _| subscriptions.includes(stock_id));
+        let events = &self.events;
+        self.order.retain(|stock_id| events.contains_key(stock_id));
+    }
+
+    fn pop(&mut self) -> Option<String> {
+        if let Some(reply) = self.replies.pop_front() {
+            return Some(reply);
+        }
+        if let Some(snapshot) = self.snapshot.take() {
+            return Some(snapshot);
+        }
+        let stock_id = self.order.pop_front()?;
+        let event = self.events.remove(&stock_id)?;
+        serde_json::to_string(&event).ok()
+    }
+}
+
+/// `GET /stockPrices/ws`: a WebSocket streaming price changes of the stocks the client
+/// subscribed to. The token goes in the `token` header or query parameter.
+pub async fn price_stream(
+    State(state): State<PriceStreamState>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let claims = match verify_request(&headers, &query) {
+        Ok(claims) => claims,
+        Err(response) => return response.into_response(),
+    };
+
+    info!("Opening price stream for {}", claims.username);
+    upgrade.on_upgrade(move |socket| stream_prices(socket, state, claims.username))
+}
+
+async fn stream_prices(socket: WebSocket, state: PriceStreamState, username: String) {
+    let (sender, mut receiver) = socket.split();
+    let mut feed = state.feed.subscribe();
+    let outgoing = Arc::new(Mutex::new(Outgoing::default()));
+    let ready = Arc::new(Notify::new());
+    let writer = tokio::spawn(write_outgoing(
+        sender,
+        Arc::clone(&outgoing),
+        Arc::clone(&ready),
+    ));
+    let mut subscriptions = Subscriptions::default();
+
+    loop {
+        tokio::select! {
+            message = receiver.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => continue, // Pings are answered by axum
+                    Some(Err(e)) => {
+                        debug!("Price stream of {} failed: {}", username, e);
+                        break;
+                    }
+                };
+                let reply = match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(message) => {
+                        subscribe(&state, &mut subscriptions, message, &outgoing).await;
+                        json!({
+                            "type": "subscriptions",
+                            "data": {
+                                "all": subscriptions.all,
+                                "stock_ids": subscriptions.stock_ids,
+                            }
+                        })
+                    }
+                    Err(e) => json!({
+                        "type": "error",
+                        "data": { "error": format!("Invalid message: {}", e) }
+                    }),
+                };
+                let queued = {
+                    let mut outgoing = outgoing.lock().unwrap();
+                    outgoing.replies.push_back(reply.to_string());
+                    outgoing.replies.len()
+                };
+                ready.notify_one();
+                if queued > MAX_QUEUED_REPLIES {
+                    warn!(
+                        "Price stream of {} left {} replies unread, closing it",
+                        username, queued
+                    );
+                    break;
+                }
+            }
+            change = feed.recv() => match change {
+                Ok(PriceChange { event, .. }) => {
+                    if subscriptions.includes(event.stock_id()) {
+                        outgoing.lock().unwrap().push_event(event);
+                        ready.notify_one();
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    // Too slow even to conflate; removals may have been missed as well, so
+                    // catch up from a snapshot of the current prices
+                    warn!(
+                        "Price stream of {} missed {} events, sending a snapshot",
+                        username, missed
+                    );
+                    send_snapshot(&state, &subscriptions, &outgoing).await;
+                    ready.notify_one();
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        }
+    }
+
+    outgoing.lock().unwrap().closed = true;
+    ready.notify_one();
+    let _ = writer.await;
+    info!("Closed price stream of {}", username);
+}
+
+/// Apply a subscription change, queueing the current price of each newly added stock.
+async fn subscribe(
+    state: &PriceStreamState,
+    subscriptions: &mut Subscriptions,
+    message: ClientMessage,
+    outgoing: &Mutex<Outgoing>,
+) {
+    match message {
+        ClientMessage::Subscribe { stock_ids, all } => {
+            let added: HashSet<String> = stock_ids
+                .into_iter()
+                .filter(|stock_id| !subscriptions.includes(stock_id))
+                .collect();
+            let was_all = subscriptions.all;
+            subscriptions.all |= all;
+            subscriptions.stock_ids.extend(added.iter().cloned());
+            let only = if all && !was_all { None } else { Some(&added) };
+            send_current(state, subscriptions, only, outgoing).await;
+        }
+        ClientMessage::Unsubscribe { stock_ids, all } => {
+            if all {
+                subscriptions.all = false;
+                subscriptions.stock_ids.clear();
+            }
+            for stock_id in &stock_ids {
+                subscriptions.stock_ids.remove(stock_id);
+            }
+            outgoing.lock().unwrap().retain(subscriptions);
+        }
+    }
+}
+
+/// Queue the current price of every subscribed stock, or only of those in `only`.
+async fn send_current(
+    state: &PriceStreamState,
+    subscriptions: &Subscriptions,
+    only: Option<&HashSet<String>>,
+    outgoing: &Mutex<Outgoing>,
+) {
+    let prices = state.prices.read().await;
+    let mut outgoing = outgoing.lock().unwrap();
+    for price in prices.stock_prices.values() {
+        let wanted = match only {
+            Some(stock_ids) => stock_ids.contains(&price.stock_id),
+            None => subscriptions.includes(&price.stock_id),
+        };
+        if wanted {
+            outgoing.push_event(PriceEvent::Price(price.clone()));
+        }
+    }
+}
+
+/// Queue a snapshot of the prices of every subscribed stock, superseding the queued events.
+async fn send_snapshot(
+    state: &PriceStreamState,
+    subscriptions: &Subscriptions,
+    outgoing: &Mutex<Outgoing>,
+) {
+    let prices = state.prices.read().await;
+    let snapshot: Vec<&StockPrice> = prices
+        .stock_prices
+        .values()
+        .filter(|price| subscriptions.includes(&price.stock_id))
+        .collect();
+    let snapshot = json!({ "type": "snapshot", "data": snapshot }).to_string();
+    outgoing.lock().unwrap().set_snapshot(snapshot);
+}
+
+/// Write queued messages to the client as fast as it takes them.
+async fn write_outgoing(
+    mut sender: SplitSink<WebSocket, Message>,
+    outgoing: Arc<Mutex<Outgoing>>,
+    ready: Arc<Notify>,
+) {
+    loop {
+        ready.notified().await;
+        loop {
+            let (next, closed) = {
+                let mut outgoing = outgoing.lock().unwrap();
+                (outgoing.pop(), outgoing.closed)
+            };
+            match next {
+                Some(text) => {
+                    if sender.send(Message::Text(text.into())).await.is_err() {
+                        return;
+                    }
+                }
+                None if closed => {
+                    let _ = sender.close().await;
+                    return;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn removed(stock_id: &str) -> PriceEvent {
+        PriceEvent::Removed {
+            stock_id: stock_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn snapshot_supersedes_queued_events_but_not_replies() {
+        let mut outgoing = Outgoing::default();
+        outgoing.replies.push_back("reply".to_string());
+        outgoing.push_event(removed("appl"));
+        outgoing.set_snapshot("first".to_string());
+        outgoing.set_snapshot("second".to_string());
+        outgoing.push_event(removed("goog"));
+
+        assert_eq!(outgoing.pop().as_deref(), Some("reply"));
+        assert_eq!(outgoing.pop().as_deref(), Some("second"));
+        let event = outgoing.pop().unwrap();
+        assert!(event.contains("goog"), "{}", event);
+        assert_eq!(outgoing.pop(), None);
+    }
+}
@@ -1,82 +1,575 @@
-use tracing::{debug, error, info};
 use amqprs::{
-    callbacks::{DefaultChannelCallback, DefaultConnectionCallback},
-    channel::{QueueBindArguments, QueueDeclareArguments},
+    Ack, BasicProperties, Cancel, Close, CloseChannel, FieldTable, FieldValue, Nack, Return,
+    callbacks::{ChannelCallback, ConnectionCallback},
+    channel::{
+        BasicCancelArguments, BasicConsumeArguments, Channel, ConfirmSelectArguments,
+        ExchangeDeclareArguments, QueueBindArguments, QueueDeclareArguments,
+    },
     connection::{Connection, OpenConnectionArguments},
 };
+use async_trait::async_trait;
+use serde::Serialize;
+use shared_rabbitmq::{TopologyMismatch, backoff_delay, declare};
+use std::{env, sync::Arc};
+use tokio::sync::{Mutex, Notify, mpsc, watch};
+use tracing::{debug, error, info, warn};
 
-use std::env;
-
-pub async fn setup_rabbitmq(
-    exchange_name: &str,
-    queue_name: &str,
-    binding_key: &str,
-) -> (Connection, amqprs::channel::Channel) {
-    // Retrieve RabbitMQ configuration
-    let host = env::var("RABBITMQ_HOST").unwrap_or_else(|_| "localhost".to_string());
-    let port = env::var("RABBITMQ_PORT")
-        .unwrap_or_else(|_| "5672".to_string())
-        .parse()
-        .unwrap_or(5672);
-    let username = env::var("RABBITMQ_USERNAME").unwrap_or_else(|_| "guest".to_string());
-    let password = env::var("RABBITMQ_PASSWORD").unwrap_or_else(|_| "guest".to_string());
-
-    // Open connection
-    info!("Connecting to RabbitMQ at {}:{}", host, port);
-    let connection = match Connection::open(&OpenConnectionArguments::new(
-        &host, port, &username, &password,
-    ))
-    .await {
-        Ok(conn) => {
-            debug!("Successfully established RabbitMQ connection");
-            conn
+use crate::consumer::PriceConsumer;
+use crate::dead_letter::{Confirm, DeadLetterChannel};
+use crate::order_book::{BookConsumer, MARKET_DATA_EXCHANGE, SnapshotRequester};
+use crate::price_snapshot::{PriceSnapshotRequester, PriceSnapshots};
+
+/// Exchange shared with the matching engine for messages that could not be processed.
+pub const DEAD_LETTER_EXCHANGE: &str = "dead_letter_exchange";
+/// Dead-letter queue of this service; also the routing key its dead letters are published with.
+pub const DEAD_LETTER_QUEUE: &str = "stock_price_dead_letter_queue";
+
+/// How the price exchange and queue are declared. Exchanges that already exist on the
+/// broker must have been declared the same way, otherwise the supervisor stops with a
+/// `TopologyMismatch` instead of retrying. The price queue is this instance's own, so it is
+/// never durable and cannot be a quorum queue.
+#[derive(Debug, Clone, Default)]
+pub struct TopologyConfig {
+    pub durable_exchanges: bool, // Shared with the matching engine, they have to agree
+    pub message_ttl_ms: Option<u32>, // Expired prices are dropped
+    pub max_length: Option<u32>, // The oldest prices are dropped beyond this
+}
+
+impl TopologyConfig {
+    fn from_env() -> Result<Self, String> {
+        match env::var("RABBITMQ_QUEUE_TYPE").as_deref() {
+            Ok("classic") | Err(_) => {}
+            Ok("quorum") => {
+                return Err(
+                    "the price queue is exclusive to each instance and cannot be a quorum queue"
+                        .to_string(),
+                );
+            }
+            Ok(other) => return Err(format!("unknown RABBITMQ_QUEUE_TYPE '{}'", other)),
         }
-        Err(e) => {
-            error!("Failed to connect to RabbitMQ: {}", e);
-            panic!("Failed to connect to RabbitMQ: {}", e);
+        Ok(Self {
+            durable_exchanges: env::var("RABBITMQ_DURABLE_EXCHANGES")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .unwrap_or(false),
+            message_ttl_ms: env::var("RABBITMQ_MESSAGE_TTL_MS")
+                .ok()
+                .and_then(|ttl| ttl.parse().ok()),
+            max_length: env::var("RABBITMQ_MAX_LENGTH")
+                .ok()
+                .and_then(|max_length| max_length.parse().ok()),
+        })
+    }
+
+    fn queue_arguments(&self) -> FieldTable {
+        let mut arguments = FieldTable::new();
+        let mut set = |name: &str, value: FieldValue| {
+            if let Ok(name) = name.try_into() {
+                arguments.insert(name, value);
+            }
+        };
+        if let Some(ttl) = self.message_ttl_ms {
+            set("x-message-ttl", FieldValue::l(ttl as i64));
         }
-    };
-    connection
-        .register_callback(DefaultConnectionCallback)
-        .await
-        .unwrap();
-
-    // Open channel
-    debug!("Opening RabbitMQ channel");
-    let channel = match connection.open_channel(None).await {
-        Ok(ch) => {
-            debug!("Successfully opened RabbitMQ channel");
-            ch
+        if let Some(max_length) = self.max_length {
+            set("x-max-length", FieldValue::l(max_length as i64));
         }
-        Err(e) => {
-            error!("Failed to open RabbitMQ channel: {}", e);
-            panic!("Failed to open RabbitMQ channel: {}", e);
+        arguments
+    }
+}
+
+pub struct RabbitMQConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    pub exchange_name: String,
+    pub binding_key: String,
+    pub consumer_tag: String,
+    pub reconnect_initial_delay_ms: u64,
+    pub reconnect_max_delay_ms: u64,
+    pub topology: TopologyConfig,
+    pub me_instances: u32, // Matching engine shards, to route snapshot requests
+}
+
+impl RabbitMQConfig {
+    pub fn from_env(
+        exchange_name: &str,
+        binding_key: &str,
+        consumer_tag: &str,
+    ) -> Result<Self, String> {
+        // Retrieve RabbitMQ configuration
+        Ok(Self {
+            host: env::var("RABBITMQ_HOST").unwrap_or_else(|_| "localhost".to_string()),
+            port: env::var("RABBITMQ_PORT")
+                .unwrap_or_else(|_| "5672".to_string())
+                .parse()
+                .unwrap_or(5672),
+            username: env::var("RABBITMQ_USERNAME").unwrap_or_else(|_| "guest".to_string()),
+            password: env::var("RABBITMQ_PASSWORD").unwrap_or_else(|_| "guest".to_string()),
+            exchange_name: exchange_name.to_string(),
+            binding_key: binding_key.to_string(),
+            consumer_tag: consumer_tag.to_string(),
+            reconnect_initial_delay_ms: env::var("RABBITMQ_RECONNECT_INITIAL_DELAY_MS")
+                .unwrap_or_else(|_| "500".to_string())
+                .parse()
+                .unwrap_or(500),
+            reconnect_max_delay_ms: env::var("RABBITMQ_RECONNECT_MAX_DELAY_MS")
+                .unwrap_or_else(|_| "30000".to_string())
+                .parse()
+                .unwrap_or(30_000),
+            topology: TopologyConfig::from_env()?,
+            // Required, as a wrong count leaves the service unready or books unsynced without
+            // any other sign of what is wrong; it must match the order manager's
+            me_instances: env::var("ME_INSTANCES")
+                .map_err(|_| "ME_INSTANCES must be set to the number of matching engine shards")?
+                .parse()
+                .ok()
+                .filter(|instances| *instances > 0)
+                .ok_or("ME_INSTANCES must be a positive number")?,
+        })
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Reconnecting,
+    Failed, // Declarations do not match the broker, not retried
+    Closed,
+}
+
+/// Connection, channel and consumers that live and die together. A new session is
+/// opened by the supervisor every time the connection or channel is lost.
+struct Session {
+    connection: Connection,
+    channel: Channel,
+    consumer_tags: Vec<String>, // Prices, book updates, then price snapshots
+    lost: Arc<Notify>,
+}
+
+/// Logs broker-initiated closes and wakes the supervisor so it can reconnect. On the
+/// dead-letter channel it also passes on publisher confirms.
+struct SessionCallback {
+    lost: Arc<Notify>,
+    closed: Arc<std::sync::Mutex<Option<(u16, String)>>>, // Reply code and text of a channel close
+    confirms: Option<mpsc::UnboundedSender<Confirm>>,
+}
+
+#[async_trait]
+impl ConnectionCallback for SessionCallback {
+    async fn close(
+        &mut self,
+        connection: &Connection,
+        close: Close,
+    ) -> Result<(), amqprs::error::Error> {
+        warn!("Connection {} closed by broker: {}", connection, close);
+        self.lost.notify_one();
+        Ok(())
+    }
+
+    async fn blocked(&mut self, connection: &Connection, reason: String) {
+        warn!("Connection {} blocked by broker: {}", connection, reason);
+    }
+
+    async fn unblocked(&mut self, connection: &Connection) {
+        info!("Connection {} unblocked", connection);
+    }
+}
+
+#[async_trait]
+impl ChannelCallback for SessionCallback {
+    async fn close(
+        &mut self,
+        channel: &Channel,
+        close: CloseChannel,
+    ) -> Result<(), amqprs::error::Error> {
+        warn!("Channel {} closed by broker: {}", channel, close);
+        *self.closed.lock().unwrap() = Some((close.reply_code(), close.reply_text().clone()));
+        self.lost.notify_one();
+        Ok(())
+    }
+
+    async fn cancel(
+        &mut self,
+        channel: &Channel,
+        cancel: Cancel,
+    ) -> Result<(), amqprs::error::Error> {
+        warn!(
+            "Consumer {} cancelled by broker on channel {}",
+            cancel.consumer_tag(),
+            channel
+        );
+        self.lost.notify_one();
+        Ok(())
+    }
+
+    async fn flow(
+        &mut self,
+        channel: &Channel,
+        active: bool,
+    ) -> Result<bool, amqprs::error::Error> {
+        info!("Flow control on channel {}: active={}", channel, active);
+        Ok(true)
+    }
+
+    async fn publish_ack(&mut self, _: &Channel, ack: Ack) {
+        if let Some(confirms) = &self.confirms {
+            let _ = confirms.send((ack.delivery_tag(), ack.mutiple(), true));
+        }
+    }
+
+    async fn publish_nack(&mut self, _: &Channel, nack: Nack) {
+        if let Some(confirms) = &self.confirms {
+            let _ = confirms.send((nack.delivery_tag(), nack.multiple(), false));
         }
-    };
-    channel
-        .register_callback(DefaultChannelCallback)
-        .await
-        .unwrap();
-
-    // Setup queue and binding
-    channel
-        .queue_declare(QueueDeclareArguments::new(queue_name))
-        .await
-        .unwrap();
-
-    channel
-        .queue_bind(QueueBindArguments::new(
-            queue_name,
-            exchange_name,
-            binding_key,
+    }
+
+    async fn publish_return(&mut self, _: &Channel, _: Return, _: BasicProperties, _: Vec<u8>) {}
+}
+
+/// Keeps the price and book consumers attached to RabbitMQ, reconnecting with exponential backoff
+/// whenever the connection or channel is lost.
+pub struct RabbitMQSupervisor {
+    config: RabbitMQConfig,
+    session: Mutex<Option<Session>>,
+    state: watch::Sender<ConnectionState>,
+    shutdown: watch::Sender<bool>,
+    failure: watch::Sender<Option<String>>,
+}
+
+impl RabbitMQSupervisor {
+    pub fn new(config: RabbitMQConfig) -> Self {
+        Self {
+            config,
+            session: Mutex::new(None),
+            state: watch::Sender::new(ConnectionState::Connecting),
+            shutdown: watch::Sender::new(false),
+            failure: watch::Sender::new(None),
+        }
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.state.borrow()
+    }
+
+    /// Resolves with the reason once the supervisor has given up for good.
+    pub async fn failed(&self) -> String {
+        let mut failure = self.failure.subscribe();
+        let reason = failure
+            .wait_for(Option::is_some)
+            .await
+            .ok()
+            .and_then(|reason| reason.clone());
+        match reason {
+            Some(reason) => reason,
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn spawn(
+        self: &Arc<Self>,
+        consumer: PriceConsumer,
+        books: BookConsumer,
+        snapshots: PriceSnapshots,
+    ) {
+        let supervisor = Arc::clone(self);
+        tokio::spawn(async move { supervisor.supervise(consumer, books, snapshots).await });
+    }
+
+    async fn supervise(
+        &self,
+        consumer: PriceConsumer,
+        books: BookConsumer,
+        snapshots: PriceSnapshots,
+    ) {
+        let mut shutdown = self.shutdown.subscribe();
+        let mut attempt: u32 = 0;
+
+        while !*shutdown.borrow() {
+            match self
+                .open_session(consumer.clone(), books.clone(), snapshots.clone())
+                .await
+            {
+                Ok(session) => {
+                    attempt = 0;
+                    let connection = session.connection.clone();
+                    let lost = Arc::clone(&session.lost);
+                    *self.session.lock().await = Some(session);
+                    self.state.send_replace(ConnectionState::Connected);
+                    info!(
+                        "Successfully connected to exchange '{}'",
+                        self.config.exchange_name
+                    );
+
+                    tokio::select! {
+                        _ = connection.listen_network_io_failure() => {}
+                        _ = lost.notified() => {}
+                        _ = shutdown.changed() => {}
+                    }
+                    if *shutdown.borrow() {
+                        break;
+                    }
+
+                    warn!("Lost RabbitMQ connection, reconnecting");
+                    self.state.send_replace(ConnectionState::Reconnecting);
+                    if let Some(session) = self.session.lock().await.take() {
+                        // Best effort, the broker may already be gone
+                        let _ = session.channel.close().await;
+                        let _ = session.connection.close().await;
+                    }
+                }
+                Err(e) if e.is::<TopologyMismatch>() => {
+                    error!("RabbitMQ declarations do not match the broker: {}", e);
+                    self.state.send_replace(ConnectionState::Failed);
+                    self.failure.send_replace(Some(e.to_string()));
+                    break;
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to connect to RabbitMQ at {}:{}: {}",
+                        self.config.host, self.config.port, e
+                    );
+                }
+            }
+
+            let delay = backoff_delay(
+                self.config.reconnect_initial_delay_ms,
+                self.config.reconnect_max_delay_ms,
+                attempt,
+            );
+            attempt = attempt.saturating_add(1);
+            info!("Retrying RabbitMQ connection in {:?}", delay);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.changed() => {}
+            }
+        }
+
+        debug!("RabbitMQ supervisor stopped");
+    }
+
+    async fn open_session(
+        &self,
+        consumer: PriceConsumer,
+        books: BookConsumer,
+        snapshots: PriceSnapshots,
+    ) -> Result<Session, Box<dyn std::error::Error + Send + Sync>> {
+        let config = &self.config;
+        let topology = &config.topology;
+        let lost = Arc::new(Notify::new());
+        let closed = Arc::new(std::sync::Mutex::new(None));
+
+        // Open connection
+        info!("Connecting to RabbitMQ at {}:{}", config.host, config.port);
+        let connection = Connection::open(&OpenConnectionArguments::new(
+            &config.host,
+            config.port,
+            &config.username,
+            &config.password,
         ))
-        .await
-        .unwrap();
+        .await?;
+        debug!("Successfully established RabbitMQ connection");
+        connection
+            .register_callback(SessionCallback {
+                lost: Arc::clone(&lost),
+                closed: Arc::clone(&closed),
+                confirms: None,
+            })
+            .await?;
+
+        // Open channel
+        debug!("Opening RabbitMQ channel");
+        let channel = connection.open_channel(None).await?;
+        debug!("Successfully opened RabbitMQ channel");
+        channel
+            .register_callback(SessionCallback {
+                lost: Arc::clone(&lost),
+                closed: Arc::clone(&closed),
+                confirms: None,
+            })
+            .await?;
+
+        // Declare the exchange too (same arguments as the matching engine) so binding
+        // does not depend on which service starts first
+        declare(
+            format!(
+                "exchange '{}' (durable={})",
+                config.exchange_name, topology.durable_exchanges
+            ),
+            &closed,
+            channel.exchange_declare(
+                ExchangeDeclareArguments::new(&config.exchange_name, "topic")
+                    .durable(topology.durable_exchanges)
+                    .finish(),
+            ),
+        )
+        .await?;
+
+        // Durable exchange and queue for price messages that could not be processed
+        declare(
+            format!("exchange '{}' (durable=true)", DEAD_LETTER_EXCHANGE),
+            &closed,
+            channel.exchange_declare(
+                ExchangeDeclareArguments::new(DEAD_LETTER_EXCHANGE, "direct")
+                    .durable(true)
+                    .finish(),
+            ),
+        )
+        .await?;
+        declare(
+            format!("queue '{}' (durable=true)", DEAD_LETTER_QUEUE),
+            &closed,
+            channel.queue_declare(
+                QueueDeclareArguments::new(DEAD_LETTER_QUEUE)
+                    .durable(true)
+                    .finish(),
+            ),
+        )
+        .await?;
+        channel
+            .queue_bind(QueueBindArguments::new(
+                DEAD_LETTER_QUEUE,
+                DEAD_LETTER_EXCHANGE,
+                DEAD_LETTER_QUEUE,
+            ))
+            .await?;
+
+        // Dead letters get a channel of their own in confirm mode, so the confirms on it
+        // are only theirs
+        let (confirms, confirmed) = mpsc::unbounded_channel();
+        let dead_letter_channel = connection.open_channel(None).await?;
+        dead_letter_channel
+            .register_callback(SessionCallback {
+                lost: Arc::clone(&lost),
+                closed: Arc::clone(&closed),
+                confirms: Some(confirms),
+            })
+            .await?;
+        dead_letter_channel
+            .confirm_select(ConfirmSelectArguments::default())
+            .await?;
+        let consumer =
+            consumer.with_dead_letters(DeadLetterChannel::new(dead_letter_channel, confirmed));
+
+        // Every instance gets every price on a queue of its own, which goes away with the
+        // connection. Prices published while it was gone are caught up from the snapshots.
+        let (price_queue, _, _) = channel
+            .queue_declare(
+                QueueDeclareArguments::exclusive_server_named()
+                    .arguments(topology.queue_arguments())
+                    .finish(),
+            )
+            .await?
+            .ok_or("price queue declaration returned no result")?;
+        channel
+            .queue_bind(QueueBindArguments::new(
+                &price_queue,
+                &config.exchange_name,
+                &config.binding_key,
+            ))
+            .await?;
 
-    info!(
-        "Successfully connected to exchange '{}', consuming from queue '{}'",
-        exchange_name, queue_name
-    );
+        let mut consume_args = BasicConsumeArguments::new(&price_queue, &config.consumer_tag);
+        consume_args.manual_ack(true);
+        let consumer_tag = channel.basic_consume(consumer, consume_args).await?;
+        info!("Consuming prices from queue '{}'", price_queue);
 
-    (connection, channel)
+        // Book updates and the replies to our snapshot requests share a queue of this
+        // instance's own, which goes away with the connection
+        declare(
+            format!(
+                "exchange '{}' (durable={})",
+                MARKET_DATA_EXCHANGE, topology.durable_exchanges
+            ),
+            &closed,
+            channel.exchange_declare(
+                ExchangeDeclareArguments::new(MARKET_DATA_EXCHANGE, "topic")
+                    .durable(topology.durable_exchanges)
+                    .finish(),
+            ),
+        )
+        .await?;
+        let (book_queue, _, _) = channel
+            .queue_declare(QueueDeclareArguments::exclusive_server_named())
+            .await?
+            .ok_or("book queue declaration returned no result")?;
+        channel
+            .queue_bind(QueueBindArguments::new(
+                &book_queue,
+                MARKET_DATA_EXCHANGE,
+                "book.update.*",
+            ))
+            .await?;
+        let mut book_consume_args = BasicConsumeArguments::new(&book_queue, "stock_price_books");
+        book_consume_args.manual_ack(false);
+        let book_consumer_tag = channel
+            .basic_consume(books.clone(), book_consume_args)
+            .await?;
+        books
+            .books
+            .lock()
+            .await
+            .connected(SnapshotRequester {
+                channel: channel.clone(),
+                reply_to: book_queue,
+                instances: config.me_instances,
+            })
+            .await;
+
+        // Replies to our price snapshot requests, asked for once prices are consumed so
+        // none falls between a snapshot and the stream
+        let (snapshot_queue, _, _) = channel
+            .queue_declare(QueueDeclareArguments::exclusive_server_named())
+            .await?
+            .ok_or("price snapshot queue declaration returned no result")?;
+        let mut snapshot_consume_args =
+            BasicConsumeArguments::new(&snapshot_queue, "stock_price_snapshots");
+        snapshot_consume_args.manual_ack(false);
+        let snapshot_consumer_tag = channel
+            .basic_consume(snapshots.clone(), snapshot_consume_args)
+            .await?;
+        snapshots
+            .connected(PriceSnapshotRequester {
+                channel: channel.clone(),
+                exchange: config.exchange_name.clone(),
+                reply_to: snapshot_queue,
+                instances: config.me_instances,
+            })
+            .await;
+
+        Ok(Session {
+            connection,
+            channel,
+            consumer_tags: vec![consumer_tag, book_consumer_tag, snapshot_consumer_tag],
+            lost,
+        })
+    }
+
+    /// Stop the supervisor, cancel the consumers and close the channel and connection.
+    pub async fn close(&self) {
+        self.shutdown.send_replace(true);
+        self.state.send_replace(ConnectionState::Closed);
+
+        let Some(session) = self.session.lock().await.take() else {
+            return;
+        };
+        for consumer_tag in &session.consumer_tags {
+            if let Err(e) = session
+                .channel
+                .basic_cancel(BasicCancelArguments::new(consumer_tag))
+                .await
+            {
+                error!("Failed to cancel consumer {}: {}", consumer_tag, e);
+            }
+        }
+        if let Err(e) = session.channel.close().await {
+            error!("Failed to close RabbitMQ channel: {}", e);
+        }
+        if let Err(e) = session.connection.close().await {
+            error!("Failed to close RabbitMQ connection: {}", e);
+        }
+    }
 }
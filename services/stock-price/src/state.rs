@@ -1,17 +1,72 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Changes kept for streaming clients that reconnect.
+const REPLAY_CHANGES: usize = 1000;
 
 // Stock prices types
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TradingStatus {
+    #[default]
+    Active, // Has sell orders
+    ActiveNoQuotes, // Listed, but nothing is offered right now
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct StockPrice {
     pub stock_id: String,
-    pub current_price: i64,
+    pub current_price: Option<i64>, // Best ask rounded, kept for older clients; null if nothing is offered
     pub stock_name: String,
+    #[serde(default)]
+    pub trading_status: TradingStatus,
+    pub best_ask: Option<f64>,
+    #[serde(default)]
+    pub best_ask_size: u64,
+    pub best_bid: Option<f64>,
+    #[serde(default)]
+    pub best_bid_size: u64,
+    pub last_trade_price: Option<f64>,
+    pub last_trade_size: Option<u64>,
+    pub last_trade_time: Option<u64>,
+    #[serde(default)]
+    pub session_volume: u64,
+}
+
+/// A change to `/stockPrices`, as pushed to streaming clients.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum PriceEvent {
+    Price(StockPrice),
+    Removed { stock_id: String }, // Gone, as far as an older producer can say
+}
+
+impl PriceEvent {
+    pub fn stock_id(&self) -> &str {
+        match self {
+            PriceEvent::Price(price) => &price.stock_id,
+            PriceEvent::Removed { stock_id } => stock_id,
+        }
+    }
+}
+
+/// A `PriceEvent` numbered in the order the changes were applied.
+#[derive(Serialize, Debug, Clone)]
+pub struct PriceChange {
+    pub id: u64,
+    pub event: PriceEvent,
 }
 
 #[derive(Serialize, Debug, Default, Clone)]
 pub struct AppState {
     pub stock_prices: HashMap<String, StockPrice>,
+    pub price_versions: HashMap<String, (u64, u64)>, // (timestamp, stock_seq) of the latest price per stock
+    pub epoch: u64,     // Start of this process (Unix milliseconds), so change ids of an earlier one are not mistaken for ours
+    pub change_id: u64, // Id of the last change
+    pub recent_changes: VecDeque<PriceChange>, // The last REPLAY_CHANGES changes, oldest first
 }
 
 #[derive(Serialize, Debug)]
@@ -22,6 +77,47 @@ pub struct StockPricesResponse {
 
 impl AppState {
     pub fn new() -> Self {
-        Self::default()
+        Self {
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as u64)
+                .unwrap_or(0),
+            ..Self::default()
+        }
+    }
+
+    /// Number the change and remember it for replay.
+    pub fn record_change(&mut self, event: PriceEvent) -> PriceChange {
+        self.change_id += 1;
+        let change = PriceChange {
+            id: self.change_id,
+            event,
+        };
+        self.recent_changes.push_back(change.clone());
+        while self.recent_changes.len() > REPLAY_CHANGES {
+            self.recent_changes.pop_front();
+        }
+        change
+    }
+
+    /// The changes after `id`, or `None` if some of them are no longer kept.
+    pub fn changes_since(&self, id: u64) -> Option<Vec<PriceChange>> {
+        if id > self.change_id {
+            return None;
+        }
+        let oldest = self
+            .recent_changes
+            .front()
+            .map_or(self.change_id + 1, |change| change.id);
+        if id + 1 < oldest {
+            return None;
+        }
+        Some(
+            self.recent_changes
+                .iter()
+                .filter(|change| change.id > id)
+                .cloned()
+                .collect(),
+        )
     }
 }
@@ -0,0 +1,102 @@
+import { test, expect, beforeAll, afterAll } from "bun:test";
+import { apiRequest, createUniqueUser, delay, withAuth } from "./utils";
+
+/* =========================
+   Stock Price Replica Tests
+   =========================
+   Meant to run against several stock-price replicas as well as one, e.g.
+
+     docker-compose up --build --scale stock-price=3
+
+   The gateway spreads requests over the replicas, so consecutive requests are answered by
+   different ones. */
+
+const REQUESTS = 12;
+const PRICE = 42;
+
+let sellUserTk: string;
+let buyUserTk: string;
+let stockId: string = "";
+
+async function getStockPrices() {
+  const response = await apiRequest(
+    "GET",
+    "/transaction/getStockPrices",
+    undefined,
+    withAuth(sellUserTk),
+    true
+  );
+  expect(response.success).toBe(true);
+  // Stocks with the same name may come in any order
+  return [...response.data].sort((a: any, b: any) => a.stock_id.localeCompare(b.stock_id));
+}
+
+beforeAll(async () => {
+  const sellResult = await createUniqueUser();
+  sellUserTk = sellResult.token;
+
+  const buyResult = await createUniqueUser();
+  buyUserTk = buyResult.token;
+
+  stockId = (
+    await apiRequest(
+      "POST",
+      "/setup/createStock",
+      { stock_name: `Stock ${Date.now()}` },
+      withAuth(sellUserTk),
+      true
+    )
+  ).data.stock_id;
+
+  await apiRequest(
+    "POST",
+    "/setup/addStockToUser",
+    { stock_id: stockId, quantity: 10 },
+    withAuth(sellUserTk),
+    true
+  );
+
+  await apiRequest(
+    "POST",
+    "/engine/placeStockOrder",
+    { stock_id: stockId, is_buy: false, order_type: "LIMIT", quantity: 10, price: PRICE },
+    withAuth(sellUserTk),
+    true
+  );
+
+  await delay(500); // Wait for every replica to get the price
+});
+
+// Clean up: Buy the stocks put up for sale
+afterAll(async () => {
+  await apiRequest(
+    "POST",
+    "/transaction/addMoneyToWallet",
+    { amount: 10 * PRICE },
+    withAuth(buyUserTk),
+    true
+  );
+  await apiRequest(
+    "POST",
+    "/engine/placeStockOrder",
+    { stock_id: stockId, is_buy: true, order_type: "MARKET", quantity: 10 },
+    withAuth(buyUserTk),
+    true
+  );
+});
+
+test("GET /transaction/getStockPrices returns the new price from every replica", async () => {
+  for (let i = 0; i < REQUESTS; i++) {
+    const prices = await getStockPrices();
+    const ourStock = prices.find((stock: any) => stock.stock_id === stockId);
+    expect(ourStock).toBeDefined();
+    expect(ourStock.current_price).toBe(PRICE);
+  }
+});
+
+test("GET /transaction/getStockPrices returns the same prices from every replica", async () => {
+  const first = await getStockPrices();
+  for (let i = 1; i < REQUESTS; i++) {
+    expect(await getStockPrices()).toEqual(first);
+  }
+});